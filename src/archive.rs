@@ -0,0 +1,697 @@
+use crate::change_detector::ChangeDetector;
+use crate::comparison::CompareStrategy;
+use crate::configuration::ProgramOptions;
+use crate::files::{create_symlink, owner, permission_bits, set_modified};
+use crate::hashing::hash_file_with;
+use crate::paths::{ActionType, FileInfoParser, UNIX_SPLITTER};
+use crate::report::TargetReport;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The formats an archive target can be written in, chosen by extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarZstd,
+    TarGzip,
+    Zip,
+}
+
+const EXTENSIONS: [(&str, ArchiveFormat); 6] = [
+    (".tar.zst", ArchiveFormat::TarZstd),
+    (".tzst", ArchiveFormat::TarZstd),
+    (".tar.gz", ArchiveFormat::TarGzip),
+    (".tgz", ArchiveFormat::TarGzip),
+    (".tar", ArchiveFormat::Tar),
+    (".zip", ArchiveFormat::Zip),
+];
+
+/// The format of the archive at `path` and the extension it was chosen by.
+pub fn archive_format(path: &Path) -> io::Result<(ArchiveFormat, &'static str)> {
+    let name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    EXTENSIONS
+        .iter()
+        .find(|(extension, _)| name.len() > extension.len() && name.ends_with(extension))
+        .map(|(extension, format)| (*format, *extension))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not a .tar, .tar.gz, .tar.zst or .zip archive",
+                    path.display()
+                ),
+            )
+        })
+}
+
+/// Opens the tar stream of a tar volume, decompressing it as needed.
+pub fn tar_reader(format: ArchiveFormat, file: File) -> io::Result<Box<dyn Read>> {
+    match format {
+        ArchiveFormat::TarZstd => Ok(Box::new(zstd::Decoder::new(file)?)),
+        ArchiveFormat::TarGzip => Ok(Box::new(flate2::read::GzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// The archived state of one path.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The volume holding the latest version.
+    pub volume: usize,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: u64,
+    pub modified_nanos: u32,
+    pub mode: u32,
+    /// The user and group ids, recorded with `--preserve-owner`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<(u32, u32)>,
+    pub link_target: Option<String>,
+    pub hash: Option<String>,
+}
+
+/// Lists the volumes of an archive target, oldest first, and where the
+/// latest version of every archived path lives. Paths are `/`-separated and
+/// relative to the source.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ArchiveIndex {
+    pub volumes: Vec<String>,
+    pub entries: BTreeMap<String, IndexEntry>,
+}
+
+/// An archive target: a base volume such as `project.tar.zst`, numbered
+/// increments `project.001.tar.zst`, `project.002.tar.zst`, ... next to it,
+/// and the index `project.tar.zst.index.json`.
+pub struct ArchiveTarget {
+    directory: PathBuf,
+    stem: String,
+    extension: &'static str,
+    format: ArchiveFormat,
+}
+
+impl ArchiveTarget {
+    pub fn new(path: &Path) -> io::Result<ArchiveTarget> {
+        let (format, extension) = archive_format(path)?;
+        let name = path.file_name().unwrap().to_string_lossy();
+        Ok(ArchiveTarget {
+            directory: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            stem: name[..name.len() - extension.len()].to_string(),
+            extension,
+            format,
+        })
+    }
+
+    fn volume_name(&self, number: usize) -> String {
+        match number {
+            0 => format!("{}{}", self.stem, self.extension),
+            n => format!("{}.{:03}{}", self.stem, n, self.extension),
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.directory
+            .join(format!("{}.index.json", self.volume_name(0)))
+    }
+
+    /// Reads the index, or returns an empty one for a new archive. A base
+    /// volume without an index is an error rather than being overwritten.
+    pub fn read_index(&self) -> io::Result<ArchiveIndex> {
+        match fs::read(self.index_path()) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let base = self.directory.join(self.volume_name(0));
+                if base.exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} exists but its index {} is missing",
+                            base.display(),
+                            self.index_path().display()
+                        ),
+                    ));
+                }
+                Ok(ArchiveIndex::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_index(&self, index: &ArchiveIndex) -> io::Result<()> {
+        let path = self.index_path();
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec_pretty(index)?)?;
+        fs::rename(&partial, &path)
+    }
+
+    /// Writes `changes` to a new volume, which only appears under its final
+    /// name once it is complete.
+    fn write_volume(&self, name: &str, changes: &[Change]) -> io::Result<()> {
+        let path = self.directory.join(name);
+        let partial = self.directory.join(format!("{}.partial", name));
+        let file = File::create(&partial)?;
+        let result = match self.format {
+            ArchiveFormat::Tar => write_tar(file, changes).and_then(|x| x.sync_all()),
+            ArchiveFormat::TarZstd => zstd::Encoder::new(file, 0)
+                .and_then(|x| write_tar(x, changes))
+                .and_then(|x| x.finish())
+                .and_then(|x| x.sync_all()),
+            ArchiveFormat::TarGzip => {
+                let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                write_tar(encoder, changes)
+                    .and_then(|x| x.finish())
+                    .and_then(|x| x.sync_all())
+            }
+            ArchiveFormat::Zip => write_zip(file, changes).and_then(|x| x.sync_all()),
+        };
+        match result {
+            Ok(()) => fs::rename(&partial, &path),
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A source entry to write to the next volume.
+struct Change {
+    action_type: ActionType,
+    key: String,
+    file: FileInfoParser,
+    entry: IndexEntry,
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+pub fn entry_time(entry: &IndexEntry) -> SystemTime {
+    UNIX_EPOCH + Duration::new(entry.modified, entry.modified_nanos)
+}
+
+/// The `/`-separated archive path of a source entry. Archive formats need
+/// UTF-8 names, so `None` is returned for anything else.
+pub fn archive_key(file: &FileInfoParser) -> Option<String> {
+    file.get_relative_path()
+        .names()
+        .map(|x| x.to_str())
+        .collect::<Option<Vec<&str>>>()
+        .map(|x| x.join(&UNIX_SPLITTER.to_string()))
+}
+
+pub fn build_index_entry(
+    o: &ProgramOptions,
+    compare: &CompareStrategy,
+    file: &FileInfoParser,
+    volume: usize,
+) -> io::Result<IndexEntry> {
+    let (modified, modified_nanos) = unix_time(file.metadata.modified()?);
+    let kind = if file.is_symlink() {
+        EntryKind::Symlink
+    } else if file.is_file {
+        EntryKind::File
+    } else {
+        EntryKind::Directory
+    };
+    let hash = match kind {
+        EntryKind::File if compare.hash => Some(hash_file_with(
+            &file.get_path(),
+            o.hash_algorithm,
+            o.hash_buffer_size,
+            o.hash_mmap,
+        )?),
+        _ => None,
+    };
+
+    Ok(IndexEntry {
+        volume,
+        kind,
+        size: match kind {
+            EntryKind::File => file.metadata.len(),
+            _ => 0,
+        },
+        modified,
+        modified_nanos,
+        mode: permission_bits(&file.metadata),
+        owner: match o.preserve_owner {
+            true => owner(&file.metadata),
+            false => None,
+        },
+        link_target: file
+            .link_target
+            .as_ref()
+            .map(|x| x.to_string_lossy().to_string()),
+        hash,
+    })
+}
+
+/// Whether the archived version of an entry is out of date. Permissions
+/// and owners count as well as contents, since they are restored too.
+pub fn entry_differs(
+    archived: &IndexEntry,
+    current: &IndexEntry,
+    compare: &CompareStrategy,
+) -> bool {
+    if archived.kind != current.kind {
+        return true;
+    }
+    if current.kind != EntryKind::Symlink
+        && (archived.mode != current.mode || archived.owner != current.owner)
+    {
+        return true;
+    }
+    match current.kind {
+        EntryKind::Directory => false,
+        EntryKind::Symlink => archived.link_target != current.link_target,
+        EntryKind::File => {
+            (compare.size && archived.size != current.size)
+                || (compare.modified
+                    && (archived.modified, archived.modified_nanos)
+                        != (current.modified, current.modified_nanos))
+                || (compare.hash && archived.hash != current.hash)
+        }
+    }
+}
+
+fn write_tar<W: Write>(writer: W, changes: &[Change]) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for change in changes {
+        let entry = &change.entry;
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.modified);
+        header.set_mode(entry.mode);
+        header.set_size(0);
+        match entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(tar::EntryType::Directory);
+                builder.append_data(&mut header, format!("{}/", change.key), io::empty())?;
+            }
+            EntryKind::Symlink => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                let target = entry.link_target.as_deref().unwrap_or_default();
+                builder.append_link(&mut header, &change.key, target)?;
+            }
+            EntryKind::File => {
+                let mut source = File::open(change.file.get_path())?;
+                let length = source.metadata()?.len();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(length);
+                builder.append_data(&mut header, &change.key, (&mut source).take(length))?;
+            }
+        }
+    }
+    builder.into_inner()
+}
+
+/// A zip timestamp, which holds local time from 1980 on. Times outside its
+/// range are stored as 1980; the index keeps the real one.
+fn zip_time(entry: &IndexEntry) -> zip::DateTime {
+    use chrono::{Datelike, TimeZone, Timelike};
+    chrono::Local
+        .timestamp_opt(entry.modified as i64, 0)
+        .single()
+        .and_then(|x| {
+            zip::DateTime::from_date_and_time(
+                u16::try_from(x.year()).ok()?,
+                x.month() as u8,
+                x.day() as u8,
+                x.hour() as u8,
+                x.minute() as u8,
+                x.second() as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+fn write_zip<W: Write + io::Seek>(writer: W, changes: &[Change]) -> io::Result<W> {
+    let mut zip = zip::ZipWriter::new(writer);
+    for change in changes {
+        let entry = &change.entry;
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip_time(entry))
+            .unix_permissions(entry.mode)
+            .large_file(entry.size >= u32::MAX as u64);
+        match entry.kind {
+            EntryKind::Directory => zip.add_directory(change.key.as_str(), options)?,
+            EntryKind::Symlink => {
+                let target = entry.link_target.as_deref().unwrap_or_default();
+                zip.add_symlink(change.key.as_str(), target, options)?
+            }
+            EntryKind::File => {
+                zip.start_file(change.key.as_str(), options)?;
+                io::copy(&mut File::open(change.file.get_path())?, &mut zip)?;
+            }
+        }
+    }
+    Ok(zip.finish()?)
+}
+
+/// Adds a volume holding the entries that changed since the last sync and
+/// records deletions in the index.
+fn sync_archive(
+    o: &ProgramOptions,
+    source_dir: &String,
+    target: &str,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let archive = ArchiveTarget::new(Path::new(target))?;
+    let mut index = archive.read_index()?;
+    let volume = index.volumes.len();
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+
+    let mut changes = Vec::<Change>::new();
+    let mut seen = HashSet::<String>::new();
+    let files = change_detector.enumerate_directory(source_dir, "source", true, o.symlinks);
+    for file in files {
+        if change_detector.find_skip_folder(&file).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        let key = match archive_key(&file) {
+            Some(key) => key,
+            None => {
+                warn!(
+                    "Skipped {} because archive paths must be valid UTF-8.",
+                    file.display_path()
+                );
+                report.skips += 1;
+                continue;
+            }
+        };
+
+        let entry = match build_index_entry(o, &compare, &file, volume) {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Keep what the archive has; a vanished file is deleted on
+                // the next sync.
+                error!("Unable to read {}: {}", file.display_path(), e);
+                report.record_error(format!("read {}: {}", file.display_path(), e));
+                seen.insert(key);
+                continue;
+            }
+        };
+        let action_type = match index.entries.get(&key) {
+            None => ActionType::Create,
+            Some(archived) if entry_differs(archived, &entry, &compare) => ActionType::Update,
+            Some(_) => {
+                seen.insert(key);
+                continue;
+            }
+        };
+        seen.insert(key.clone());
+        changes.push(Change {
+            action_type,
+            key,
+            file,
+            entry,
+        });
+    }
+    changes.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let mut deleted = index
+        .entries
+        .keys()
+        .filter(|x| !seen.contains(*x))
+        .cloned()
+        .collect::<Vec<String>>();
+    if !o.enable_deletes && !deleted.is_empty() {
+        info!("Deleted suppressed by config");
+        report.skips += deleted.len();
+        deleted.clear();
+    }
+    if changes.is_empty() && deleted.is_empty() && volume > 0 {
+        info!("{} is up to date.", target);
+        return Ok(());
+    }
+
+    if !changes.is_empty() || volume == 0 {
+        let name = archive.volume_name(volume);
+        info!(
+            job = o.job_name.as_str(),
+            target = target;
+            "Writing {} entries to {}", changes.len(), &name
+        );
+        archive.write_volume(&name, &changes)?;
+        index.volumes.push(name);
+    }
+    for change in changes {
+        report.record_change(&change.action_type, &change.key, change.entry.size);
+        index.entries.insert(change.key, change.entry);
+    }
+    for key in deleted {
+        index.entries.remove(&key);
+        report.record_change(&ActionType::Delete, &key, 0);
+    }
+    archive.write_index(&index)
+}
+
+/// Syncs the source into every `archive:` target.
+pub fn sync_archives(o: &ProgramOptions) -> Vec<TargetReport> {
+    let source_dir = o.get_source_directory();
+    o.get_archive_targets()
+        .iter()
+        .map(|target| {
+            info!("Archive target is {}", target);
+            let mut report = TargetReport::new(&source_dir, target);
+            if let Err(e) = sync_archive(o, &source_dir, target, &mut report) {
+                error!("Unable to update archive {}: {}", target, e);
+                report.record_error(format!("archive {}: {}", target, e));
+            }
+            report.finish();
+            report
+        })
+        .collect()
+}
+
+/// Where an archived path is restored to. Paths that would escape
+/// `directory` are rejected.
+pub fn restore_path(directory: &Path, key: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(key);
+    if key.is_empty()
+        || !relative
+            .components()
+            .all(|x| matches!(x, Component::Normal(_)))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsafe archive path {}", key),
+        ));
+    }
+    let path = directory.join(relative);
+    check_link_free(directory, &path)?;
+    Ok(path)
+}
+
+/// Refuses a path under `directory` whose parents below it include a
+/// symbolic link, which an earlier entry could have planted to get later
+/// ones written outside `directory`. The last name may be a link; it is
+/// replaced rather than followed.
+pub fn check_link_free(directory: &Path, path: &Path) -> io::Result<()> {
+    let relative = path.strip_prefix(directory).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside {}", path.display(), directory.display()),
+        )
+    })?;
+    let mut parent = directory.to_path_buf();
+    let mut names = relative.components().peekable();
+    while let Some(name) = names.next() {
+        if names.peek().is_none() {
+            break;
+        }
+        parent.push(name);
+        if fs::symlink_metadata(&parent).is_ok_and(|x| x.file_type().is_symlink()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} leads through the link {}",
+                    path.display(),
+                    parent.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file or link in the way of a restored entry.
+pub fn prepare_restore_path(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Gives a restored file its archived permissions, owner and modification
+/// time. An owner that can't be set, as when restoring without root, is
+/// only warned about.
+pub fn restore_file_metadata(path: &Path, entry: &IndexEntry) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some((uid, gid)) = entry.owner {
+        match std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                warn!("Unable to set the owner of {}: {}", path.display(), e)
+            }
+            result => result?,
+        }
+    }
+    // The mode goes last, since it may leave the file read-only.
+    set_modified(path, entry_time(entry))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.mode))?;
+    }
+    Ok(())
+}
+
+/// Writes one archived entry, read from `data`, under `directory`.
+fn restore_entry(
+    data: &mut dyn Read,
+    key: &str,
+    indexed: &IndexEntry,
+    directory: &Path,
+) -> io::Result<()> {
+    let path = restore_path(directory, key)?;
+    prepare_restore_path(&path)?;
+    match indexed.kind {
+        EntryKind::Directory => fs::create_dir_all(&path),
+        EntryKind::Symlink => {
+            let target = indexed.link_target.as_deref().unwrap_or_default();
+            create_symlink(Path::new(target), &path)
+        }
+        EntryKind::File => {
+            io::copy(data, &mut File::create(&path)?)?;
+            restore_file_metadata(&path, indexed)
+        }
+    }
+}
+
+/// Restores the entries of a tar volume whose latest version it holds.
+fn restore_tar<R: Read>(
+    reader: R,
+    number: usize,
+    index: &ArchiveIndex,
+    directory: &Path,
+) -> io::Result<usize> {
+    let mut restored = 0;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let key = entry
+            .path()?
+            .to_string_lossy()
+            .trim_end_matches('/')
+            .to_string();
+        let indexed = match index.entries.get(&key) {
+            Some(indexed) if indexed.volume == number => indexed,
+            _ => continue,
+        };
+        restore_entry(&mut entry, &key, indexed, directory)?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Restores the entries of a zip volume whose latest version it holds.
+fn restore_zip(
+    file: File,
+    number: usize,
+    index: &ArchiveIndex,
+    directory: &Path,
+) -> io::Result<usize> {
+    let mut restored = 0;
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let key = entry.name().trim_end_matches('/').to_string();
+        let indexed = match index.entries.get(&key) {
+            Some(indexed) if indexed.volume == number => indexed,
+            _ => continue,
+        };
+        restore_entry(&mut entry, &key, indexed, directory)?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Rebuilds the tree an archive target holds into `directory` from its
+/// base volume and increments. Returns the number of entries restored.
+pub fn restore_archive(archive_path: &Path, directory: &Path) -> io::Result<usize> {
+    let archive = ArchiveTarget::new(archive_path)?;
+    let index = archive.read_index()?;
+    if index.volumes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no volumes", archive_path.display()),
+        ));
+    }
+
+    fs::create_dir_all(directory)?;
+    let mut restored = 0;
+    for (number, name) in index.volumes.iter().enumerate() {
+        info!("Restoring from {}", name);
+        let file = File::open(archive.directory.join(name))?;
+        restored += match archive.format {
+            ArchiveFormat::Zip => restore_zip(file, number, &index, directory)?,
+            format => restore_tar(tar_reader(format, file)?, number, &index, directory)?,
+        };
+    }
+    if restored != index.entries.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "only {} of {} indexed entries were found in the volumes",
+                restored,
+                index.entries.len()
+            ),
+        ));
+    }
+
+    // Directory times last, deepest first, since restoring their contents
+    // changes them.
+    for (key, entry) in index.entries.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            set_modified(&restore_path(directory, key)?, entry_time(entry))?;
+        }
+    }
+    Ok(restored)
+}
+
+/// Runs the restore subcommand and returns the process exit code.
+pub fn run_restore(archive: &str, directory: &str) -> i32 {
+    match restore_archive(Path::new(archive), Path::new(directory)) {
+        Ok(count) => {
+            info!("Restored {} entries to {}", count, directory);
+            0
+        }
+        Err(e) => {
+            error!("Unable to restore {}: {}", archive, e);
+            2
+        }
+    }
+}
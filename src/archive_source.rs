@@ -0,0 +1,546 @@
+use crate::archive::{archive_format, check_link_free, tar_reader, ArchiveFormat, EntryKind};
+use crate::change_detector::{locate_dir, ChangeDetector};
+use crate::comparison::CompareStrategy;
+use crate::configuration::{ProgramOptions, SymlinkPolicy};
+use crate::files::{create_symlink, set_modified};
+use crate::paths::{
+    extension_matches, extension_of, ActionType, FileInfoParser, RelativePath, UpdateReason,
+};
+use crate::report::TargetReport;
+use crate::timestamps::TimeTolerance;
+
+use itertools::Itertools;
+use log::{error, info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An entry of a source archive, listed the way `get_all_files` lists a
+/// directory.
+#[derive(Clone, Debug)]
+struct SourceEntry {
+    /// The name as stored in the archive.
+    archive_name: String,
+    kind: EntryKind,
+    size: u64,
+    modified: Option<SystemTime>,
+    mode: Option<u32>,
+    /// Only zip archives store checksums.
+    crc32: Option<u32>,
+    link_target: Option<String>,
+}
+
+/// A create or update to stream out of the archive.
+struct Write {
+    action_type: ActionType,
+    destination: PathBuf,
+}
+
+/// How precisely each format stores modification times.
+fn time_granularity(format: ArchiveFormat) -> Duration {
+    match format {
+        ArchiveFormat::Zip => Duration::from_secs(2),
+        _ => Duration::from_secs(1),
+    }
+}
+
+/// The `/`-separated relative path of an archive member, or `None` for
+/// names that are empty or would escape the target.
+fn normalize_name(name: &str) -> Option<String> {
+    if name.starts_with('/') || name.contains('\\') {
+        return None;
+    }
+    let names = name
+        .split('/')
+        .filter(|x| !x.is_empty() && *x != ".")
+        .collect::<Vec<&str>>();
+    if names.is_empty() || names.contains(&"..") {
+        return None;
+    }
+    Some(names.join("/"))
+}
+
+fn tar_kind(entry_type: tar::EntryType) -> Option<EntryKind> {
+    match entry_type {
+        tar::EntryType::Regular | tar::EntryType::Continuous => Some(EntryKind::File),
+        tar::EntryType::Directory => Some(EntryKind::Directory),
+        tar::EntryType::Symlink => Some(EntryKind::Symlink),
+        _ => None,
+    }
+}
+
+fn list_tar(reader: Box<dyn Read>) -> io::Result<Vec<SourceEntry>> {
+    let mut entries = Vec::<SourceEntry>::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let archive_name = entry.path()?.to_string_lossy().to_string();
+        let kind = match tar_kind(entry.header().entry_type()) {
+            Some(kind) => kind,
+            None => {
+                warn!(
+                    "Skipped {}; only files, directories and links are synced.",
+                    archive_name
+                );
+                continue;
+            }
+        };
+        entries.push(SourceEntry {
+            kind,
+            size: entry.size(),
+            modified: entry
+                .header()
+                .mtime()
+                .ok()
+                .map(|x| UNIX_EPOCH + Duration::from_secs(x)),
+            mode: entry.header().mode().ok(),
+            crc32: None,
+            link_target: entry.link_name()?.map(|x| x.to_string_lossy().to_string()),
+            archive_name,
+        });
+    }
+    Ok(entries)
+}
+
+/// Converts a zip timestamp, which is in local time.
+fn zip_modified(time: zip::DateTime) -> Option<SystemTime> {
+    use chrono::TimeZone;
+    chrono::Local
+        .with_ymd_and_hms(
+            time.year() as i32,
+            time.month() as u32,
+            time.day() as u32,
+            time.hour() as u32,
+            time.minute() as u32,
+            time.second() as u32,
+        )
+        .earliest()
+        .map(SystemTime::from)
+}
+
+fn list_zip(file: File) -> io::Result<Vec<SourceEntry>> {
+    let mut entries = Vec::<SourceEntry>::new();
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let kind = if entry.is_dir() {
+            EntryKind::Directory
+        } else if entry.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::File
+        };
+        let link_target = match kind {
+            EntryKind::Symlink => {
+                let mut target = String::new();
+                entry.read_to_string(&mut target)?;
+                Some(target)
+            }
+            _ => None,
+        };
+        entries.push(SourceEntry {
+            archive_name: entry.name().to_string(),
+            kind,
+            size: entry.size(),
+            modified: entry.last_modified().and_then(zip_modified),
+            mode: entry.unix_mode().map(|x| x & 0o7777),
+            crc32: Some(entry.crc32()),
+            link_target,
+        });
+    }
+    Ok(entries)
+}
+
+/// Lists the entries of an archive by relative path, adding the parent
+/// directories archives often leave out. A path stored more than once
+/// takes its last entry, as extracting it would.
+fn list_entries(format: ArchiveFormat, path: &Path) -> io::Result<BTreeMap<String, SourceEntry>> {
+    let file = File::open(path)?;
+    let listed = match format {
+        ArchiveFormat::Zip => list_zip(file)?,
+        format => list_tar(tar_reader(format, file)?)?,
+    };
+
+    let mut entries = BTreeMap::<String, SourceEntry>::new();
+    for entry in listed {
+        match normalize_name(&entry.archive_name) {
+            Some(name) => {
+                entries.insert(name, entry);
+            }
+            None => warn!("Skipped unsafe archive path {}.", entry.archive_name),
+        }
+    }
+
+    let parents = entries
+        .keys()
+        .flat_map(|name| name.match_indices('/').map(|(i, _)| name[..i].to_string()))
+        .filter(|x| !entries.contains_key(x))
+        .collect::<Vec<String>>();
+    for parent in parents {
+        entries.insert(
+            parent.clone(),
+            SourceEntry {
+                archive_name: parent,
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: None,
+                mode: None,
+                crc32: None,
+                link_target: None,
+            },
+        );
+    }
+    Ok(entries)
+}
+
+fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+/// Compares an archive entry with the target the way `find_update_reasons`
+/// compares two files, using the stored CRC as the content hash. Entries
+/// that are a file on one side and a directory on the other are left
+/// alone, as in a directory sync.
+fn find_update_reasons(
+    entry: &SourceEntry,
+    existing: &FileInfoParser,
+    compare: &CompareStrategy,
+    tolerance: TimeTolerance,
+) -> Vec<UpdateReason> {
+    let mut reasons = Vec::<UpdateReason>::new();
+    if entry.kind == EntryKind::Symlink || existing.is_symlink() {
+        let existing_target = existing
+            .link_target
+            .as_ref()
+            .map(|x| x.to_string_lossy().to_string());
+        if entry.link_target != existing_target {
+            reasons.push(UpdateReason::LinkTarget);
+        }
+        return reasons;
+    }
+    if entry.kind != EntryKind::File || !existing.is_file {
+        return reasons;
+    }
+
+    let size_differs = compare.size && entry.size != existing.metadata.len();
+    let hash_differs = match entry.crc32 {
+        Some(crc32) if compare.hash => {
+            size_differs || file_crc32(&existing.get_path()).ok() != Some(crc32)
+        }
+        _ => false,
+    };
+    if size_differs {
+        reasons.push(UpdateReason::Size);
+    }
+
+    let modified_differs = match (entry.modified, existing.metadata.modified().ok()) {
+        (Some(x), Some(y)) => !tolerance.same(x, y),
+        _ => false,
+    };
+    let content_verified = compare.hash && entry.crc32.is_some() && !size_differs && !hash_differs;
+    if compare.modified && modified_differs && !content_verified {
+        reasons.push(UpdateReason::Modified);
+    }
+
+    if hash_differs {
+        reasons.push(UpdateReason::Hash);
+    }
+    reasons
+}
+
+/// Writes one file or link read from `data` to `destination` under
+/// `target_dir`, replacing whatever is there. Returns the bytes written.
+fn write_entry(
+    data: &mut dyn Read,
+    entry: &SourceEntry,
+    target_dir: &Path,
+    destination: &Path,
+) -> io::Result<u64> {
+    check_link_free(target_dir, destination)?;
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Ok(metadata) = fs::symlink_metadata(destination) {
+        if metadata.is_symlink() || entry.kind == EntryKind::Symlink {
+            fs::remove_file(destination)?;
+        }
+    }
+
+    if entry.kind == EntryKind::Symlink {
+        let target = entry.link_target.as_deref().unwrap_or_default();
+        create_symlink(Path::new(target), destination)?;
+        return Ok(0);
+    }
+
+    let bytes = io::copy(data, &mut File::create(destination)?)?;
+    if let Some(modified) = entry.modified {
+        set_modified(destination, modified)?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(destination, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(bytes)
+}
+
+/// Streams the files and links in `writes` out of the archive, in a single
+/// pass for tar archives.
+fn stream_entries(
+    format: ArchiveFormat,
+    path: &Path,
+    target_dir: &Path,
+    entries: &BTreeMap<String, SourceEntry>,
+    writes: &BTreeMap<String, Write>,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let mut written = HashMap::<String, io::Result<u64>>::new();
+    let file = File::open(path)?;
+    if format == ArchiveFormat::Zip {
+        let mut archive = zip::ZipArchive::new(file)?;
+        for (name, write) in writes {
+            let entry = &entries[name];
+            let result = archive
+                .by_name(&entry.archive_name)
+                .map_err(io::Error::from)
+                .and_then(|mut data| write_entry(&mut data, entry, target_dir, &write.destination));
+            written.insert(name.clone(), result);
+        }
+    } else {
+        let mut archive = tar::Archive::new(tar_reader(format, file)?);
+        for data in archive.entries()? {
+            let mut data = data?;
+            let name = match normalize_name(&data.path()?.to_string_lossy()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(write) = writes.get(&name) {
+                // Later copies of a path replace earlier ones, so the last
+                // one wins as it did when listing.
+                let result =
+                    write_entry(&mut data, &entries[&name], target_dir, &write.destination);
+                written.insert(name, result);
+            }
+        }
+    }
+
+    for (name, write) in writes {
+        let display_path = write.destination.to_string_lossy();
+        match written.remove(name) {
+            Some(Ok(bytes)) => report.record_change(&write.action_type, &display_path, bytes),
+            Some(Err(e)) => {
+                error!("Unable to {} {}: {}", write.action_type, display_path, e);
+                report.record_error(format!("{} {}: {}", write.action_type, display_path, e));
+            }
+            None => {
+                error!("{} disappeared from {}", name, path.display());
+                report.record_error(format!(
+                    "{} {}: missing from the archive",
+                    write.action_type, name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sync_target(
+    o: &ProgramOptions,
+    path: &Path,
+    target_dir: &String,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let (format, _) = archive_format(path)?;
+    info!("Trying to find the target directory...");
+    locate_dir(target_dir)?;
+
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+    let case_sensitive = change_detector.is_case_sensitive(target_dir);
+    let mut tolerance = change_detector.time_tolerance(target_dir);
+    tolerance.window = tolerance.window.max(time_granularity(format));
+
+    info!("Enumerating the source archive {}...", path.display());
+    let entries = list_entries(format, path)?;
+    let mut listed = BTreeMap::<OsString, Vec<(RelativePath, String)>>::new();
+    for (name, entry) in &entries {
+        let relative = RelativePath::new(Path::new(name), case_sensitive);
+        if change_detector.find_skip_folder_of(&relative).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        let extension = extension_of(Path::new(name));
+        if entry.kind == EntryKind::File
+            && !extension_matches(extension.as_ref(), o.extensions.clone())
+        {
+            continue;
+        }
+        listed
+            .entry(relative.key().to_os_string())
+            .or_default()
+            .push((relative, name.clone()));
+    }
+
+    // Names differing only by case can't all be stored in a case-insensitive
+    // target, so they are reported and left out with everything beneath
+    // them, as in a directory sync.
+    let colliding_keys = listed
+        .iter()
+        .filter(|(_, x)| x.len() > 1)
+        .map(|(key, _)| key.clone())
+        .collect::<HashSet<OsString>>();
+    let is_colliding = |key: &OsStr| {
+        Path::new(key)
+            .ancestors()
+            .any(|a| colliding_keys.contains(a.as_os_str()))
+    };
+    let mut source = BTreeMap::<OsString, (RelativePath, String)>::new();
+    for (key, mut group) in listed {
+        if !is_colliding(&key) {
+            source.insert(key, group.remove(0));
+            continue;
+        }
+        report.skips += group.len();
+        if group.len() > 1 {
+            let names = group
+                .iter()
+                .map(|(relative, _)| relative.display())
+                .collect::<Vec<String>>();
+            warn!(
+                "{} differ only by case and can't all be stored in the target; skipping them.",
+                names.join(", ")
+            );
+            report.collisions.push(names);
+        }
+    }
+    info!("{} item(s) found in the source archive.", source.len());
+
+    let mut target = change_detector
+        .enumerate_directory(
+            target_dir,
+            "target",
+            case_sensitive,
+            SymlinkPolicy::CopyAsLink,
+        )
+        .into_iter()
+        .map(|x| (x.get_relative_path().key().to_os_string(), x))
+        .collect::<HashMap<OsString, FileInfoParser>>();
+    let target_count = target.len();
+    target.retain(|key, _| !is_colliding(key));
+    report.skips += target_count - target.len();
+
+    let mut directories = Vec::<PathBuf>::new();
+    let mut writes = BTreeMap::<String, Write>::new();
+    for (key, (relative, name)) in &source {
+        let entry = &entries[name];
+        let (action_type, destination) = match target.get(key) {
+            None => (
+                ActionType::Create,
+                Path::new(target_dir).join(relative.as_path()),
+            ),
+            Some(existing) => {
+                let reasons = find_update_reasons(entry, existing, &compare, tolerance);
+                if reasons.is_empty() {
+                    continue;
+                }
+                info!(
+                    "{} needs an update because of its {}.",
+                    existing.display_path(),
+                    reasons.iter().join(", ")
+                );
+                (ActionType::Update, existing.get_path())
+            }
+        };
+        if entry.kind == EntryKind::Directory {
+            directories.push(destination);
+        } else {
+            writes.insert(
+                name.clone(),
+                Write {
+                    action_type,
+                    destination,
+                },
+            );
+        }
+    }
+
+    for directory in directories {
+        let result = check_link_free(Path::new(target_dir), &directory)
+            .and_then(|_| fs::create_dir_all(&directory));
+        match result {
+            Ok(()) => report.record_change(&ActionType::Create, &directory.to_string_lossy(), 0),
+            Err(e) => {
+                error!("Unable to create dir {}: {}", directory.display(), e);
+                report.record_error(format!("create {}: {}", directory.display(), e));
+            }
+        }
+    }
+    if !writes.is_empty() {
+        stream_entries(
+            format,
+            path,
+            Path::new(target_dir),
+            &entries,
+            &writes,
+            report,
+        )?;
+    }
+
+    let deletes = target
+        .values()
+        .filter(|x| !source.contains_key(x.get_relative_path().key()))
+        .filter(|x| change_detector.find_skip_folder(x).is_none())
+        .sorted_by_key(|x| std::cmp::Reverse(x.get_relative_path().depth()))
+        .collect::<Vec<&FileInfoParser>>();
+    if !o.enable_deletes {
+        if !deletes.is_empty() {
+            info!("Deleted suppressed by config");
+            report.skips += deletes.len();
+        }
+        return Ok(());
+    }
+    for existing in deletes {
+        let destination = existing.get_path();
+        let result = match existing.is_file || existing.is_symlink() {
+            true => fs::remove_file(&destination),
+            false => fs::remove_dir(&destination),
+        };
+        match result {
+            Ok(()) => report.record_change(&ActionType::Delete, &destination.to_string_lossy(), 0),
+            Err(e) => {
+                error!("Unable to delete {}: {}", existing.display_path(), e);
+                report.record_error(format!("delete {}: {}", existing.display_path(), e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Syncs every directory target from the archive given as the source.
+/// Options it can't honour are refused when they're parsed; see
+/// `ProgramOptions::get_archive_source_conflicts`.
+pub fn sync_from_archive(o: &ProgramOptions, archive: &str) -> Vec<TargetReport> {
+    let mut reports = Vec::<TargetReport>::new();
+    for target_dir in o.get_target_directories() {
+        info!("Target directory is {}", target_dir);
+        let mut report = TargetReport::new(archive, &target_dir);
+        if let Err(e) = sync_target(o, Path::new(archive), &target_dir, &mut report) {
+            error!("Unable to sync {} from {}: {}", target_dir, archive, e);
+            report.record_error(format!("archive {}: {}", archive, e));
+        }
+        report.finish();
+        reports.push(report);
+    }
+    reports
+}
@@ -0,0 +1,110 @@
+use crate::configuration::ProgramOptions;
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+
+/// The extended attributes holding a file's POSIX ACLs.
+pub const ACL_ATTRIBUTES: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Extended attribute names and values, sorted by name.
+pub type Attributes = Vec<(OsString, Vec<u8>)>;
+
+/// Selects the extended attributes that are replicated: those in one of
+/// `namespaces` (the part of the name before the first dot) and, with
+/// `acls`, the POSIX ACLs.
+#[derive(Clone, Debug)]
+pub struct AttributeFilter {
+    namespaces: Vec<String>,
+    acls: bool,
+}
+
+impl AttributeFilter {
+    /// The configured filter, or `None` when attributes aren't replicated.
+    pub fn from_options(o: &ProgramOptions) -> Option<AttributeFilter> {
+        if !o.xattrs && !o.acls {
+            return None;
+        }
+
+        Some(AttributeFilter {
+            namespaces: match o.xattrs {
+                true => o.xattr_namespaces.clone(),
+                false => Vec::new(),
+            },
+            acls: o.acls,
+        })
+    }
+
+    pub fn matches(&self, name: &OsStr) -> bool {
+        if is_acl(name) {
+            return self.acls;
+        }
+
+        match name.to_str().and_then(|x| x.split_once('.')) {
+            Some((namespace, _)) => self.namespaces.iter().any(|x| x == namespace),
+            None => false,
+        }
+    }
+}
+
+pub fn is_acl(name: &OsStr) -> bool {
+    ACL_ATTRIBUTES.iter().any(|x| OsStr::new(x) == name)
+}
+
+/// Reads the attributes of `path` selected by `filter`, following links.
+#[cfg(unix)]
+pub fn read_attributes(path: &Path, filter: &AttributeFilter) -> io::Result<Attributes> {
+    let mut attributes = Attributes::new();
+    for name in xattr::list_deref(path)? {
+        if !filter.matches(&name) {
+            continue;
+        }
+        if let Some(value) = xattr::get_deref(path, &name)? {
+            attributes.push((name, value));
+        }
+    }
+    attributes.sort();
+    Ok(attributes)
+}
+
+/// Makes the attributes of `path` selected by `filter` equal `attributes`,
+/// removing any it has that aren't listed.
+#[cfg(unix)]
+pub fn write_attributes(
+    path: &Path,
+    attributes: &Attributes,
+    filter: &AttributeFilter,
+) -> io::Result<()> {
+    let existing = read_attributes(path, filter)?;
+    for (name, _) in &existing {
+        if !attributes.iter().any(|(x, _)| x == name) {
+            xattr::remove_deref(path, name)?;
+        }
+    }
+    for (name, value) in attributes {
+        if !existing.iter().any(|(x, y)| x == name && y == value) {
+            xattr::set_deref(path, name, value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn read_attributes(_path: &Path, _filter: &AttributeFilter) -> io::Result<Attributes> {
+    Ok(Attributes::new())
+}
+
+#[cfg(not(unix))]
+pub fn write_attributes(
+    _path: &Path,
+    attributes: &Attributes,
+    _filter: &AttributeFilter,
+) -> io::Result<()> {
+    match attributes.is_empty() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are not supported on this platform",
+        )),
+    }
+}
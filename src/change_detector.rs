@@ -1,32 +1,65 @@
-use crate::configuration::ProgramOptions;
-use crate::paths::{ActionType, FileInfoParser, FileInfoParserAction, FileInfoParserActionList, PathParser};
-use crate::utilities::{read_file_incremental_action};
-use log::{error, info, warn};
-use std::collections::HashMap;
+use crate::attributes::{is_acl, read_attributes, AttributeFilter};
+use crate::comparison::CompareStrategy;
+use crate::compression::{map_compressed_entries, stored_as_expected, TargetCompression};
+use crate::configuration::{CaseSensitivity, ProgramOptions, SymlinkPolicy, UpdatePolicy};
+use crate::files::{owner, permission_bits, probe_case_sensitive, FileId};
+use crate::hashing::hash_file_with;
+use crate::paths::{
+    ActionType, FileInfoParser, FileInfoParserAction, FileInfoParserActionList, PathParser,
+    RelativePath, UpdateReason,
+};
+use crate::timestamps::{infer_time_granularity, probe_time_granularity, TimeTolerance};
+use itertools::EitherOrBoth::{Both, Left, Right};
+use itertools::Itertools;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
-use xxhash_rust::xxh3::xxh3_64;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 pub struct ChangeDetector {
     program_options: ProgramOptions,
+    read_only: bool,
+    attribute_filter: Option<AttributeFilter>,
+    compare: CompareStrategy,
+    compression: Option<TargetCompression>,
 }
 
 impl ChangeDetector {
     pub fn new(o: ProgramOptions) -> ChangeDetector {
-        ChangeDetector { program_options: o }
+        ChangeDetector {
+            attribute_filter: AttributeFilter::from_options(&o),
+            compare: CompareStrategy::from_options(&o),
+            compression: TargetCompression::from_options(&o),
+            program_options: o,
+            read_only: false,
+        }
+    }
+
+    /// A detector that never creates missing directories; a missing
+    /// directory is treated as empty.
+    pub fn new_read_only(o: ProgramOptions) -> ChangeDetector {
+        ChangeDetector {
+            attribute_filter: AttributeFilter::from_options(&o),
+            compare: CompareStrategy::from_options(&o),
+            compression: TargetCompression::from_options(&o),
+            program_options: o,
+            read_only: true,
+        }
     }
 
     #[allow(dead_code)]
     pub fn changed(&self) -> bool {
         info!("Checking for changes...");
         let merge = self.three_way_merge();
-        merge.len() > 0
+        !merge.is_empty()
     }
 
     pub fn incremental_changes(&self) -> Vec<FileInfoParserActionList> {
         info!("Checking for changes...");
-        let merge = self.three_way_merge();
-        merge
+        self.three_way_merge()
     }
 
     pub fn three_way_merge(&self) -> Vec<FileInfoParserActionList> {
@@ -41,109 +74,246 @@ impl ChangeDetector {
         for target_dir in target_dirs {
             info!("Target directory is {}", target_dir);
 
+            if !self.read_only {
+                info!("Trying to find the source directory...");
+                if let Err(e) = locate_dir(&source_dir) {
+                    error!("Unable to create the source dir {}: {}", source_dir, e);
+                    continue;
+                }
+
+                info!("Trying to find the target directory...");
+                if let Err(e) = locate_dir(&target_dir) {
+                    error!("Unable to create the target dir {}: {}", target_dir, e);
+                    continue;
+                }
+            }
+
+            let case_sensitive = self.is_case_sensitive(&target_dir);
             let source_pp = PathParser::new(&source_dir);
             let dest_pp = PathParser::new(&target_dir);
 
-            if source_pp.get_segment().identical(&dest_pp.get_segment()) {
+            if source_pp
+                .get_segment()
+                .identical(&dest_pp.get_segment(), case_sensitive)
+            {
                 error!(
                     "Source and destination paths are identical. 
                         Please change the paths to allow for copying."
                 );
                 continue;
             }
-
-            info!("Trying to find the source directory...");
-            locate_dir(&source_dir);
-
-            info!("Trying to find the target directory...");
-            locate_dir(&target_dir);
-
-            let file_info_source = self.enumerate_directory(&source_dir, "source");
-            let file_info_target = self.enumerate_directory(&target_dir, "target");
-
-            info!("Building path caches...");
-            let source_hash = build_file_hash_list(&file_info_source);
-            let target_hash = build_file_hash_list(&file_info_target);
-
-            let mut in_first_only = Vec::<FileInfoParser>::new();
-            let mut in_both = Vec::<(FileInfoParser, FileInfoParser)>::new();
-
-            check_created_updated_files(
-                file_info_source,
-                target_hash,
-                &target_dir,
-                &mut in_both,
-                &mut in_first_only,
+            let mut file_info_source = self.enumerate_directory(
+                &source_dir,
+                "source",
+                case_sensitive,
+                self.program_options.symlinks,
             );
-
-            let in_second_only = check_deleted_files(file_info_target, source_hash);
-            let actions = self.enumerate_actions(in_first_only, in_second_only, in_both);
+            let mut file_info_target = self.enumerate_target(&target_dir, case_sensitive);
+            let (stored_collisions, stored_skipped) = match &self.compression {
+                Some(compression) => drop_collisions(
+                    compression.find_collisions(&file_info_source, case_sensitive),
+                    &mut file_info_source,
+                    &mut file_info_target,
+                ),
+                None => (Vec::new(), 0),
+            };
+
+            let tolerance = self.time_tolerance(&target_dir);
+            let mut joined = join_entries(file_info_source, file_info_target);
+            joined.collisions.extend(stored_collisions);
+            joined.skipped += stored_skipped;
+            let (hard_links, broken_hard_links) = if self.program_options.preserve_hard_links {
+                find_hard_link_anchors(&joined.in_both)
+            } else {
+                (HashMap::new(), HashSet::new())
+            };
+            let (actions, unchanged) = self.enumerate_actions(
+                joined.in_first_only,
+                joined.in_second_only,
+                joined.in_both,
+                &broken_hard_links,
+                tolerance,
+            );
+            let action_count = actions.len();
+            let (actions, newer_on_target) = self.apply_update_policy(actions, tolerance);
             let actions_noskip = self.filter_skip_actions(actions);
+            let skipped = joined.skipped + unchanged + action_count - actions_noskip.len();
 
             results.push(FileInfoParserActionList {
                 actions: actions_noskip,
                 source_directory: source_dir.clone(),
                 target_directory: target_dir.clone(),
+                skipped,
+                hard_links,
+                collisions: joined.collisions,
+                newer_on_target,
             })
         }
 
         results
     }
 
-    fn enumerate_directory(&self, source_dir: &String, dir_type: &str) -> Vec<FileInfoParser> {
+    /// How closely modification times must match for `target_dir`. The
+    /// target's timestamp precision is probed, or in read-only runs inferred
+    /// from the times already stored there, and used unless `--modify-window`
+    /// is given. Timezone and DST shifts are ignored on FAT targets, and on
+    /// any target with `--ignore-time-shifts`.
+    pub fn time_tolerance(&self, target_dir: &String) -> TimeTolerance {
+        let o = &self.program_options;
+        let granularity = probe_time_granularity(Path::new(target_dir), !self.read_only)
+            .or_else(|| infer_time_granularity(Path::new(target_dir)));
+        match granularity {
+            Some(granularity) => info!(
+                "{} stores modification times to within {:?}.",
+                target_dir, granularity
+            ),
+            None if o.modify_window.is_none() => info!(
+                "Unable to probe the timestamp precision of {}; comparing times exactly.",
+                target_dir
+            ),
+            None => {}
+        }
+
+        TimeTolerance::for_target(granularity, o.modify_window, o.ignore_time_shifts)
+    }
+
+    /// Whether paths are matched case-sensitively for `target_dir`.
+    pub fn is_case_sensitive(&self, target_dir: &String) -> bool {
+        match self.program_options.case_sensitivity {
+            CaseSensitivity::Sensitive => true,
+            CaseSensitivity::Insensitive => false,
+            CaseSensitivity::Auto => {
+                match probe_case_sensitive(Path::new(target_dir), !self.read_only) {
+                    Some(sensitive) => {
+                        info!(
+                            "{} is case-{}.",
+                            target_dir,
+                            if sensitive {
+                                "sensitive"
+                            } else {
+                                "insensitive"
+                            }
+                        );
+                        sensitive
+                    }
+                    None => {
+                        let sensitive = !cfg!(any(windows, target_os = "macos"));
+                        warn!(
+                            "Unable to probe the case sensitivity of {}; assuming case-{}.",
+                            target_dir,
+                            if sensitive {
+                                "sensitive"
+                            } else {
+                                "insensitive"
+                            }
+                        );
+                        sensitive
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn enumerate_directory(
+        &self,
+        source_dir: &String,
+        dir_type: &str,
+        case_sensitive: bool,
+        symlinks: SymlinkPolicy,
+    ) -> Vec<FileInfoParser> {
+        self.enumerate(source_dir, dir_type, case_sensitive, symlinks, false)
+    }
+
+    /// Enumerates a target directory. Links in the target are never
+    /// followed, so nothing outside it is touched, and files stored
+    /// compressed are described by the originals they hold.
+    pub fn enumerate_target(
+        &self,
+        target_dir: &String,
+        case_sensitive: bool,
+    ) -> Vec<FileInfoParser> {
+        self.enumerate(
+            target_dir,
+            "target",
+            case_sensitive,
+            SymlinkPolicy::CopyAsLink,
+            true,
+        )
+    }
+
+    fn enumerate(
+        &self,
+        source_dir: &String,
+        dir_type: &str,
+        case_sensitive: bool,
+        symlinks: SymlinkPolicy,
+        map_compressed: bool,
+    ) -> Vec<FileInfoParser> {
         info!("Enumerating the {} directory...", dir_type);
-        let files1 = crate::files::get_all_files(source_dir).unwrap();
-        let results1 = files1
-            .iter()
-            .map(|x| FileInfoParser::new(x, source_dir))
-            .filter(|x| {
-                x.match_extension(self.program_options.extensions.clone())
+        if self.read_only && !Path::new(source_dir).exists() {
+            warn!("The {} directory {} doesn't exist.", dir_type, source_dir);
+            return Vec::new();
+        }
+        let base: Arc<Path> = Arc::from(Path::new(source_dir));
+        let files1 = crate::files::get_all_files(&base, symlinks).unwrap();
+        let mut results1 = files1
+            .par_iter()
+            .map(|(path, metadata)| {
+                FileInfoParser::with_metadata(path, &base, metadata.clone(), case_sensitive)
             })
             .collect::<Vec<FileInfoParser>>();
+        if map_compressed {
+            results1 = map_compressed_entries(results1, case_sensitive);
+        }
+        results1.retain(|x| x.match_extension(self.program_options.extensions.clone()));
         info!("{} item(s) found in {}.", &files1.len(), dir_type);
         results1
     }
 
-    fn filter_skip_actions(&self, actions: Vec<FileInfoParserAction>) -> Vec<FileInfoParserAction> {
-        let skip_folders = self
-            .program_options
+    /// Returns the configured skip folder that contains the given entry.
+    pub fn find_skip_folder(&self, file: &FileInfoParser) -> Option<String> {
+        self.find_skip_folder_of(file.get_relative_path())
+    }
+
+    /// Returns the configured skip folder that contains the given path.
+    pub fn find_skip_folder_of(&self, relative: &RelativePath) -> Option<String> {
+        self.program_options
             .get_skip_folders()
             .iter()
-            .map(|x| PathParser::new(x))
-            .collect::<Vec<PathParser>>();
+            .map(PathParser::new)
+            .find(|x| relative.contains_all_of_segment(&x.get_segment()))
+            .map(|x| x.get_segment().get_default_segment_string())
+    }
 
+    fn filter_skip_actions(&self, actions: Vec<FileInfoParserAction>) -> Vec<FileInfoParserAction> {
         let mut actions_after_skipping = Vec::<FileInfoParserAction>::new();
-        if skip_folders.is_empty() {
+        if self.program_options.get_skip_folders().is_empty() {
             return actions;
         }
 
         let mut delete_actions = actions
             .iter()
             .filter(|x| x.action_type == ActionType::Delete)
-            .map(|x| x.clone())
+            .cloned()
             .collect::<Vec<FileInfoParserAction>>();
 
         let non_delete_actions = actions
             .iter()
             .filter(|x| x.action_type != ActionType::Delete)
-            .map(|x| x.clone())
+            .cloned()
             .collect::<Vec<FileInfoParserAction>>();
 
         for action in non_delete_actions {
-            for skip_folder in &skip_folders {
-                let skip_segment = skip_folder.get_segment().get_default_segment_string();
-                let action_source = action.source.clone().unwrap();
-                let action_segment = action_source.get_segment();
-                let skip_folder_segment = &skip_folder.get_segment();
-                if action_segment.contains_all_of_segment(skip_folder_segment) {
-                    let source_path = &action_source.get_path();
+            let action_source = action.source.as_ref().unwrap();
+            match self.find_skip_folder(action_source) {
+                Some(skip_segment) => {
+                    let source_path = &action_source.display_path();
                     warn!(
                         "Skipped {} because path '{}' is skipped.",
                         source_path, skip_segment
                     );
-                } else {
-                    actions_after_skipping.push(action.clone());
                 }
+                None => actions_after_skipping.push(action),
             }
         }
 
@@ -154,46 +324,65 @@ impl ChangeDetector {
     fn remap_update_actions(
         &self,
         in_both: Vec<(FileInfoParser, FileInfoParser)>,
+        broken_hard_links: &HashSet<OsString>,
+        tolerance: TimeTolerance,
         actions: &mut Vec<FileInfoParserAction>,
-    ) {
-        info!("Enumerating possible update actions...");
+    ) -> usize {
+        info!("Enumerating possible update actions by {}...", self.compare);
         let mut ignore_counter = 0;
         let mut use_counter = 0;
         let mut directory_counter = 0;
-        for (first, second) in in_both {
-            if !first.is_file || !second.is_file {
-                directory_counter += 1;
-                continue;
-            }
-    
-            let mut include = false;
-
-            if self.program_options.update_compare_size {
-                let first_len = first.metadata.len();
-                let second_len = second.metadata.len();
-                include = include || (first_len != second_len);
-            }
-
-            if self.program_options.update_compare_modified {
-                let first_modified = first.metadata.modified().unwrap();
-                let second_modified = second.metadata.modified().unwrap();
-                include = include || first_modified != second_modified
-            }
-
-            if self.program_options.update_compare_md5 {
-                let first_hash = build_file_comparative_hash(&first);
-                let second_hash = build_file_comparative_hash(&second);
-                include = include || first_hash.trim() != second_hash.trim();
-            }
-    
-            if include {
-                actions.push(FileInfoParserAction::new(first, second, ActionType::Update));
-                use_counter += 1;
-            } else {
-                ignore_counter += 1;
+        let compared = in_both
+            .into_par_iter()
+            .map(|(first, second)| {
+                let link_involved = first.is_symlink() || second.is_symlink();
+                if !link_involved && (!first.is_file || !second.is_file) {
+                    if first.is_file || second.is_file {
+                        return (first, second, None);
+                    }
+                    let mut reasons = self.find_metadata_reasons(&first, &second);
+                    if self.program_options.preserve_times
+                        && !same_modified(&first, &second, tolerance)
+                    {
+                        reasons.push(UpdateReason::Times);
+                    }
+                    if reasons.is_empty() {
+                        return (first, second, None);
+                    }
+                    return (first, second, Some(reasons));
+                }
+                let mut reasons = self.find_update_reasons(&first, &second, tolerance);
+                if broken_hard_links.contains(first.get_relative_path().key()) {
+                    reasons.push(UpdateReason::HardLink);
+                }
+                (first, second, Some(reasons))
+            })
+            .collect::<Vec<(FileInfoParser, FileInfoParser, Option<Vec<UpdateReason>>)>>();
+
+        for (first, second, reasons) in compared {
+            match reasons {
+                None => directory_counter += 1,
+                Some(reasons) if reasons.is_empty() => ignore_counter += 1,
+                Some(reasons) => {
+                    let action_type = if reasons.iter().all(UpdateReason::is_metadata) {
+                        ActionType::UpdateMetadata
+                    } else {
+                        ActionType::Update
+                    };
+                    debug!(
+                        "{} needs an {} because of its {}.",
+                        first.display_path(),
+                        action_type,
+                        reasons.iter().join(", ")
+                    );
+                    let mut action = FileInfoParserAction::new(first, second, action_type);
+                    action.reasons = reasons;
+                    actions.push(action);
+                    use_counter += 1;
+                }
             }
         }
-    
+
         info!(
             "{} update actions on directories ignored.",
             directory_counter
@@ -206,6 +395,214 @@ impl ChangeDetector {
             "{} update actions used based on file criteria.",
             use_counter
         );
+        ignore_counter
+    }
+
+    /// Drops the updates `--update-policy` doesn't allow. Returns the
+    /// remaining actions and the relative paths of the dropped updates whose
+    /// target copy is newer than the source.
+    fn apply_update_policy(
+        &self,
+        actions: Vec<FileInfoParserAction>,
+        tolerance: TimeTolerance,
+    ) -> (Vec<FileInfoParserAction>, Vec<String>) {
+        let policy = self.program_options.update_policy;
+        if policy == UpdatePolicy::Always {
+            return (actions, Vec::new());
+        }
+
+        let mut allowed = Vec::<FileInfoParserAction>::new();
+        let mut newer_on_target = Vec::<String>::new();
+        for action in actions {
+            let (source, destination) = match (action.source.as_ref(), action.destination.as_ref())
+            {
+                (Some(source), Some(destination)) => (source, destination),
+                _ => {
+                    allowed.push(action);
+                    continue;
+                }
+            };
+            let permitted = match (policy, &action.action_type) {
+                (UpdatePolicy::Never, _) => false,
+                (_, ActionType::UpdateMetadata) => true,
+                (UpdatePolicy::Newer, _) => is_newer(source, destination, tolerance),
+                (UpdatePolicy::Larger, _) => source.content_length() > destination.content_length(),
+                _ => true,
+            };
+            if permitted {
+                allowed.push(action);
+                continue;
+            }
+
+            let path = source.get_relative_path().display();
+            if is_newer(destination, source, tolerance) {
+                warn!(
+                    "Skipped {} because the target copy is newer than the source.",
+                    path
+                );
+                newer_on_target.push(path);
+            } else {
+                info!("Skipped {} because of the {} update policy.", path, policy);
+            }
+        }
+        newer_on_target.sort();
+        (allowed, newer_on_target)
+    }
+
+    fn find_update_reasons(
+        &self,
+        first: &FileInfoParser,
+        second: &FileInfoParser,
+        tolerance: TimeTolerance,
+    ) -> Vec<UpdateReason> {
+        let mut reasons = Vec::<UpdateReason>::new();
+
+        // Links are compared by where they point; their own size and times
+        // don't matter.
+        if first.is_symlink() || second.is_symlink() {
+            if first.link_target != second.link_target {
+                reasons.push(UpdateReason::LinkTarget);
+            }
+            return reasons;
+        }
+
+        if !stored_as_expected(self.compression.as_ref(), first, second) {
+            reasons.push(UpdateReason::Compression);
+        }
+
+        // Compressed targets are compared by the size and hash of the
+        // original recorded in their sidecar.
+        let compare = &self.compare;
+        let size_differs = compare.size && first.content_length() != second.content_length();
+        // Contents of different sizes can't match, so only hash equal sizes.
+        let hash_differs = compare.hash && (size_differs || !self.same_content(first, second));
+        if size_differs {
+            reasons.push(UpdateReason::Size);
+        }
+
+        // A differing mtime usually means the contents changed too, unless a
+        // matching hash proves otherwise; then only the time needs fixing.
+        if !same_modified(first, second, tolerance) {
+            let content_verified = compare.hash && !size_differs && !hash_differs;
+            if compare.modified && !content_verified {
+                reasons.push(UpdateReason::Modified);
+            } else if self.program_options.preserve_times {
+                reasons.push(UpdateReason::Times);
+            }
+        }
+
+        if hash_differs {
+            reasons.push(UpdateReason::Hash);
+        }
+
+        reasons.append(&mut self.find_metadata_reasons(first, second));
+        reasons
+    }
+
+    /// Compares the replicated permissions, owners, extended attributes and
+    /// ACLs of two entries. Modification times are compared by the caller.
+    fn find_metadata_reasons(
+        &self,
+        first: &FileInfoParser,
+        second: &FileInfoParser,
+    ) -> Vec<UpdateReason> {
+        let mut reasons = Vec::<UpdateReason>::new();
+        if self.program_options.preserve_permissions
+            && permission_bits(&first.metadata) != permission_bits(&second.metadata)
+        {
+            reasons.push(UpdateReason::Mode);
+        }
+        if self.program_options.preserve_owner && owner(&first.metadata) != owner(&second.metadata)
+        {
+            reasons.push(UpdateReason::Owner);
+        }
+        reasons.append(&mut self.find_attribute_reasons(first, second));
+        reasons
+    }
+
+    /// Compares the replicated extended attributes and ACLs of two entries.
+    /// Entries that can't be read are treated as different so that writing
+    /// them is attempted and any error is reported there.
+    fn find_attribute_reasons(
+        &self,
+        first: &FileInfoParser,
+        second: &FileInfoParser,
+    ) -> Vec<UpdateReason> {
+        let filter = match self.attribute_filter.as_ref() {
+            Some(filter) => filter,
+            None => return Vec::new(),
+        };
+
+        let first_attributes = read_attributes(&first.get_path(), filter);
+        let second_attributes = read_attributes(&second.get_path(), filter);
+        let (first_attributes, second_attributes) = match (first_attributes, second_attributes) {
+            (Ok(x), Ok(y)) => (x, y),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!(
+                    "Unable to read the attributes of {}: {}",
+                    first.display_path(),
+                    e
+                );
+                return vec![UpdateReason::Xattr];
+            }
+        };
+
+        let mut differing = first_attributes
+            .iter()
+            .filter(|x| !second_attributes.contains(x))
+            .chain(
+                second_attributes
+                    .iter()
+                    .filter(|x| !first_attributes.contains(x)),
+            )
+            .map(|(name, _)| is_acl(name))
+            .collect::<Vec<bool>>();
+        differing.sort();
+        differing.dedup();
+        differing
+            .into_iter()
+            .map(|acl| match acl {
+                true => UpdateReason::Acl,
+                false => UpdateReason::Xattr,
+            })
+            .collect()
+    }
+
+    fn build_file_hash(&self, file_info: &FileInfoParser) -> Option<String> {
+        let o = &self.program_options;
+        if let Some(compressed) = file_info.compressed.as_ref() {
+            if compressed.hash_algorithm != o.hash_algorithm.to_string() {
+                debug!(
+                    "{} was hashed with {}, not {}.",
+                    file_info.display_path(),
+                    compressed.hash_algorithm,
+                    o.hash_algorithm
+                );
+                return None;
+            }
+            return Some(compressed.hash.clone());
+        }
+        let path = file_info.get_path();
+        match hash_file_with(&path, o.hash_algorithm, o.hash_buffer_size, o.hash_mmap) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                error!("Unable to hash {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Files that cannot be hashed are treated as different so that the
+    /// copy is attempted and any error is reported there.
+    fn same_content(&self, first: &FileInfoParser, second: &FileInfoParser) -> bool {
+        let (first_hash, second_hash) = rayon::join(
+            || self.build_file_hash(first),
+            || self.build_file_hash(second),
+        );
+        match (first_hash, second_hash) {
+            (Some(first_hash), Some(second_hash)) => first_hash == second_hash,
+            _ => false,
+        }
     }
 
     fn enumerate_actions(
@@ -213,19 +610,22 @@ impl ChangeDetector {
         in_first_only: Vec<FileInfoParser>,
         in_second_only: Vec<FileInfoParser>,
         in_both: Vec<(FileInfoParser, FileInfoParser)>,
-    ) -> Vec<FileInfoParserAction> {
+        broken_hard_links: &HashSet<OsString>,
+        tolerance: TimeTolerance,
+    ) -> (Vec<FileInfoParserAction>, usize) {
         let mut actions = Vec::<FileInfoParserAction>::new();
 
         let mut first_paths = remap_create_actions(in_first_only);
         let mut second_paths = remap_delete_actions(in_second_only);
-    
+
         actions.append(&mut first_paths);
         actions.append(&mut second_paths);
-    
-        self.remap_update_actions(in_both, &mut actions);
-    
+
+        let unchanged =
+            self.remap_update_actions(in_both, broken_hard_links, tolerance, &mut actions);
+
         info!("{} total actions found.", &actions.len());
-        actions
+        (actions, unchanged)
     }
 }
 
@@ -249,82 +649,197 @@ fn remap_delete_actions(in_second_only: Vec<FileInfoParser>) -> Vec<FileInfoPars
     second_paths
 }
 
-fn check_deleted_files(
-    file_info_target: Vec<FileInfoParser>,
-    source_hash: HashMap<String, String>,
-) -> Vec<FileInfoParser> {
-    info!("Checking for deleted files...");
-    let mut in_second_only = Vec::<FileInfoParser>::new();
-    for file2 in file_info_target {
-        let key = file2
-            .get_segment()
-            .get_default_segment_string()
-            .to_lowercase();
-        if !source_hash.contains_key(&key) {
-            in_second_only.push(file2);
-        }
+/// Entries whose modification time can't be read compare as equal.
+fn same_modified(
+    first: &FileInfoParser,
+    second: &FileInfoParser,
+    tolerance: TimeTolerance,
+) -> bool {
+    match (first.metadata.modified(), second.metadata.modified()) {
+        (Ok(x), Ok(y)) => tolerance.same(x, y),
+        _ => true,
+    }
+}
+
+/// Whether `first` was modified later than `second`, beyond the tolerance.
+fn is_newer(first: &FileInfoParser, second: &FileInfoParser, tolerance: TimeTolerance) -> bool {
+    match (first.metadata.modified(), second.metadata.modified()) {
+        (Ok(x), Ok(y)) => tolerance.newer(x, y),
+        _ => false,
     }
-    info!("{} items to be deleted.", &in_second_only.len());
-    in_second_only
 }
 
-fn check_created_updated_files(
-    file_info_source: Vec<FileInfoParser>,
-    target_hash: HashMap<String, String>,
-    target_dir: &String,
-    in_both: &mut Vec<(FileInfoParser, FileInfoParser)>,
-    in_first_only: &mut Vec<FileInfoParser>,
-) {
-    info!("Checking for created or updated files...");
-    for f in file_info_source {
-        let key = f
-            .get_segment()
-            .get_default_segment_string()
-            .to_lowercase();
-        if target_hash.contains_key(&key) {
-            let f2 = &target_hash[&key];
-            let fif = FileInfoParser::new(&f2, target_dir);
-            in_both.push((f, fif));
-        } else {
-            in_first_only.push(f);
+/// Leaves out the source files in `collisions`, and the target files they
+/// would be stored as, so they are reported instead of synced. Returns the
+/// colliding names and how many entries were left out.
+fn drop_collisions(
+    collisions: Vec<Vec<RelativePath>>,
+    file_info_source: &mut Vec<FileInfoParser>,
+    file_info_target: &mut Vec<FileInfoParser>,
+) -> (Vec<Vec<String>>, usize) {
+    let keys = collisions
+        .iter()
+        .flatten()
+        .map(|x| x.key().to_os_string())
+        .collect::<HashSet<OsString>>();
+    let count = file_info_source.len() + file_info_target.len();
+    file_info_source.retain(|x| !keys.contains(x.get_relative_path().key()));
+    file_info_target.retain(|x| !keys.contains(x.get_relative_path().key()));
+    let skipped = count - file_info_source.len() - file_info_target.len();
+
+    let names = collisions
+        .iter()
+        .map(|x| x.iter().map(RelativePath::display).collect::<Vec<String>>())
+        .collect::<Vec<Vec<String>>>();
+    for names in &names {
+        warn!(
+            "{} would be stored under the same name in the target; skipping them.",
+            names.join(", ")
+        );
+    }
+    (names, skipped)
+}
+
+/// The result of matching source entries to target entries.
+pub struct JoinedEntries {
+    pub in_first_only: Vec<FileInfoParser>,
+    pub in_second_only: Vec<FileInfoParser>,
+    pub in_both: Vec<(FileInfoParser, FileInfoParser)>,
+    /// Source paths sharing a key, reported instead of synced.
+    pub collisions: Vec<Vec<String>>,
+    /// Entries left out because they are or lie beneath a collision.
+    pub skipped: usize,
+}
+
+/// Matches source entries to target entries by key with a sorted merge.
+/// Each list in the result is ordered by key. Source entries that share a
+/// key, which happens when names differing only by case meet a
+/// case-insensitive target, are left out together with everything beneath
+/// them and the target entries they would map to.
+pub fn join_entries(
+    mut file_info_source: Vec<FileInfoParser>,
+    mut file_info_target: Vec<FileInfoParser>,
+) -> JoinedEntries {
+    info!("Joining source and target entries...");
+    file_info_source
+        .par_sort_by(|a, b| a.get_relative_path().key().cmp(b.get_relative_path().key()));
+    file_info_target
+        .par_sort_by(|a, b| a.get_relative_path().key().cmp(b.get_relative_path().key()));
+
+    let colliding_keys = file_info_source
+        .windows(2)
+        .map(|x| {
+            (
+                x[0].get_relative_path().key(),
+                x[1].get_relative_path().key(),
+            )
+        })
+        .filter(|(a, b)| a == b)
+        .map(|(a, _)| a.to_os_string())
+        .collect::<HashSet<OsString>>();
+
+    let mut collisions = Vec::<Vec<String>>::new();
+    let mut skipped = 0;
+    if !colliding_keys.is_empty() {
+        let is_colliding = |x: &FileInfoParser| {
+            Path::new(x.get_relative_path().key())
+                .ancestors()
+                .any(|a| colliding_keys.contains(a.as_os_str()))
+        };
+        let (colliding, kept): (Vec<FileInfoParser>, Vec<FileInfoParser>) =
+            file_info_source.into_iter().partition(is_colliding);
+        file_info_source = kept;
+        let target_count = file_info_target.len();
+        file_info_target.retain(|x| !is_colliding(x));
+        skipped = colliding.len() + target_count - file_info_target.len();
+
+        for (key, group) in &colliding.iter().group_by(|x| x.get_relative_path().key()) {
+            if !colliding_keys.contains(key) {
+                continue;
+            }
+            let names = group
+                .map(|x| x.get_relative_path().display())
+                .collect::<Vec<String>>();
+            warn!(
+                "{} differ only by case and can't all be stored in the target; skipping them.",
+                names.join(", ")
+            );
+            collisions.push(names);
+        }
+    }
+
+    let mut in_first_only = Vec::<FileInfoParser>::new();
+    let mut in_second_only = Vec::<FileInfoParser>::new();
+    let mut in_both = Vec::<(FileInfoParser, FileInfoParser)>::new();
+    let joined = file_info_source
+        .into_iter()
+        .merge_join_by(file_info_target, |a, b| {
+            a.get_relative_path().key().cmp(b.get_relative_path().key())
+        });
+    for item in joined {
+        match item {
+            Left(first) => in_first_only.push(first),
+            Right(second) => in_second_only.push(second),
+            Both(first, mut second) => {
+                second.share_relative_path(&first);
+                in_both.push((first, second));
+            }
         }
     }
+
     info!("{} items to be created.", &in_first_only.len());
     info!("{} items to be updated.", &in_both.len());
-}
-
-fn locate_dir(dir: &String) {
-    let dir = Path::new(dir);
-    if !dir.exists() {
-        warn!("Source dir doesn't exist; creating it.");
-        fs::create_dir(dir).unwrap();
-    } else {
-        info!("Found.")
+    info!("{} items to be deleted.", &in_second_only.len());
+    JoinedEntries {
+        in_first_only,
+        in_second_only,
+        in_both,
+        collisions,
+        skipped,
     }
 }
 
-fn build_file_hash_list(file_info_list: &Vec<FileInfoParser>) -> HashMap<String, String> {
-    let mut file_hash = HashMap::<String, String>::new();
-    for file1 in file_info_list {
-        file_hash.insert(
-            file1
-                .get_segment()
-                .get_default_segment_string()
-                .to_lowercase(),
-            file1.get_path(),
-        );
+/// Picks, for every source hard link group present on the target, the
+/// target path of its first member in key order as the one the rest of the
+/// group is linked to. Also returns the keys of members whose target isn't
+/// linked to that path yet.
+fn find_hard_link_anchors(
+    in_both: &[(FileInfoParser, FileInfoParser)],
+) -> (HashMap<FileId, PathBuf>, HashSet<OsString>) {
+    let mut anchors = HashMap::<FileId, (PathBuf, Option<FileId>)>::new();
+    let mut broken = HashSet::<OsString>::new();
+    for (first, second) in in_both {
+        let id = match first.hard_link_id {
+            Some(id) if second.is_file && !second.is_symlink() => id,
+            _ => continue,
+        };
+        match anchors.get(&id) {
+            Some((_, anchor_id)) => {
+                if anchor_id.is_none() || second.hard_link_id != *anchor_id {
+                    broken.insert(first.get_relative_path().key().to_os_string());
+                }
+            }
+            None => {
+                anchors.insert(id, (second.get_path(), second.hard_link_id));
+            }
+        }
     }
-    file_hash
+
+    let anchors = anchors
+        .into_iter()
+        .map(|(id, (path, _))| (id, path))
+        .collect::<HashMap<FileId, PathBuf>>();
+    (anchors, broken)
 }
 
-fn build_file_comparative_hash(file_info: &FileInfoParser) -> String {
-    let filename = file_info.get_path();
-    let mut result_vec = Vec::new();
-    let mut f = std::fs::File::open(filename).expect("Unable to open file");
-    read_file_incremental_action(&mut f, | result: &[u8] | {
-        let h = xxh3_64(result);
-        result_vec.push(h);
-    });
-    let s_result = result_vec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("");
-    s_result
+/// Creates `dir` if it doesn't exist yet.
+pub fn locate_dir(dir: &String) -> std::io::Result<()> {
+    let path = Path::new(dir);
+    if !path.exists() {
+        warn!("{} doesn't exist; creating it.", dir);
+        fs::create_dir(path)?;
+    } else {
+        info!("Found.")
+    }
+    Ok(())
 }
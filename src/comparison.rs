@@ -0,0 +1,83 @@
+use crate::configuration::{ComparePreset, ProgramOptions};
+
+use std::fmt::Display;
+
+/// What the change detector compares to decide that a file needs updating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompareStrategy {
+    pub size: bool,
+    pub modified: bool,
+    pub hash: bool,
+    /// Hash both copies again after every copy.
+    pub verify: bool,
+}
+
+impl CompareStrategy {
+    pub fn from_preset(preset: ComparePreset) -> CompareStrategy {
+        match preset {
+            ComparePreset::Quick => CompareStrategy {
+                size: true,
+                modified: true,
+                hash: false,
+                verify: false,
+            },
+            ComparePreset::Checksum => CompareStrategy {
+                size: true,
+                modified: false,
+                hash: true,
+                verify: false,
+            },
+            ComparePreset::Paranoid => CompareStrategy {
+                size: true,
+                modified: true,
+                hash: true,
+                verify: true,
+            },
+        }
+    }
+
+    /// The `--compare` preset plus any `--update-compare-*` flags. Without
+    /// either, files are compared by size and mtime so that changed files
+    /// are never silently left alone.
+    pub fn from_options(o: &ProgramOptions) -> CompareStrategy {
+        let flags = CompareStrategy {
+            size: o.update_compare_size,
+            modified: o.update_compare_modified,
+            hash: o.update_compare_hash,
+            verify: false,
+        };
+        let preset = match o.compare_preset {
+            Some(preset) => preset,
+            None if flags.size || flags.modified || flags.hash => return flags,
+            None => ComparePreset::Quick,
+        };
+
+        let strategy = CompareStrategy::from_preset(preset);
+        CompareStrategy {
+            size: strategy.size || flags.size,
+            modified: strategy.modified || flags.modified,
+            hash: strategy.hash || flags.hash,
+            verify: strategy.verify,
+        }
+    }
+}
+
+impl Display for CompareStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let compared = [
+            (self.size, "size"),
+            (self.modified, "mtime"),
+            (self.hash, "hash"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| *name)
+        .collect::<Vec<&str>>()
+        .join(", ");
+        write!(f, "{}", compared)?;
+        if self.verify {
+            write!(f, " (copies verified)")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use crate::configuration::{CompressionCodec, ProgramOptions};
+use crate::constants::COMPRESSION_SIDECAR_SUFFIX;
+use crate::hashing::{hash_reader, HashingReader};
+use crate::paths::{FileInfoParser, RelativePath};
+use crate::utilities::{path_key, strip_path_suffix};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Appended to a stored file's name while it is written.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// What a file stored compressed on the target holds, as recorded in its
+/// sidecar.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CompressedFile {
+    pub codec: String,
+    /// Appended to the original name to form the stored name.
+    pub suffix: String,
+    /// The size of the original.
+    pub size: u64,
+    pub hash_algorithm: String,
+    /// The hash of the original.
+    pub hash: String,
+}
+
+/// Which files `--compress` stores compressed, and how.
+pub struct TargetCompression {
+    codec: CompressionCodec,
+    suffix: String,
+    extensions: Vec<String>,
+}
+
+impl TargetCompression {
+    pub fn from_options(o: &ProgramOptions) -> Option<TargetCompression> {
+        let codec = o.compress?;
+        Some(TargetCompression {
+            codec,
+            suffix: o
+                .compress_suffix
+                .clone()
+                .unwrap_or_else(|| codec.default_suffix().to_string()),
+            extensions: o.compress_extensions.clone(),
+        })
+    }
+
+    /// Whether `source` should be stored compressed.
+    pub fn applies_to(&self, source: &FileInfoParser) -> bool {
+        source.is_file && !source.is_symlink() && source.match_extension(self.extensions.clone())
+    }
+
+    /// Finds source files that would be stored under the name of a file
+    /// stored compressed, or of its sidecar, such as `a.log.zst` beside a
+    /// compressed `a.log`. Returns each clashing group, the compressed file
+    /// first.
+    pub fn find_collisions(
+        &self,
+        sources: &[FileInfoParser],
+        case_sensitive: bool,
+    ) -> Vec<Vec<RelativePath>> {
+        let by_key = sources
+            .iter()
+            .map(|x| (x.get_relative_path().key(), x))
+            .collect::<HashMap<&OsStr, &FileInfoParser>>();
+        let mut collisions = Vec::<Vec<RelativePath>>::new();
+        for source in sources.iter().filter(|x| self.applies_to(x)) {
+            let stored = stored_path(source.get_relative_path().as_path(), &self.suffix);
+            let mut names = vec![source.get_relative_path().clone()];
+            for clash in [&stored, &sidecar_path(&stored)] {
+                if let Some(other) = by_key.get(path_key(clash, case_sensitive).as_os_str()) {
+                    names.push(other.get_relative_path().clone());
+                }
+            }
+            if names.len() > 1 {
+                collisions.push(names);
+            }
+        }
+        collisions
+    }
+
+    /// Compresses `src` to `original` plus the suffix and writes the
+    /// sidecar recording the original's size and hash. Both are written
+    /// under a partial name first, and the stored file is put in place
+    /// before its sidecar, so a sidecar never describes contents that
+    /// aren't there.
+    pub fn compress_file(
+        &self,
+        src: &Path,
+        original: &Path,
+        o: &ProgramOptions,
+    ) -> io::Result<(PathBuf, CompressedFile)> {
+        let stored = stored_path(original, &self.suffix);
+        let partial = stored_path(&stored, PARTIAL_SUFFIX);
+
+        let result = self.write_compressed(src, &partial, o);
+        let (size, hash) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+
+        let compressed = CompressedFile {
+            codec: self.codec.to_string(),
+            suffix: self.suffix.clone(),
+            size,
+            hash_algorithm: o.hash_algorithm.to_string(),
+            hash,
+        };
+        let sidecar = sidecar_path(&stored);
+        let partial_sidecar = stored_path(&sidecar, PARTIAL_SUFFIX);
+        fs::write(&partial_sidecar, serde_json::to_vec_pretty(&compressed)?)?;
+        fs::rename(&partial, &stored)?;
+        fs::rename(&partial_sidecar, &sidecar)?;
+        Ok((stored, compressed))
+    }
+
+    /// Compresses `src` into `output`, hashing it in the same pass.
+    /// Returns the size and hash of the original.
+    fn write_compressed(
+        &self,
+        src: &Path,
+        output: &Path,
+        o: &ProgramOptions,
+    ) -> io::Result<(u64, String)> {
+        let mut input = HashingReader::new(
+            io::BufReader::with_capacity(o.hash_buffer_size.max(1), File::open(src)?),
+            o.hash_algorithm,
+        );
+        let output = File::create(output)?;
+        let size = match self.codec {
+            CompressionCodec::Zstd => {
+                let mut encoder = zstd::stream::Encoder::new(output, 0)?;
+                let size = io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+                size
+            }
+            CompressionCodec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(output, flate2::Compression::default());
+                let size = io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+                size
+            }
+        };
+        Ok((size, input.finish_hex()))
+    }
+}
+
+/// Whether a target entry is stored the way `source` should be: compressed
+/// with the configured suffix when compression applies, and plainly
+/// otherwise.
+pub fn stored_as_expected(
+    compression: Option<&TargetCompression>,
+    source: &FileInfoParser,
+    target: &FileInfoParser,
+) -> bool {
+    let expected = compression
+        .filter(|x| x.applies_to(source))
+        .map(|x| x.suffix.as_str());
+    let actual = target.compressed.as_ref().map(|x| x.suffix.as_str());
+    expected == actual
+}
+
+/// `path` with `suffix` appended to its name.
+fn stored_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut stored = path.as_os_str().to_os_string();
+    stored.push(suffix);
+    PathBuf::from(stored)
+}
+
+fn sidecar_path(stored: &Path) -> PathBuf {
+    stored_path(stored, COMPRESSION_SIDECAR_SUFFIX)
+}
+
+fn read_sidecar(stored: &Path) -> io::Result<CompressedFile> {
+    Ok(serde_json::from_slice(&fs::read(sidecar_path(stored))?)?)
+}
+
+/// Decompresses a stored file and hashes the original it holds.
+pub fn hash_stored(
+    stored: &Path,
+    compressed: &CompressedFile,
+    o: &ProgramOptions,
+) -> io::Result<String> {
+    let file = File::open(stored)?;
+    let mut decoder: Box<dyn Read> = match compressed.codec.as_str() {
+        "zstd" => Box::new(zstd::stream::Decoder::new(file)?),
+        "gzip" => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        codec => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown codec {}", codec),
+            ))
+        }
+    };
+    hash_reader(&mut decoder, o.hash_algorithm, o.hash_buffer_size)
+}
+
+/// Removes a stored file and, if it was compressed, its sidecar.
+pub fn remove_stored(entry: &FileInfoParser) -> io::Result<()> {
+    let path = entry.get_path();
+    fs::remove_file(&path)?;
+    if entry.compressed.is_some() {
+        match fs::remove_file(sidecar_path(&path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Describes the files of a target listing that are stored compressed by
+/// the originals they hold and drops their sidecars. Files whose sidecar
+/// is missing or unreadable are left as they are, so that they are
+/// replaced like any other stray file.
+pub fn map_compressed_entries(
+    entries: Vec<FileInfoParser>,
+    case_sensitive: bool,
+) -> Vec<FileInfoParser> {
+    let stored_paths = entries
+        .iter()
+        .filter(|x| x.is_file && !x.is_symlink())
+        .filter_map(|x| strip_path_suffix(&x.get_path(), COMPRESSION_SIDECAR_SUFFIX))
+        .collect::<Vec<PathBuf>>();
+    if stored_paths.is_empty() {
+        return entries;
+    }
+
+    let mut sidecars = HashMap::<PathBuf, CompressedFile>::new();
+    for stored in stored_paths {
+        if !stored.is_file() {
+            continue;
+        }
+        match read_sidecar(&stored) {
+            Ok(compressed) => {
+                sidecars.insert(stored, compressed);
+            }
+            Err(e) => warn!("Unable to read the sidecar of {}: {}", stored.display(), e),
+        }
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let path = entry.get_path();
+            let is_sidecar = strip_path_suffix(&path, COMPRESSION_SIDECAR_SUFFIX)
+                .is_some_and(|x| sidecars.contains_key(&x));
+            if is_sidecar {
+                return None;
+            }
+            match sidecars.get(&path) {
+                Some(compressed) if entry.is_file && !entry.is_symlink() => {
+                    Some(entry.into_compressed(compressed.clone(), case_sensitive))
+                }
+                _ => Some(entry),
+            }
+        })
+        .collect()
+}
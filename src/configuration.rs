@@ -1,10 +1,19 @@
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+
+use crate::constants::{
+    ARCHIVE_TARGET_PREFIX, ENCRYPTED_TARGET_PREFIX, LINKED_TARGET_PREFIX, MEGABYTE1,
+    SFTP_TARGET_PREFIX, SNAPSHOT_TARGET_PREFIX,
+};
+use crate::hashing::HashAlgorithm;
 
 use std::env;
+use std::ffi::OsString;
 use std::fmt::Display;
+use std::fs;
 use std::str::FromStr;
+use std::time::Duration;
 
-const HEADER: &'static str = r"
+const HEADER: &str = r"
 _____                       __      ____                                
 /\  __`\          __        /\ \    /\  _`\                              
 \ \ \/\ \  __  __/\_\    ___\ \ \/'\\ \ \/\_\    ___   _____   __  __    
@@ -14,32 +23,25 @@ _____                       __      ____
    \/__//_/\/___/  \/_/\/____/ \/_/\/_/\/___/  \/___/  \ \ \/  `/___/> \
                                                         \ \_\     /\___/
                                                          \/_/     \/__/ ";
-const SEPARATOR: &'static str =
-    r"----------------------------------------------------------------------";
+const SEPARATOR: &str = r"----------------------------------------------------------------------";
 
 pub fn get_header() -> String {
-    let current_dir = env::current_dir()
-        .unwrap()
-        .into_os_string()
-        .into_string()
-        .unwrap();
-
-    String::from(
-        HEADER.to_owned()
-            + "\n"
-            + SEPARATOR
-            + "\n"
-            + "Version: "
-            + crate_version!()
-            + "\n"
-            + "Author: "
-            + crate_authors!("\n")
-            + "\n"
-            + "Working Directory: "
-            + current_dir.as_str()
-            + "\n"
-            + SEPARATOR,
-    )
+    let current_dir = env::current_dir().unwrap().to_string_lossy().to_string();
+
+    HEADER.to_owned()
+        + "\n"
+        + SEPARATOR
+        + "\n"
+        + "Version: "
+        + crate_version!()
+        + "\n"
+        + "Author: "
+        + crate_authors!("\n")
+        + "\n"
+        + "Working Directory: "
+        + current_dir.as_str()
+        + "\n"
+        + SEPARATOR
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -73,53 +75,708 @@ impl Display for RuntimeType {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn to_level_filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// How relative paths in the source and a target are matched. `Auto`
+/// probes each target's filesystem.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseSensitivity {
+    Auto,
+    Sensitive,
+    Insensitive,
+}
+
+impl Display for CaseSensitivity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            CaseSensitivity::Auto => "auto",
+            CaseSensitivity::Sensitive => "sensitive",
+            CaseSensitivity::Insensitive => "insensitive",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// What to do with symbolic links found in the source. `FollowSafe` only
+/// follows links that resolve to a path inside the source directory.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    CopyAsLink,
+    Follow,
+    Skip,
+    FollowSafe,
+}
+
+impl Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            SymlinkPolicy::CopyAsLink => "copy-as-link",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::Skip => "skip",
+            SymlinkPolicy::FollowSafe => "follow-safe",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Named comparison strategies. `Quick` compares size and mtime,
+/// `Checksum` size and content hash, and `Paranoid` all three, verifying
+/// every copy afterwards.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComparePreset {
+    Quick,
+    Checksum,
+    Paranoid,
+}
+
+impl Display for ComparePreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            ComparePreset::Quick => "quick",
+            ComparePreset::Checksum => "checksum",
+            ComparePreset::Paranoid => "paranoid",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Which differing target files may be overwritten. `Never` only creates
+/// missing entries.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdatePolicy {
+    Always,
+    Newer,
+    Never,
+    Larger,
+}
+
+impl Display for UpdatePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            UpdatePolicy::Always => "always",
+            UpdatePolicy::Newer => "newer",
+            UpdatePolicy::Never => "never",
+            UpdatePolicy::Larger => "larger",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// How files matching `--compress-extensions` are stored on the target.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionCodec {
+    Zstd,
+    Gzip,
+}
+
+impl CompressionCodec {
+    /// The suffix appended to compressed names unless `--compress-suffix`
+    /// is given.
+    pub fn default_suffix(self) -> &'static str {
+        match self {
+            CompressionCodec::Zstd => ".zst",
+            CompressionCodec::Gzip => ".gz",
+        }
+    }
+}
+
+impl Display for CompressionCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            CompressionCodec::Zstd => "zstd",
+            CompressionCodec::Gzip => "gzip",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Compares the source with each target without changing anything.
+    /// Exits with status 1 when any target differs from the source.
+    Diff {
+        #[arg(long, value_name = "format", default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Rebuilds the tree held by an archive target from its base volume and
+    /// increments.
+    Restore {
+        /// The archive target's base volume, e.g. `project.tar.zst`.
+        archive: String,
+
+        directory: String,
+    },
+    /// Decrypts the tree held by an encrypted target, using `--key-file`.
+    Decrypt {
+        /// The encrypted target directory.
+        target: String,
+
+        directory: String,
+    },
+    /// Creates or verifies a checksum manifest of a directory tree.
+    Manifest {
+        #[command(subcommand)]
+        command: ManifestCommand,
+    },
+    /// Lists, restores and prunes the snapshots of a snapshot target.
+    Snapshots {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SnapshotCommand {
+    /// Lists the snapshots in a store, oldest first.
+    List {
+        /// The snapshot target directory.
+        store: String,
+    },
+    /// Rebuilds the tree held by a snapshot, the latest unless `--snapshot`
+    /// names another.
+    Restore {
+        /// The snapshot target directory.
+        store: String,
+
+        directory: String,
+
+        #[arg(long, value_name = "snapshot")]
+        snapshot: Option<String>,
+    },
+    /// Removes the snapshots the `--keep-*` options don't keep, then the
+    /// chunks no remaining snapshot uses.
+    Prune {
+        /// The snapshot target directory.
+        store: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ManifestCommand {
+    /// Writes a SHA256SUMS-compatible manifest of every file in a tree,
+    /// honoring the same filters as a sync.
+    Create {
+        directory: String,
+
+        #[arg(short = 'o', long, value_name = "output")]
+        output: Option<String>,
+
+        #[arg(long, value_name = "algorithm", default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+    },
+    /// Checks a tree against a manifest and reports missing, extra and
+    /// corrupted files. Exits with status 1 when anything is wrong.
+    Verify {
+        directory: String,
+
+        #[arg(short = 'm', long, value_name = "manifest")]
+        manifest: Option<String>,
+
+        #[arg(long, value_name = "algorithm", default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+
+        #[arg(long, value_name = "format", default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+}
+
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about)]
+#[command(author, version, about, long_about, args_override_self = true)]
 pub struct ProgramOptions {
-    #[arg(long, value_name = "runtime-type", default_value_t = RuntimeType::Batch)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(long, value_name = "runtime-type", default_value_t = RuntimeType::Batch, global = true)]
     pub runtime: RuntimeType,
 
-    #[arg(short = 's', long, value_name = "source-dir")]
-    source_directory: String,
+    #[arg(short = 's', long, value_name = "source-dir", global = true)]
+    source_directory: Option<String>,
 
-    #[arg(short = 't', long, value_name = "target-dirs")]
+    #[arg(short = 't', long, value_name = "target-dirs", global = true)]
     target_directories: Vec<String>,
 
-    #[arg(long, value_name = "check-time", default_value_t=20000)]
+    #[arg(
+        long,
+        value_name = "check-time",
+        default_value_t = 20000,
+        global = true
+    )]
     pub check_time: u64,
 
-    #[arg(short = 'e', long, value_name = "enable-deletes")]
+    #[arg(short = 'e', long, value_name = "enable-deletes", global = true)]
     pub enable_deletes: bool,
 
-    #[arg(long, value_name = "skip-folders")]
+    #[arg(long, value_name = "preserve-hard-links", global = true)]
+    pub preserve_hard_links: bool,
+
+    #[arg(long, value_name = "preserve-permissions", global = true)]
+    pub preserve_permissions: bool,
+
+    #[arg(long, value_name = "preserve-owner", global = true)]
+    pub preserve_owner: bool,
+
+    #[arg(long, value_name = "preserve-times", global = true)]
+    pub preserve_times: bool,
+
+    #[arg(long, value_name = "xattrs", global = true)]
+    pub xattrs: bool,
+
+    #[arg(
+        long,
+        value_name = "xattr-namespaces",
+        default_value = "user",
+        global = true
+    )]
+    pub xattr_namespaces: Vec<String>,
+
+    #[arg(long, value_name = "acls", global = true)]
+    pub acls: bool,
+
+    #[arg(long, value_name = "compress", global = true)]
+    pub compress: Option<CompressionCodec>,
+
+    #[arg(long, value_name = "compress-suffix", global = true)]
+    pub compress_suffix: Option<String>,
+
+    #[arg(long, value_name = "compress-extensions", global = true)]
+    pub compress_extensions: Vec<String>,
+
+    #[arg(long, value_name = "key-file", global = true)]
+    pub key_file: Option<String>,
+
+    #[arg(long, value_name = "encrypt-names", global = true)]
+    pub encrypt_names: bool,
+
+    #[arg(long, value_name = "ssh-key-file", global = true)]
+    pub ssh_key_file: Option<String>,
+
+    #[arg(long, value_name = "known-hosts", global = true)]
+    pub known_hosts: Option<String>,
+
+    #[arg(long, value_name = "keep-last", default_value_t = 0, global = true)]
+    pub keep_last: usize,
+
+    #[arg(long, value_name = "keep-hourly", default_value_t = 0, global = true)]
+    pub keep_hourly: usize,
+
+    #[arg(long, value_name = "keep-daily", default_value_t = 0, global = true)]
+    pub keep_daily: usize,
+
+    #[arg(long, value_name = "keep-weekly", default_value_t = 0, global = true)]
+    pub keep_weekly: usize,
+
+    #[arg(long, value_name = "skip-folders", global = true)]
     pub skip_folders: Vec<String>,
 
-    #[arg(short = 'x', long, value_name = "extensions")]
+    #[arg(short = 'x', long, value_name = "extensions", global = true)]
     pub extensions: Vec<String>,
 
-    #[arg(long, value_name = "use-config-file")]
+    #[arg(long, value_name = "case-sensitivity", default_value_t = CaseSensitivity::Auto, global = true)]
+    pub case_sensitivity: CaseSensitivity,
+
+    #[arg(long, value_name = "symlinks", default_value_t = SymlinkPolicy::Follow, global = true)]
+    pub symlinks: SymlinkPolicy,
+
+    #[arg(long, value_name = "update-policy", default_value_t = UpdatePolicy::Always, global = true)]
+    pub update_policy: UpdatePolicy,
+
+    #[arg(long, value_name = "use-config-file", global = true)]
     pub use_config_file: bool,
 
-    #[arg(long, value_name = "compare-modified")]
+    #[arg(
+        long,
+        value_name = "config-file",
+        default_value = "quick-copy.conf",
+        global = true
+    )]
+    pub config_file: String,
+
+    #[arg(long, value_name = "compare-modified", global = true)]
     pub update_compare_modified: bool,
 
-    #[arg(long, value_name = "compare-size")]
+    #[arg(long, value_name = "modify-window", value_parser = parse_modify_window, global = true)]
+    pub modify_window: Option<Duration>,
+
+    #[arg(long, value_name = "ignore-time-shifts", global = true)]
+    pub ignore_time_shifts: bool,
+
+    #[arg(long, value_name = "compare-size", global = true)]
     pub update_compare_size: bool,
 
-    #[arg(long, value_name = "compare-md5")]
-    pub update_compare_md5: bool,
+    #[arg(
+        long = "compare-hash",
+        value_name = "compare-hash",
+        visible_alias = "compare-md5",
+        alias = "update-compare-md5",
+        global = true
+    )]
+    pub update_compare_hash: bool,
+
+    #[arg(long = "compare", value_name = "compare", global = true)]
+    pub compare_preset: Option<ComparePreset>,
+
+    #[arg(long, value_name = "hash-algorithm", default_value_t = HashAlgorithm::Xxh3128, global = true)]
+    pub hash_algorithm: HashAlgorithm,
+
+    #[arg(long, value_name = "hash-buffer-size", default_value_t = MEGABYTE1, global = true)]
+    pub hash_buffer_size: usize,
+
+    #[arg(long, value_name = "copy-buffer-size", default_value_t = MEGABYTE1, global = true)]
+    pub copy_buffer_size: usize,
+
+    #[arg(long, value_name = "hash-mmap", global = true)]
+    pub hash_mmap: bool,
+
+    #[arg(long, value_name = "threads", default_value_t = 0, global = true)]
+    pub threads: usize,
+
+    #[arg(
+        long,
+        value_name = "job-name",
+        default_value = "default",
+        global = true
+    )]
+    pub job_name: String,
+
+    #[arg(long, value_name = "pre-cycle-hook", global = true)]
+    pub pre_cycle_hook: Option<String>,
+
+    #[arg(long, value_name = "post-cycle-hook", global = true)]
+    pub post_cycle_hook: Option<String>,
+
+    #[arg(long, value_name = "on-error-hook", global = true)]
+    pub on_error_hook: Option<String>,
+
+    #[arg(long, value_name = "per-file-copied-hook", global = true)]
+    pub per_file_copied_hook: Option<String>,
+
+    #[arg(long, value_name = "abort-on-pre-cycle-failure", global = true)]
+    pub abort_on_pre_cycle_failure: bool,
+
+    #[arg(long, value_name = "log-level", default_value_t = LogLevel::Info, global = true)]
+    pub log_level: LogLevel,
+
+    /// Also log to this file. Without it, logs only go to the console.
+    #[arg(long, value_name = "log-file", global = true)]
+    pub log_file: Option<String>,
+
+    #[arg(long, value_name = "log-max-size", global = true)]
+    pub log_max_size: Option<u64>,
+
+    #[arg(long, value_name = "log-rotate-daily", global = true)]
+    pub log_rotate_daily: bool,
+
+    #[arg(long, value_name = "log-retention", default_value_t = 7, global = true)]
+    pub log_retention: usize,
+
+    #[arg(long, value_name = "log-format", default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    #[arg(long, value_name = "report-dir", global = true)]
+    pub report_dir: Option<String>,
+
+    #[arg(long, value_name = "report-html", global = true)]
+    pub report_html: bool,
 }
 
 impl ProgramOptions {
     pub fn get_source_directory(&self) -> String {
-        self.source_directory.clone()
+        self.source_directory.clone().unwrap_or_default()
+    }
+
+    /// The path of a source given as `archive:<path>`.
+    pub fn get_source_archive(&self) -> Option<String> {
+        self.source_directory
+            .as_ref()
+            .and_then(|x| x.strip_prefix(ARCHIVE_TARGET_PREFIX))
+            .map(String::from)
+    }
+
+    /// What an archive source can't do: anything besides syncing directory
+    /// targets with the archived permissions and times. Empty for directory
+    /// sources.
+    pub fn get_archive_source_conflicts(&self) -> Vec<String> {
+        if self.get_source_archive().is_none() {
+            return Vec::new();
+        }
+        let mut conflicts = self
+            .get_special_targets()
+            .into_iter()
+            .map(|x| format!("the target {}", x))
+            .collect::<Vec<String>>();
+        if self.update_policy != UpdatePolicy::Always {
+            conflicts.push("--update-policy".to_string());
+        }
+        conflicts.extend(self.get_directory_only_options());
+        conflicts
+    }
+
+    /// What `sftp://` targets can't do: the options only directory targets
+    /// honour. Alongside directory targets these apply to those and the
+    /// SFTP targets ignore them, so they only conflict when there are none.
+    pub fn get_sftp_conflicts(&self) -> Vec<String> {
+        if self.get_sftp_targets().is_empty() || !self.get_target_directories().is_empty() {
+            return Vec::new();
+        }
+        self.get_directory_only_options()
+    }
+
+    /// The options set that only syncs to directory targets honour.
+    pub fn get_directory_only_options(&self) -> Vec<String> {
+        let mut options = Vec::<String>::new();
+        for (set, option) in [
+            (self.preserve_hard_links, "--preserve-hard-links"),
+            (self.preserve_owner, "--preserve-owner"),
+            (self.xattrs, "--xattrs"),
+            (self.acls, "--acls"),
+            (self.compress.is_some(), "--compress"),
+            (
+                self.per_file_copied_hook.is_some(),
+                "--per-file-copied-hook",
+            ),
+        ] {
+            if set {
+                options.push(option.to_string());
+            }
+        }
+        options
     }
 
     pub fn get_target_directories(&self) -> Vec<String> {
-        self.target_directories.clone()
+        self.target_directories
+            .iter()
+            .filter(|x| !is_special_target(x))
+            .cloned()
+            .collect()
+    }
+
+    /// Targets given with a prefix, such as `archive:` or `sftp://`, as
+    /// given.
+    pub fn get_special_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter(|x| is_special_target(x))
+            .cloned()
+            .collect()
+    }
+
+    /// The paths of targets given as `encrypted:<path>`.
+    pub fn get_encrypted_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter_map(|x| x.strip_prefix(ENCRYPTED_TARGET_PREFIX))
+            .map(String::from)
+            .collect()
+    }
+
+    /// The paths of targets given as `snapshot:<path>`.
+    pub fn get_snapshot_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter_map(|x| x.strip_prefix(SNAPSHOT_TARGET_PREFIX))
+            .map(String::from)
+            .collect()
+    }
+
+    /// The targets given as `sftp://user@host:/path`, prefix included.
+    pub fn get_sftp_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter(|x| x.starts_with(SFTP_TARGET_PREFIX))
+            .cloned()
+            .collect()
+    }
+
+    /// The paths of targets given as `linked:<path>`.
+    pub fn get_linked_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter_map(|x| x.strip_prefix(LINKED_TARGET_PREFIX))
+            .map(String::from)
+            .collect()
+    }
+
+    /// The paths of targets given as `archive:<path>`.
+    pub fn get_archive_targets(&self) -> Vec<String> {
+        self.target_directories
+            .iter()
+            .filter_map(|x| x.strip_prefix(ARCHIVE_TARGET_PREFIX))
+            .map(String::from)
+            .collect()
     }
 
     pub fn get_skip_folders(&self) -> Vec<String> {
         self.skip_folders.clone()
     }
 }
+
+/// Whether a target is given with a prefix rather than as a directory.
+fn is_special_target(target: &str) -> bool {
+    [
+        ARCHIVE_TARGET_PREFIX,
+        ENCRYPTED_TARGET_PREFIX,
+        SNAPSHOT_TARGET_PREFIX,
+        LINKED_TARGET_PREFIX,
+        SFTP_TARGET_PREFIX,
+    ]
+    .iter()
+    .any(|prefix| target.starts_with(prefix))
+}
+
+/// Parses `--modify-window`, a non-negative number of seconds.
+fn parse_modify_window(value: &str) -> Result<Duration, String> {
+    let seconds = value
+        .parse::<f64>()
+        .map_err(|e| format!("{} isn't a number of seconds: {}", value, e))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{} isn't a usable number of seconds", value))
+}
+
+/// Parses the command line and, when `--use-config-file` is given, the
+/// config file as well. The config file holds one option per line, e.g.
+/// `--log-level debug`; blank lines and lines starting with `#` are ignored.
+/// Options on the command line take precedence over the config file.
+pub fn load_program_options() -> ProgramOptions {
+    let args = env::args_os().collect::<Vec<OsString>>();
+    let o = ProgramOptions::parse_from(&args);
+    if !o.use_config_file {
+        return validate_program_options(o);
+    }
+
+    let contents = match fs::read_to_string(&o.config_file) {
+        Ok(c) => c,
+        Err(e) => ProgramOptions::command()
+            .error(
+                clap::error::ErrorKind::Io,
+                format!("Unable to read config file {}: {}", &o.config_file, e),
+            )
+            .exit(),
+    };
+
+    let mut merged_args = args.iter().take(1).cloned().collect::<Vec<OsString>>();
+    merged_args.append(&mut parse_config_args(&contents));
+    merged_args.extend(args.iter().skip(1).cloned());
+    validate_program_options(ProgramOptions::parse_from(merged_args))
+}
+
+fn validate_program_options(o: ProgramOptions) -> ProgramOptions {
+    let needs_source = matches!(o.command, None | Some(Command::Diff { .. }));
+    if needs_source && o.source_directory.is_none() {
+        ProgramOptions::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  --source-directory <source-dir>",
+            )
+            .exit()
+    }
+    let conflicts = o.get_archive_source_conflicts();
+    if !conflicts.is_empty() {
+        ProgramOptions::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "archive sources can't be used with {}",
+                    conflicts.join(", ")
+                ),
+            )
+            .exit()
+    }
+    let conflicts = o.get_sftp_conflicts();
+    if !conflicts.is_empty() {
+        ProgramOptions::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "sftp:// targets can't be used with {}",
+                    conflicts.join(", ")
+                ),
+            )
+            .exit()
+    }
+    o
+}
+
+pub fn parse_config_args(contents: &str) -> Vec<OsString> {
+    let mut result = Vec::<OsString>::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((flag, value)) if flag.starts_with('-') => {
+                result.push(OsString::from(flag));
+                result.push(OsString::from(value.trim()));
+            }
+            _ => result.push(OsString::from(line)),
+        }
+    }
+    result
+}
@@ -1,4 +1,15 @@
-
-//pub (crate) const MEGABYTE1: usize = 1_048_576;
+pub(crate) const MEGABYTE1: usize = 1_048_576;
+/// Marks a `-s` or `-t` value as an archive file rather than a directory.
+pub(crate) const ARCHIVE_TARGET_PREFIX: &str = "archive:";
+/// Marks a `-t` value as a directory holding encrypted copies.
+pub(crate) const ENCRYPTED_TARGET_PREFIX: &str = "encrypted:";
+/// Marks a `-t` value as a deduplicating snapshot store.
+pub(crate) const SNAPSHOT_TARGET_PREFIX: &str = "snapshot:";
+/// Marks a `-t` value as a directory of hard-linked snapshot directories.
+pub(crate) const LINKED_TARGET_PREFIX: &str = "linked:";
+/// Marks a `-t` value as a directory on a server reached over SSH.
+pub(crate) const SFTP_TARGET_PREFIX: &str = "sftp://";
+/// Appended to the name of a file stored compressed on the target to name
+/// the sidecar recording the original's size and hash.
+pub(crate) const COMPRESSION_SIDECAR_SUFFIX: &str = ".qcmeta";
 //pub(crate) const MEGABYTE4: usize = 4_194_304;
-pub (crate) const READ5192: usize = 5192;
\ No newline at end of file
@@ -1,123 +1,410 @@
+use crate::attributes::{read_attributes, write_attributes, AttributeFilter};
+use crate::comparison::CompareStrategy;
+use crate::compression::{hash_stored, remove_stored, CompressedFile, TargetCompression};
 use crate::configuration::ProgramOptions;
-use crate::paths::{ActionType, FileInfoParserAction, FileInfoParserActionList};
+use crate::copy_engine::copy_file;
+use crate::files::{create_symlink, set_modified, set_owner, FileId};
+use crate::hashing::hash_file_with;
+use crate::hooks::{HookEnvironment, HookType, Hooks};
+use crate::paths::{ActionType, FileInfoParser, FileInfoParserAction, FileInfoParserActionList};
+use crate::report::TargetReport;
 
 use itertools::Itertools;
-use log::info;
+use log::{debug, error, info};
 use std::cmp::Ordering;
-use std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fs, io};
 
 pub struct Copier {
     program_options: ProgramOptions,
+    hooks: Hooks,
+    attribute_filter: Option<AttributeFilter>,
+    compare: CompareStrategy,
+    compression: Option<TargetCompression>,
 }
 
 impl Copier {
     pub fn new(o: ProgramOptions) -> Copier {
-        Copier { program_options: o }
+        Copier {
+            hooks: Hooks::new(o.clone()),
+            attribute_filter: AttributeFilter::from_options(&o),
+            compare: CompareStrategy::from_options(&o),
+            compression: TargetCompression::from_options(&o),
+            program_options: o,
+        }
     }
 
-    pub fn incremental_copy(&self, action_list: Vec<FileInfoParserActionList>) {
+    pub fn incremental_copy(
+        &self,
+        action_list: Vec<FileInfoParserActionList>,
+    ) -> Vec<TargetReport> {
+        let mut reports = Vec::<TargetReport>::new();
         for action_item in action_list {
-            let actions = action_item.actions;
-
-            let ordered_creates = actions
-                .clone()
-                .into_iter()
-                .sorted_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
-                .filter(|x| {
-                    x.action_type == ActionType::Create || x.action_type == ActionType::Update
-                })
-                .collect::<Vec<FileInfoParserAction>>();
-
-            let ordered_deletes = actions
-                .clone()
-                .into_iter()
-                .sorted_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
-                .filter(|x| x.action_type == ActionType::Delete)
-                .rev()
-                .collect::<Vec<FileInfoParserAction>>();
-
-            let mut counter = 0;
-            let total = ordered_creates.len() + ordered_deletes.len();
-
-            for c in ordered_creates {
-                match c.action_type {
-                    ActionType::Create => {
-                        let source = c.source.as_ref();
-                        let dest_dir = action_item.target_directory.clone();
-                        let destination_segment = c.get_destination_from_segment(&dest_dir);
-
-                        let src = source.unwrap().get_path();
-                        let dst = destination_segment;
-
-                        if c.source.unwrap().is_file {
-                            info!("Copying {} to {}", &src, &dst);
-                            fs::copy(src, dst).unwrap();
-                        } else {
-                            info!("Creating dir {}", &dst);
-                            fs::create_dir(dst).unwrap();
-                        }
-                    }
-                    ActionType::Update => {
-                        let source = c.source.as_ref();
-                        let src = source.unwrap().get_path();
-                        let dst = c.destination.unwrap().get_path();
-
-                        if c.source.unwrap().is_file {
-                            info!("Copying {} to {}", &src, &dst);
-                            fs::copy(&src, dst).unwrap();
-                        } else {
-                            info!("Creating dir {}", &dst);
-                            fs::create_dir(dst).unwrap();
+            let mut report =
+                TargetReport::new(&action_item.source_directory, &action_item.target_directory);
+            report.skips = action_item.skipped;
+            report.collisions = action_item.collisions.clone();
+            report.newer_on_target = action_item.newer_on_target.clone();
+
+            let environment = self.hooks.target_environment(&action_item);
+            self.copy_target(&action_item, &environment, &mut report);
+            report.finish();
+            reports.push(report);
+        }
+        info!("Copy operations completed");
+        reports
+    }
+
+    fn run_file_copied_hook(&self, environment: &HookEnvironment, src: &Path, dst: &Path) {
+        let mut file_environment = environment.clone();
+        file_environment.push((String::from("QC_SOURCE_PATH"), src.into()));
+        file_environment.push((String::from("QC_DESTINATION_PATH"), dst.into()));
+        self.hooks.run(HookType::PerFileCopied, &file_environment);
+    }
+
+    fn copy_target(
+        &self,
+        action_item: &FileInfoParserActionList,
+        environment: &HookEnvironment,
+        report: &mut TargetReport,
+    ) {
+        let actions = &action_item.actions;
+
+        let ordered_creates = actions
+            .clone()
+            .into_iter()
+            .sorted_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .filter(|x| {
+                matches!(
+                    x.action_type,
+                    ActionType::Create | ActionType::Update | ActionType::UpdateMetadata
+                )
+            })
+            .collect::<Vec<FileInfoParserAction>>();
+
+        let ordered_deletes = actions
+            .clone()
+            .into_iter()
+            .sorted_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .filter(|x| x.action_type == ActionType::Delete)
+            .rev()
+            .collect::<Vec<FileInfoParserAction>>();
+
+        let mut counter = 0;
+        let total = ordered_creates.len() + ordered_deletes.len();
+        let mut hard_links = action_item.hard_links.clone();
+        let mut directory_times = Vec::<(PathBuf, SystemTime)>::new();
+
+        for c in ordered_creates {
+            match self.copy_action(&c, action_item, environment, &mut hard_links) {
+                Ok((path, bytes)) => {
+                    let source = c.source.as_ref().unwrap();
+                    if self.program_options.preserve_times
+                        && !source.is_file
+                        && !source.is_symlink()
+                    {
+                        if let Ok(modified) = source.metadata.modified() {
+                            directory_times.push((path.clone(), modified));
                         }
                     }
-                    ActionType::Delete => {
-                        info!("Nothing to do.");
-                    }
+                    report.record_change(&c.action_type, &path.to_string_lossy(), bytes)
+                }
+                Err(e) => {
+                    let source_path = c.source.as_ref().unwrap().display_path();
+                    error!("Unable to {} {}: {}", c.action_type, &source_path, e);
+                    report.record_error(format!("{} {}: {}", c.action_type, source_path, e));
+                }
+            }
+            counter += 1;
+            info!(
+                "{} / {} operations performed ({}%).",
+                counter,
+                total,
+                ((counter as f64 / total as f64) * 100.0).round() as i64
+            );
+        }
+
+        if !self.program_options.enable_deletes && !ordered_deletes.is_empty() {
+            info!("Deleted suppressed by config");
+            report.skips += ordered_deletes.len();
+        } else {
+            self.delete_target(ordered_deletes, action_item, report, &mut counter, total);
+        }
+
+        // Directory times are set last, deepest first, since creating and
+        // removing entries inside a directory changes its mtime.
+        for (path, modified) in directory_times.into_iter().rev() {
+            if let Err(e) = set_modified(&path, modified) {
+                error!("Unable to set the times of {}: {}", path.display(), e);
+                report.record_error(format!("times {}: {}", path.display(), e));
+            }
+        }
+    }
+
+    fn delete_target(
+        &self,
+        ordered_deletes: Vec<FileInfoParserAction>,
+        action_item: &FileInfoParserActionList,
+        report: &mut TargetReport,
+        counter: &mut usize,
+        total: usize,
+    ) {
+        for d in ordered_deletes {
+            match self.delete_action(&d, action_item) {
+                Ok(path) => report.record_change(&d.action_type, &path.to_string_lossy(), 0),
+                Err(e) => {
+                    let destination_path = d.destination.as_ref().unwrap().display_path();
+                    error!("Unable to delete {}: {}", &destination_path, e);
+                    report.record_error(format!("delete {}: {}", destination_path, e));
+                }
+            }
+            *counter += 1;
+            info!(
+                "{} / {} operations performed ({}%).",
+                counter,
+                total,
+                ((*counter as f64 / total as f64) * 100.0).round() as i64
+            );
+        }
+    }
+
+    fn copy_action(
+        &self,
+        c: &FileInfoParserAction,
+        action_item: &FileInfoParserActionList,
+        environment: &HookEnvironment,
+        hard_links: &mut HashMap<FileId, PathBuf>,
+    ) -> io::Result<(PathBuf, u64)> {
+        let job = self.program_options.job_name.as_str();
+        let target_dir = action_item.target_directory.as_str();
+        let source = c.source.as_ref().unwrap();
+        let src = source.get_path();
+        // Updated files are written under their original name, and compressed
+        // again from there if they still should be.
+        let dst = match c.action_type {
+            ActionType::Create => c.get_destination_from_segment(&action_item.target_directory),
+            ActionType::Update => c.destination.as_ref().unwrap().get_original_path(),
+            _ => c.destination.as_ref().unwrap().get_path(),
+        };
+        let action = c.action_type.to_string();
+        let reasons = c.reasons.iter().join(",");
+        let display_dst = dst.to_string_lossy();
+        // Creates have no reasons to show.
+        let because = if reasons.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", reasons)
+        };
+
+        if c.action_type == ActionType::UpdateMetadata {
+            info!(
+                job,
+                target = target_dir,
+                action = action.as_str(),
+                path = display_dst.as_ref(),
+                reasons = reasons.as_str();
+                "Updating metadata of {} ({})", &display_dst, &reasons
+            );
+            self.apply_metadata(source, &dst, source.is_file)?;
+            return Ok((dst, 0));
+        }
+
+        // Replace rather than write through a link, and swap a regular entry
+        // for a link.
+        if let Some(destination) = c.destination.as_ref() {
+            if source.is_symlink() || destination.is_symlink() {
+                if destination.is_file {
+                    fs::remove_file(&dst)?;
+                } else {
+                    fs::remove_dir(&dst)?;
                 }
-                counter += 1;
+            }
+        }
+
+        if let Some(link_target) = source.link_target.as_ref() {
+            info!(
+                job,
+                target = target_dir,
+                action = action.as_str(),
+                path = display_dst.as_ref();
+                "Linking {} to {}", &display_dst, link_target.display()
+            );
+            create_symlink(link_target, &dst)?;
+            self.run_file_copied_hook(environment, &src, &dst);
+            Ok((dst, 0))
+        } else if source.is_file {
+            if let Some(compression) = self.compression.as_ref().filter(|x| x.applies_to(source)) {
                 info!(
-                    "{} / {} operations performed ({}%).",
-                    counter,
-                    total,
-                    ((counter as f64 / total as f64) * 100.0).round() as i64
+                    job,
+                    target = target_dir,
+                    action = action.as_str(),
+                    path = display_dst.as_ref(),
+                    reasons = reasons.as_str(),
+                    bytes = source.metadata.len();
+                    "Compressing {} to {}{}", src.display(), &display_dst, &because
                 );
+                let (stored, compressed) =
+                    compression.compress_file(&src, &dst, &self.program_options)?;
+                if let Some(destination) = c.destination.as_ref() {
+                    if destination.get_path() != stored {
+                        remove_stored(destination)?;
+                    }
+                }
+                if self.compare.verify {
+                    self.verify_compressed(&stored, &compressed)?;
+                }
+                self.apply_metadata(source, &stored, true)?;
+                self.run_file_copied_hook(environment, &src, &stored);
+                return Ok((stored, compressed.size));
+            }
+            if let Some(destination) = c.destination.as_ref().filter(|x| x.compressed.is_some()) {
+                remove_stored(destination)?;
             }
 
-            for d in ordered_deletes {
-                match d.action_type {
-                    ActionType::Create => {
-                        info!("Nothing to do.")
-                    }
-                    ActionType::Update => {
-                        info!("Nothing to do.");
-                    }
-                    ActionType::Delete => {
-                        if self.program_options.enable_deletes {
-                            let destination = d.destination.as_ref();
-                            let destination_path = destination.unwrap().get_path();
-                            let file = destination.unwrap().is_file;
-                            if file {
-                                info!("Remove file {}", &destination_path);
-                                fs::remove_file(destination_path).unwrap();
-                            } else {
-                                info!("Remove directory {}", &destination_path);
-                                fs::remove_dir(destination_path).unwrap();
-                            }
-                        } else {
-                            info!("Deleted suppressed by config");
-                            break;
-                        }
+            let link_id = source
+                .hard_link_id
+                .filter(|_| self.program_options.preserve_hard_links);
+            if let Some(existing) = link_id.and_then(|id| hard_links.get(&id)) {
+                if existing != &dst {
+                    info!(
+                        job,
+                        target = target_dir,
+                        action = action.as_str(),
+                        path = display_dst.as_ref();
+                        "Hard linking {} to {}", &display_dst, existing.display()
+                    );
+                    if fs::symlink_metadata(&dst).is_ok() {
+                        fs::remove_file(&dst)?;
                     }
+                    fs::hard_link(existing, &dst)?;
+                    self.run_file_copied_hook(environment, &src, &dst);
+                    return Ok((dst, 0));
                 }
-                counter += 1;
-                info!(
-                    "{} / {} operations performed ({}%).",
-                    counter,
-                    total,
-                    ((counter as f64 / total as f64) * 100.0).round() as i64
-                );
             }
+
+            let bytes = source.metadata.len();
+            info!(
+                job,
+                target = target_dir,
+                action = action.as_str(),
+                path = display_dst.as_ref(),
+                reasons = reasons.as_str(),
+                bytes;
+                "Copying {} to {}{}", src.display(), &display_dst, &because
+            );
+            let (copied, method) = copy_file(&src, &dst, self.program_options.copy_buffer_size)?;
+            debug!("Copied {} using {}.", &display_dst, method);
+            if self.compare.verify {
+                self.verify_copy(&src, &dst)?;
+            }
+            self.apply_metadata(source, &dst, true)?;
+            if let Some(id) = link_id {
+                hard_links.entry(id).or_insert_with(|| dst.clone());
+            }
+            self.run_file_copied_hook(environment, &src, &dst);
+            Ok((dst, copied))
+        } else {
+            info!(
+                job,
+                target = target_dir,
+                action = action.as_str(),
+                path = display_dst.as_ref();
+                "Creating dir {}", &display_dst
+            );
+            fs::create_dir(&dst)?;
+            self.apply_metadata(source, &dst, false)?;
+            Ok((dst, 0))
         }
-        info!("Copy operations completed");
+    }
+
+    /// Hashes both copies of a file again, failing if they differ.
+    fn verify_copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let o = &self.program_options;
+        let (source_hash, target_hash) = rayon::join(
+            || hash_file_with(src, o.hash_algorithm, o.hash_buffer_size, o.hash_mmap),
+            || hash_file_with(dst, o.hash_algorithm, o.hash_buffer_size, o.hash_mmap),
+        );
+        if source_hash? != target_hash? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the copy does not match the source",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decompresses a stored file, failing if it doesn't hold the original
+    /// recorded in its sidecar.
+    fn verify_compressed(&self, stored: &Path, compressed: &CompressedFile) -> io::Result<()> {
+        if hash_stored(stored, compressed, &self.program_options)? != compressed.hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the compressed copy does not match the source",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replicates the preserved metadata of `source` onto `dst`: the owner
+    /// first, since changing it can clear set-id bits, then extended
+    /// attributes and ACLs, the modification time, and last permissions,
+    /// which may leave the file read-only. File times are also copied
+    /// whenever they are compared, or every copy would look changed on the
+    /// next cycle.
+    fn apply_metadata(
+        &self,
+        source: &FileInfoParser,
+        dst: &Path,
+        set_times: bool,
+    ) -> io::Result<()> {
+        let o = &self.program_options;
+        if o.preserve_owner {
+            set_owner(dst, &source.metadata)?;
+        }
+        if let Some(filter) = self.attribute_filter.as_ref() {
+            write_attributes(dst, &read_attributes(&source.get_path(), filter)?, filter)?;
+        }
+        if (o.preserve_times || self.compare.modified) && set_times {
+            set_modified(dst, source.metadata.modified()?)?;
+        }
+        if o.preserve_permissions {
+            fs::set_permissions(dst, source.metadata.permissions())?;
+        }
+        Ok(())
+    }
+
+    fn delete_action(
+        &self,
+        d: &FileInfoParserAction,
+        action_item: &FileInfoParserActionList,
+    ) -> io::Result<PathBuf> {
+        let job = self.program_options.job_name.as_str();
+        let target_dir = action_item.target_directory.as_str();
+        let destination = d.destination.as_ref().unwrap();
+        let destination_path = destination.get_path();
+        let display_path = destination.display_path();
+
+        if destination.is_file {
+            info!(
+                job,
+                target = target_dir,
+                action = "delete",
+                path = display_path.as_str();
+                "Remove file {}", &display_path
+            );
+            remove_stored(destination)?;
+        } else {
+            info!(
+                job,
+                target = target_dir,
+                action = "delete",
+                path = display_path.as_str();
+                "Remove directory {}", &display_path
+            );
+            fs::remove_dir(&destination_path)?;
+        }
+        Ok(destination_path)
     }
 }
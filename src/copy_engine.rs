@@ -0,0 +1,204 @@
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+
+/// How a file's contents ended up on the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// The target shares the source's blocks (`FICLONE`).
+    Reflink,
+    /// The kernel copied the data (`copy_file_range`).
+    CopyFileRange,
+    /// The data was read and written through a buffer.
+    Buffered,
+}
+
+impl Display for CopyMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Buffered => "buffered",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Copies the contents and permissions of `src` to `dst`, overwriting it in
+/// place if it exists. On Linux a reflink is tried first; otherwise only the
+/// data regions found with `SEEK_DATA`/`SEEK_HOLE` are copied, so holes stay
+/// holes, using `copy_file_range` where the kernel supports it and a
+/// `buffer_size` buffer where it doesn't. Returns the file length and the
+/// method used.
+pub fn copy_file(src: &Path, dst: &Path, buffer_size: usize) -> io::Result<(u64, CopyMethod)> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::copy_file(src, dst, buffer_size)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = buffer_size;
+        std::fs::copy(src, dst).map(|x| (x, CopyMethod::Buffered))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::CopyMethod;
+
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    pub fn copy_file(src: &Path, dst: &Path, buffer_size: usize) -> io::Result<(u64, CopyMethod)> {
+        let source = File::open(src)?;
+        let metadata = source.metadata()?;
+        let target = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dst)?;
+        let length = metadata.len();
+
+        let method = if reflink(&source, &target) {
+            CopyMethod::Reflink
+        } else {
+            copy_data_regions(&source, &target, length, buffer_size)?
+        };
+
+        target.set_len(length)?;
+        fs::set_permissions(dst, metadata.permissions())?;
+        Ok((length, method))
+    }
+
+    fn reflink(source: &File, target: &File) -> bool {
+        // SAFETY: both descriptors are open for the duration of the call.
+        unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) == 0 }
+    }
+
+    /// Copies every data region of `source` to the same offset in `target`,
+    /// skipping holes.
+    fn copy_data_regions(
+        source: &File,
+        target: &File,
+        length: u64,
+        buffer_size: usize,
+    ) -> io::Result<CopyMethod> {
+        let mut method = CopyMethod::CopyFileRange;
+        let mut offset = 0;
+        while offset < length {
+            let start = match seek(source, offset, libc::SEEK_DATA) {
+                Ok(Some(start)) => start,
+                // Past the last data region: the rest is a hole.
+                Ok(None) => break,
+                // The filesystem can't report holes; copy everything.
+                Err(_) => offset,
+            };
+            let end = match seek(source, start, libc::SEEK_HOLE) {
+                Ok(Some(end)) => end.min(length),
+                _ => length,
+            };
+
+            if method == CopyMethod::CopyFileRange
+                && !copy_range(source, target, start, end - start)?
+            {
+                method = CopyMethod::Buffered;
+            }
+            if method == CopyMethod::Buffered {
+                copy_buffered(source, target, start, end - start, buffer_size)?;
+            }
+            offset = end;
+        }
+        Ok(method)
+    }
+
+    /// `lseek` on `file`, returning `None` when there's no data after `offset`.
+    fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+        // SAFETY: the descriptor is open for the duration of the call.
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        if result >= 0 {
+            return Ok(Some(result as u64));
+        }
+
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(error),
+        }
+    }
+
+    /// Copies a range with `copy_file_range`. Returns false, having copied
+    /// nothing, if the kernel or filesystem doesn't support it.
+    fn copy_range(source: &File, target: &File, start: u64, length: u64) -> io::Result<bool> {
+        let mut offset_in = start as libc::loff_t;
+        let mut offset_out = start as libc::loff_t;
+        let mut remaining = length;
+        while remaining > 0 {
+            // SAFETY: both descriptors are open and the offsets outlive the
+            // call.
+            let copied = unsafe {
+                libc::copy_file_range(
+                    source.as_raw_fd(),
+                    &mut offset_in,
+                    target.as_raw_fd(),
+                    &mut offset_out,
+                    remaining as usize,
+                    0,
+                )
+            };
+            if copied < 0 {
+                let error = io::Error::last_os_error();
+                let unsupported = matches!(
+                    error.raw_os_error(),
+                    Some(libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL)
+                );
+                if unsupported && remaining == length {
+                    return Ok(false);
+                }
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            if copied == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "source file shrank while copying",
+                ));
+            }
+            remaining -= copied as u64;
+        }
+        Ok(true)
+    }
+
+    fn copy_buffered(
+        source: &File,
+        target: &File,
+        start: u64,
+        length: u64,
+        buffer_size: usize,
+    ) -> io::Result<()> {
+        let mut buffer = vec![0u8; buffer_size.max(1)];
+        let mut offset = start;
+        let end = start + length;
+        while offset < end {
+            let wanted = buffer.len().min((end - offset) as usize);
+            let read = match source.read_at(&mut buffer[..wanted], offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "source file shrank while copying",
+                    ))
+                }
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            target.write_all_at(&buffer[..read], offset)?;
+            offset += read as u64;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use crate::change_detector::ChangeDetector;
+use crate::configuration::{OutputFormat, ProgramOptions};
+use crate::paths::{ActionType, FileInfoParserActionList};
+
+use log::error;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DifferingPath {
+    pub path: String,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TargetDiff {
+    pub source_directory: String,
+    pub target_directory: String,
+    pub only_in_source: Vec<String>,
+    pub only_in_target: Vec<String>,
+    pub differing: Vec<DifferingPath>,
+    pub collisions: Vec<Vec<String>>,
+    pub newer_on_target: Vec<String>,
+}
+
+impl TargetDiff {
+    pub fn from_action_list(action_list: &FileInfoParserActionList) -> TargetDiff {
+        let mut diff = TargetDiff {
+            source_directory: action_list.source_directory.clone(),
+            target_directory: action_list.target_directory.clone(),
+            only_in_source: Vec::new(),
+            only_in_target: Vec::new(),
+            differing: Vec::new(),
+            collisions: action_list.collisions.clone(),
+            newer_on_target: action_list.newer_on_target.clone(),
+        };
+
+        for action in &action_list.actions {
+            match action.action_type {
+                ActionType::Create => diff.only_in_source.push(
+                    action
+                        .source
+                        .as_ref()
+                        .unwrap()
+                        .get_relative_path()
+                        .display(),
+                ),
+                ActionType::Delete => diff.only_in_target.push(
+                    action
+                        .destination
+                        .as_ref()
+                        .unwrap()
+                        .get_relative_path()
+                        .display(),
+                ),
+                ActionType::Update | ActionType::UpdateMetadata => {
+                    diff.differing.push(DifferingPath {
+                        path: action
+                            .source
+                            .as_ref()
+                            .unwrap()
+                            .get_relative_path()
+                            .display(),
+                        reasons: action.reasons.iter().map(|x| x.to_string()).collect(),
+                    })
+                }
+            }
+        }
+
+        diff.only_in_source.sort();
+        diff.only_in_target.sort();
+        diff.differing.sort_by(|a, b| a.path.cmp(&b.path));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.only_in_source.is_empty()
+            && self.only_in_target.is_empty()
+            && self.differing.is_empty()
+            && self.collisions.is_empty()
+            && self.newer_on_target.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} -> {}\n", &self.source_directory, &self.target_directory);
+        if self.is_empty() {
+            text.push_str("  identical\n");
+        }
+        for path in &self.only_in_source {
+            text.push_str(&format!("  only in source: {}\n", path));
+        }
+        for path in &self.only_in_target {
+            text.push_str(&format!("  only in target: {}\n", path));
+        }
+        for differing in &self.differing {
+            text.push_str(&format!(
+                "  differs ({}): {}\n",
+                differing.reasons.join(", "),
+                &differing.path
+            ));
+        }
+        for names in &self.collisions {
+            text.push_str(&format!("  collision: {}\n", names.join(", ")));
+        }
+        for path in &self.newer_on_target {
+            text.push_str(&format!("  newer in target: {}\n", path));
+        }
+        text
+    }
+}
+
+/// Prints the differences between the source and every target and returns
+/// the process exit code: 0 when all targets match, 1 otherwise.
+pub fn run_diff(o: &ProgramOptions, format: OutputFormat) -> i32 {
+    if let Some(archive) = o.get_source_archive() {
+        error!("Diff doesn't support archive sources: {}", archive);
+        return 2;
+    }
+    let special_targets = o.get_special_targets();
+    for target in &special_targets {
+        error!("Diff only supports directory targets, not {}", target);
+    }
+    if !special_targets.is_empty() {
+        return 2;
+    }
+    let change_detector = ChangeDetector::new_read_only(o.clone());
+    let diffs = change_detector
+        .three_way_merge()
+        .iter()
+        .map(TargetDiff::from_action_list)
+        .collect::<Vec<TargetDiff>>();
+
+    match format {
+        OutputFormat::Text => {
+            for diff in &diffs {
+                print!("{}", diff.to_text());
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&diffs).unwrap()),
+    }
+
+    if diffs.iter().all(|x| x.is_empty()) {
+        0
+    } else {
+        1
+    }
+}
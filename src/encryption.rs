@@ -0,0 +1,592 @@
+use crate::archive::{
+    archive_key, build_index_entry, entry_differs, entry_time, prepare_restore_path,
+    restore_file_metadata, restore_path, EntryKind, IndexEntry,
+};
+use crate::change_detector::ChangeDetector;
+use crate::comparison::CompareStrategy;
+use crate::configuration::ProgramOptions;
+use crate::files::{create_symlink, set_modified};
+use crate::hashing::hash_file_with;
+use crate::paths::ActionType;
+use crate::report::TargetReport;
+
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::XChaCha20Poly1305;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Starts every encrypted file and the index, followed by the format
+/// version.
+const MAGIC: &[u8; 8] = b"QCCRYPT1";
+/// Appended to the names of encrypted files.
+const FILE_SUFFIX: &str = ".qce";
+const INDEX_NAME: &str = ".quick-copy-index";
+/// Plaintext bytes per STREAM chunk; each chunk gains a 16-byte tag.
+const CHUNK_SIZE: usize = 65536;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const STREAM_NONCE_SIZE: usize = 19;
+
+/// Describes an encrypted file; stored encrypted at its start, so files can
+/// be decrypted without the index.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FileHeader {
+    key: String,
+    stream_nonce: Vec<u8>,
+    #[serde(flatten)]
+    entry: IndexEntry,
+}
+
+/// What an encrypted target holds, with the plaintext size, times and hash
+/// of every file. Encrypted targets have no volumes, so `volume` is 0.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct EncryptedIndex {
+    encrypted_names: bool,
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn aead_error(_: chacha20poly1305::aead::Error) -> io::Error {
+    invalid_data("authentication failed; wrong key or corrupted data")
+}
+
+/// The keys an encrypted target is written with, derived from a key file
+/// holding 32 random bytes, raw or as 64 hex digits.
+pub struct TargetKeys {
+    cipher: XChaCha20Poly1305,
+    names: [u8; 32],
+}
+
+impl TargetKeys {
+    pub fn load(path: &Path) -> io::Result<TargetKeys> {
+        let contents = fs::read(path)?;
+        let text = String::from_utf8_lossy(&contents);
+        let text = text.trim();
+        let key = if contents.len() == 32 {
+            contents
+        } else if text.len() == 64 && text.chars().all(|x| x.is_ascii_hexdigit()) {
+            (0..64)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+                .collect()
+        } else {
+            return Err(invalid_data(
+                "the key file must hold 32 bytes, raw or as 64 hex digits",
+            ));
+        };
+        let key: [u8; 32] = key.try_into().unwrap();
+
+        Ok(TargetKeys {
+            cipher: XChaCha20Poly1305::new(
+                &blake3::derive_key("quick-copy encrypted target contents", &key).into(),
+            ),
+            names: blake3::derive_key("quick-copy encrypted target names", &key),
+        })
+    }
+
+    /// Encrypts a small value with a random nonce stored in front of it.
+    fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.append(&mut self.cipher.encrypt(&nonce, plaintext).map_err(aead_error)?);
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(invalid_data("truncated encrypted data"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(aead_error)
+    }
+
+    /// Where an entry is stored, relative to the target. With
+    /// `--encrypt-names` every name is replaced by a keyed hash of the path
+    /// up to it, so equal names in different directories don't match.
+    fn stored_path(&self, key: &str, kind: EntryKind, encrypt_names: bool) -> PathBuf {
+        let mut stored = match encrypt_names {
+            false => PathBuf::from(key),
+            true => {
+                let names = key.split('/').collect::<Vec<&str>>();
+                (1..=names.len())
+                    .map(|i| {
+                        let hash = blake3::keyed_hash(&self.names, names[..i].join("/").as_bytes());
+                        hash.to_hex()[..32].to_string()
+                    })
+                    .collect()
+            }
+        };
+        if kind == EntryKind::File {
+            let mut name = stored.into_os_string();
+            name.push(FILE_SUFFIX);
+            stored = PathBuf::from(name);
+        }
+        stored
+    }
+}
+
+/// Writes `path` through a partial file that only takes its final name once
+/// complete.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result = File::create(&partial).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()
+    });
+    match result {
+        Ok(()) => fs::rename(&partial, path),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Reads until `buffer` is full or the input ends, returning the length.
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn write_sealed(writer: &mut dyn Write, keys: &TargetKeys, plaintext: &[u8]) -> io::Result<()> {
+    let sealed = keys.seal(plaintext)?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(sealed.len() as u32).to_le_bytes())?;
+    writer.write_all(&sealed)
+}
+
+fn read_sealed(reader: &mut dyn Read, keys: &TargetKeys) -> io::Result<Vec<u8>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a quick-copy encrypted file"));
+    }
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let mut sealed = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut sealed)?;
+    keys.open(&sealed)
+}
+
+/// Encrypts `src` to `dst`: the sealed header, then the contents in
+/// authenticated chunks, the last one marked so truncation is detected.
+fn encrypt_file(keys: &TargetKeys, src: &Path, dst: &Path, header: &FileHeader) -> io::Result<()> {
+    write_atomically(dst, |writer| {
+        write_sealed(writer, keys, &serde_json::to_vec(header)?)?;
+        let mut encryptor = EncryptorBE32::from_aead(
+            keys.cipher.clone(),
+            GenericArray::from_slice(&header.stream_nonce),
+        );
+        let mut input = File::open(src)?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut next = vec![0u8; CHUNK_SIZE];
+        let mut length = read_full(&mut input, &mut chunk)?;
+        loop {
+            let next_length = read_full(&mut input, &mut next)?;
+            if next_length == 0 {
+                let sealed = encryptor
+                    .encrypt_last(&chunk[..length])
+                    .map_err(aead_error)?;
+                return writer.write_all(&sealed);
+            }
+            let sealed = encryptor
+                .encrypt_next(&chunk[..length])
+                .map_err(aead_error)?;
+            writer.write_all(&sealed)?;
+            std::mem::swap(&mut chunk, &mut next);
+            length = next_length;
+        }
+    })
+}
+
+/// Reads the header of an encrypted file, leaving `reader` at its contents.
+fn read_header(reader: &mut dyn Read, keys: &TargetKeys) -> io::Result<FileHeader> {
+    let header: FileHeader = serde_json::from_slice(&read_sealed(reader, keys)?)?;
+    if header.stream_nonce.len() != STREAM_NONCE_SIZE {
+        return Err(invalid_data("invalid stream nonce"));
+    }
+    Ok(header)
+}
+
+/// Decrypts the contents following a header into `writer`.
+fn decrypt_contents(
+    reader: &mut dyn Read,
+    keys: &TargetKeys,
+    header: &FileHeader,
+    writer: &mut dyn Write,
+) -> io::Result<u64> {
+    let mut decryptor = DecryptorBE32::from_aead(
+        keys.cipher.clone(),
+        GenericArray::from_slice(&header.stream_nonce),
+    );
+    let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut length = read_full(reader, &mut chunk)?;
+    let mut written = 0;
+    loop {
+        let next_length = read_full(reader, &mut next)?;
+        if next_length == 0 {
+            let plaintext = decryptor
+                .decrypt_last(&chunk[..length])
+                .map_err(aead_error)?;
+            writer.write_all(&plaintext)?;
+            return Ok(written + plaintext.len() as u64);
+        }
+        let plaintext = decryptor
+            .decrypt_next(&chunk[..length])
+            .map_err(aead_error)?;
+        writer.write_all(&plaintext)?;
+        written += plaintext.len() as u64;
+        std::mem::swap(&mut chunk, &mut next);
+        length = next_length;
+    }
+}
+
+fn read_index(target: &Path, keys: &TargetKeys) -> io::Result<Option<EncryptedIndex>> {
+    match File::open(target.join(INDEX_NAME)) {
+        Ok(file) => {
+            let index = read_sealed(&mut BufReader::new(file), keys)?;
+            Ok(Some(serde_json::from_slice(&index)?))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_index(target: &Path, keys: &TargetKeys, index: &EncryptedIndex) -> io::Result<()> {
+    write_atomically(&target.join(INDEX_NAME), |writer| {
+        write_sealed(writer, keys, &serde_json::to_vec(index)?)
+    })
+}
+
+/// Removes what an entry left on the target; directories only once empty.
+fn remove_stored(path: &Path, kind: EntryKind) -> io::Result<()> {
+    let result = match kind {
+        EntryKind::File => fs::remove_file(path),
+        EntryKind::Directory => fs::remove_dir(path),
+        EntryKind::Symlink => return Ok(()),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Encrypts the entries that changed since the last sync into `target`
+/// and records them in its index. Links are only recorded in the index.
+fn sync_encrypted(
+    o: &ProgramOptions,
+    source_dir: &String,
+    target: &str,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let key_file = o.key_file.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encrypted targets need --key-file",
+        )
+    })?;
+    let keys = TargetKeys::load(Path::new(key_file))?;
+    let target_dir = Path::new(target);
+    fs::create_dir_all(target_dir)?;
+    let mut index = match read_index(target_dir, &keys)? {
+        Some(index) => index,
+        None => EncryptedIndex {
+            encrypted_names: o.encrypt_names,
+            entries: BTreeMap::new(),
+        },
+    };
+    if index.encrypted_names != o.encrypt_names {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} was written {} --encrypt-names; decrypt it and sync to an empty target to change this",
+                target,
+                if index.encrypted_names { "with" } else { "without" }
+            ),
+        ));
+    }
+
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+    let mut seen = HashSet::<String>::new();
+    // Entries whose kind changed. Their old stored names never clash with
+    // the new ones, so they're removed after the deletes have emptied them.
+    let mut replaced = Vec::<(String, EntryKind)>::new();
+    let mut files = change_detector.enumerate_directory(source_dir, "source", true, o.symlinks);
+    files.sort_by(|a, b| a.get_relative_path().key().cmp(b.get_relative_path().key()));
+    for file in files {
+        if change_detector.find_skip_folder(&file).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        let key = match archive_key(&file) {
+            Some(key) => key,
+            None => {
+                warn!(
+                    "Skipped {} because encrypted paths must be valid UTF-8.",
+                    file.display_path()
+                );
+                report.skips += 1;
+                continue;
+            }
+        };
+        seen.insert(key.clone());
+
+        let mut entry = match build_index_entry(o, &compare, &file, 0) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Unable to read {}: {}", file.display_path(), e);
+                report.record_error(format!("read {}: {}", file.display_path(), e));
+                continue;
+            }
+        };
+        let stored = target_dir.join(keys.stored_path(&key, entry.kind, o.encrypt_names));
+        let action_type = match index.entries.get(&key) {
+            None => ActionType::Create,
+            Some(recorded) if entry_differs(recorded, &entry, &compare) => {
+                if recorded.kind != entry.kind {
+                    replaced.push((key.clone(), recorded.kind));
+                }
+                ActionType::Update
+            }
+            // A file removed from the target is written again.
+            Some(_) if entry.kind == EntryKind::File && !stored.is_file() => ActionType::Update,
+            Some(_) => continue,
+        };
+
+        let result = match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&stored),
+            EntryKind::Symlink => Ok(()),
+            EntryKind::File => (|| {
+                if entry.hash.is_none() {
+                    entry.hash = Some(hash_file_with(
+                        &file.get_path(),
+                        o.hash_algorithm,
+                        o.hash_buffer_size,
+                        o.hash_mmap,
+                    )?);
+                }
+                let mut stream_nonce = vec![0u8; STREAM_NONCE_SIZE];
+                OsRng.fill_bytes(&mut stream_nonce);
+                let header = FileHeader {
+                    key: key.clone(),
+                    stream_nonce,
+                    entry: entry.clone(),
+                };
+                info!(
+                    job = o.job_name.as_str(),
+                    target = target,
+                    action = action_type.to_string().as_str(),
+                    path = key.as_str();
+                    "Encrypting {} to {}", file.display_path(), stored.display()
+                );
+                stored
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| encrypt_file(&keys, &file.get_path(), &stored, &header))
+            })(),
+        };
+        match result {
+            Ok(()) => {
+                report.record_change(&action_type, &key, entry.size);
+                index.entries.insert(key, entry);
+            }
+            Err(e) => {
+                error!("Unable to {} {}: {}", action_type, file.display_path(), e);
+                report.record_error(format!("{} {}: {}", action_type, file.display_path(), e));
+            }
+        }
+    }
+
+    let deleted = index
+        .entries
+        .keys()
+        .filter(|x| !seen.contains(*x))
+        .cloned()
+        .collect::<Vec<String>>();
+    if !o.enable_deletes && !deleted.is_empty() {
+        info!("Deleted suppressed by config");
+        report.skips += deleted.len();
+    } else {
+        // Deepest first, so directories are empty when they are removed.
+        for key in deleted.into_iter().rev() {
+            let kind = index.entries[&key].kind;
+            let stored = target_dir.join(keys.stored_path(&key, kind, o.encrypt_names));
+            match remove_stored(&stored, kind) {
+                Ok(()) => {
+                    index.entries.remove(&key);
+                    report.record_change(&ActionType::Delete, &key, 0);
+                }
+                Err(e) => {
+                    error!("Unable to delete {}: {}", stored.display(), e);
+                    report.record_error(format!("delete {}: {}", stored.display(), e));
+                }
+            }
+        }
+    }
+
+    for (key, kind) in replaced.into_iter().rev() {
+        // What was beneath a replaced directory is only gone with deletes.
+        if kind == EntryKind::Directory && !o.enable_deletes {
+            continue;
+        }
+        let stored = target_dir.join(keys.stored_path(&key, kind, o.encrypt_names));
+        if let Err(e) = remove_stored(&stored, kind) {
+            error!("Unable to remove {}: {}", stored.display(), e);
+            report.record_error(format!("delete {}: {}", stored.display(), e));
+        }
+    }
+    write_index(target_dir, &keys, &index)
+}
+
+/// Syncs the source into every `encrypted:` target.
+pub fn sync_encrypted_targets(o: &ProgramOptions) -> Vec<TargetReport> {
+    let source_dir = o.get_source_directory();
+    o.get_encrypted_targets()
+        .iter()
+        .map(|target| {
+            info!("Encrypted target is {}", target);
+            let mut report = TargetReport::new(&source_dir, target);
+            if let Err(e) = sync_encrypted(o, &source_dir, target, &mut report) {
+                error!("Unable to update encrypted target {}: {}", target, e);
+                report.record_error(format!("encrypted {}: {}", target, e));
+            }
+            report.finish();
+            report
+        })
+        .collect()
+}
+
+/// Decrypts one file into `directory`, under the path in its header.
+fn decrypt_file(keys: &TargetKeys, stored: &Path, directory: &Path) -> io::Result<FileHeader> {
+    let mut reader = BufReader::new(File::open(stored)?);
+    let header = read_header(&mut reader, keys)?;
+    let path = restore_path(directory, &header.key)?;
+    prepare_restore_path(&path)?;
+    let mut writer = BufWriter::new(File::create(&path)?);
+    let written = decrypt_contents(&mut reader, keys, &header, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    if written != header.entry.size {
+        return Err(invalid_data("the decrypted size does not match the header"));
+    }
+    restore_file_metadata(&path, &header.entry)?;
+    Ok(header)
+}
+
+/// Lists the encrypted files under `directory` when the index is lost.
+fn find_encrypted_files(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_encrypted_files(&path, files)?;
+        } else if path.to_string_lossy().ends_with(FILE_SUFFIX) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts the tree an encrypted target holds into `directory`. Without
+/// an index, the files are found by walking the target and restored from
+/// their headers; directories that held no files and links are then lost.
+/// Returns the number of entries restored.
+pub fn decrypt_target(target: &Path, directory: &Path, key_file: &Path) -> io::Result<usize> {
+    let keys = TargetKeys::load(key_file)?;
+    fs::create_dir_all(directory)?;
+    let index = match read_index(target, &keys)? {
+        Some(index) => index,
+        None => {
+            warn!(
+                "{} has no index; restoring the files found in it.",
+                target.display()
+            );
+            let mut files = Vec::<PathBuf>::new();
+            find_encrypted_files(target, &mut files)?;
+            for file in &files {
+                decrypt_file(&keys, file, directory)?;
+            }
+            return Ok(files.len());
+        }
+    };
+
+    for (key, entry) in &index.entries {
+        let stored = target.join(keys.stored_path(key, entry.kind, index.encrypted_names));
+        match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(restore_path(directory, key)?)?,
+            EntryKind::Symlink => {
+                let path = restore_path(directory, key)?;
+                prepare_restore_path(&path)?;
+                let link_target = entry.link_target.as_deref().unwrap_or_default();
+                create_symlink(Path::new(link_target), &path)?;
+            }
+            EntryKind::File => {
+                let header = decrypt_file(&keys, &stored, directory)?;
+                if &header.key != key || header.entry.hash != entry.hash {
+                    return Err(invalid_data(&format!(
+                        "{} does not hold {}",
+                        stored.display(),
+                        key
+                    )));
+                }
+            }
+        }
+    }
+
+    // Directory times last, deepest first, since restoring their contents
+    // changes them.
+    for (key, entry) in index.entries.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            set_modified(&restore_path(directory, key)?, entry_time(entry))?;
+        }
+    }
+    Ok(index.entries.len())
+}
+
+/// Runs the decrypt subcommand and returns the process exit code.
+pub fn run_decrypt(o: &ProgramOptions, target: &str, directory: &str) -> i32 {
+    let key_file = match o.key_file.as_ref() {
+        Some(key_file) => key_file,
+        None => {
+            error!("Decrypting needs --key-file.");
+            return 2;
+        }
+    };
+    match decrypt_target(Path::new(target), Path::new(directory), Path::new(key_file)) {
+        Ok(count) => {
+            info!("Decrypted {} entries to {}", count, directory);
+            0
+        }
+        Err(e) => {
+            error!("Unable to decrypt {}: {}", target, e);
+            2
+        }
+    }
+}
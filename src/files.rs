@@ -1,8 +1,14 @@
-use std::path::Path;
+use crate::configuration::SymlinkPolicy;
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::str;
+use std::time::SystemTime;
 use std::{fs, io};
 
-use log::{debug, info};
+use log::{debug, info, warn};
+use rayon::prelude::*;
 
 #[allow(dead_code)]
 pub fn enumerate_files(path: &str) -> io::Result<Vec<String>> {
@@ -13,30 +19,267 @@ pub fn enumerate_files(path: &str) -> io::Result<Vec<String>> {
     info!("{} entries...", entries.len());
 
     for e in entries.clone() {
-        debug!("{}", e.to_str().unwrap());
+        debug!("{}", e.display());
     }
 
     let result = entries
         .iter()
-        .map(|x| x.clone().into_os_string().into_string().unwrap())
+        .map(|x| x.to_string_lossy().to_string())
         .collect::<Vec<String>>();
 
     Ok(result)
 }
 
-pub fn get_all_files(dir: &String) -> io::Result<Vec<String>> {
-    let mut result = Vec::<String>::new();
+/// Works out whether names in `dir` are case-sensitive. An existing entry
+/// is looked up again with its case swapped; if the directory is empty and
+/// `may_write` is set, a probe file is created and removed. Returns `None`
+/// when neither works.
+pub fn probe_case_sensitive(dir: &Path, may_write: bool) -> Option<bool> {
+    let names = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|x| x.ok())
+        .map(|x| x.file_name())
+        .collect::<HashSet<OsString>>();
+
+    for name in &names {
+        let swapped = match swap_case(name) {
+            Some(x) => x,
+            None => continue,
+        };
+        if names.contains(&swapped) {
+            return Some(true);
+        }
+        return Some(fs::symlink_metadata(dir.join(&swapped)).is_err());
+    }
+
+    if !may_write {
+        return None;
+    }
+    let probe = dir.join(format!(".quick-copy-case-probe-{}", std::process::id()));
+    fs::write(&probe, "").ok()?;
+    let swapped = dir.join(swap_case(probe.file_name()?)?);
+    let sensitive = fs::symlink_metadata(swapped).is_err();
+    let _ = fs::remove_file(&probe);
+    Some(sensitive)
+}
+
+/// The name with the case of every letter swapped, or `None` if that
+/// changes nothing or the name is not valid Unicode.
+fn swap_case(name: &OsStr) -> Option<OsString> {
+    let name = name.to_str()?;
+    let swapped = name
+        .chars()
+        .map(|x| {
+            if x.is_lowercase() {
+                x.to_uppercase().next().unwrap_or(x)
+            } else {
+                x.to_lowercase().next().unwrap_or(x)
+            }
+        })
+        .collect::<String>();
+    if swapped == name {
+        return None;
+    }
+    Some(OsString::from(swapped))
+}
+
+/// The device and inode of a file.
+pub type FileId = (u64, u64);
+
+/// The id shared by all hard links to a regular file, or `None` for files
+/// with a single link. Always `None` outside Unix.
+pub fn hard_link_id(metadata: &fs::Metadata) -> Option<FileId> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.is_file() && metadata.nlink() > 1 {
+            return Some((metadata.dev(), metadata.ino()));
+        }
+    }
+    let _ = metadata;
+    None
+}
+
+/// Identifies a directory for loop detection.
+#[cfg(unix)]
+type DirectoryId = FileId;
+#[cfg(not(unix))]
+type DirectoryId = PathBuf;
+
+#[cfg(unix)]
+fn directory_id(_path: &Path, metadata: &fs::Metadata) -> io::Result<DirectoryId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn directory_id(path: &Path, _metadata: &fs::Metadata) -> io::Result<DirectoryId> {
+    fs::canonicalize(path)
+}
+
+/// Recursively lists everything under `dir` together with its metadata,
+/// each directory followed by its contents. Subdirectories are walked in
+/// parallel; entries are sorted by name so the result is deterministic.
+///
+/// Symbolic links are handled according to `symlinks`. Links are returned
+/// with their own metadata under `CopyAsLink` and with their target's
+/// otherwise. Broken links and links that are not followed are left out.
+/// A directory that is also one of its own ancestors is reported and left
+/// out.
+pub fn get_all_files(
+    dir: &Path,
+    symlinks: SymlinkPolicy,
+) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+    let safe_root = match symlinks {
+        SymlinkPolicy::FollowSafe => Some(fs::canonicalize(dir)?),
+        _ => None,
+    };
+    let root_id = directory_id(dir, &fs::metadata(dir)?)?;
+    walk_directory(dir, symlinks, safe_root.as_deref(), &[root_id])
+}
+
+fn walk_directory(
+    dir: &Path,
+    symlinks: SymlinkPolicy,
+    safe_root: Option<&Path>,
+    ancestors: &[DirectoryId],
+) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+    let mut entries = Vec::<(PathBuf, fs::Metadata)>::new();
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
-        if path.is_dir() && Path::new(&dir) != path {
-            let cur_path = path.into_os_string().into_string().unwrap();
-            result.push(cur_path.clone());
-            let mut results = get_all_files(&cur_path)?;
-            result.append(&mut results);
+        let metadata = if entry.file_type()?.is_symlink() {
+            match resolve_symlink(&path, symlinks, safe_root) {
+                Some(metadata) => metadata,
+                None => continue,
+            }
         } else {
-            result.push(path.into_os_string().into_string().unwrap());
+            entry.metadata()?
+        };
+        entries.push((path, metadata));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let nested = entries
+        .into_par_iter()
+        .map(|(path, metadata)| {
+            let mut result = Vec::<(PathBuf, fs::Metadata)>::new();
+            if !metadata.is_dir() {
+                result.push((path, metadata));
+                return Ok(result);
+            }
+
+            let id = directory_id(&path, &metadata)?;
+            if ancestors.contains(&id) {
+                warn!(
+                    "Skipping {} because it loops back to one of its parents.",
+                    path.display()
+                );
+                return Ok(result);
+            }
+            let mut nested_ancestors = ancestors.to_vec();
+            nested_ancestors.push(id);
+            let mut children = walk_directory(&path, symlinks, safe_root, &nested_ancestors)?;
+            result.push((path, metadata));
+            result.append(&mut children);
+            Ok(result)
+        })
+        .collect::<io::Result<Vec<Vec<(PathBuf, fs::Metadata)>>>>()?;
+
+    Ok(nested.into_iter().flatten().collect())
+}
+
+/// The metadata to list a symbolic link with, or `None` to leave it out.
+fn resolve_symlink(
+    path: &Path,
+    symlinks: SymlinkPolicy,
+    safe_root: Option<&Path>,
+) -> Option<fs::Metadata> {
+    match symlinks {
+        SymlinkPolicy::CopyAsLink => fs::symlink_metadata(path).ok(),
+        SymlinkPolicy::Skip => {
+            debug!("Skipping symlink {}.", path.display());
+            None
+        }
+        SymlinkPolicy::Follow | SymlinkPolicy::FollowSafe => {
+            if let Some(safe_root) = safe_root {
+                let inside = fs::canonicalize(path)
+                    .map(|x| x.starts_with(safe_root))
+                    .unwrap_or(false);
+                if !inside {
+                    warn!(
+                        "Skipping symlink {} because it points outside the source.",
+                        path.display()
+                    );
+                    return None;
+                }
+            }
+            match fs::metadata(path) {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    warn!("Skipping broken symlink {}: {}", path.display(), e);
+                    None
+                }
+            }
         }
     }
-    Ok(result)
+}
+
+/// Creates a symbolic link at `link` pointing to `target`.
+pub fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        let resolved = link.parent().map(|x| x.join(target));
+        match resolved.map(|x| x.is_dir()) {
+            Some(true) => std::os::windows::fs::symlink_dir(target, link),
+            _ => std::os::windows::fs::symlink_file(target, link),
+        }
+    }
+}
+
+/// The permission bits compared and replicated by `--preserve-permissions`:
+/// the mode on Unix and the read-only flag elsewhere.
+pub fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        metadata.permissions().readonly() as u32
+    }
+}
+
+/// The owning user and group ids. Always `None` outside Unix.
+pub fn owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.uid(), metadata.gid()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Gives `path` the owner and group recorded in `metadata`.
+pub fn set_owner(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    match owner(metadata) {
+        #[cfg(unix)]
+        Some((uid, gid)) => std::os::unix::fs::chown(path, Some(uid), Some(gid)),
+        _ => Ok(()),
+    }
+}
+
+/// Sets the modification time of `path`, which may be a directory. The
+/// path isn't opened, so this works whatever its permissions are.
+pub fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    filetime::set_file_mtime(path, filetime::FileTime::from_system_time(time))
 }
@@ -0,0 +1,164 @@
+use crate::constants::MEGABYTE1;
+use crate::utilities::read_file_incremental_action;
+
+use clap::ValueEnum;
+use memmap2::Mmap;
+use sha2::Digest;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Xxh3,
+    #[value(name = "xxh3-128")]
+    Xxh3128,
+    Blake3,
+    Md5,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Xxh3128 => "xxh3-128",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Md5 => "md5",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+impl HashAlgorithm {
+    /// The conventional name of a checksum file for this algorithm.
+    pub fn manifest_file_name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256SUMS",
+            HashAlgorithm::Xxh3 => "XXH3SUMS",
+            HashAlgorithm::Xxh3128 => "XXH128SUMS",
+            HashAlgorithm::Blake3 => "B3SUMS",
+            HashAlgorithm::Md5 => "MD5SUMS",
+        }
+    }
+}
+
+enum FileHasher {
+    Sha256(sha2::Sha256),
+    Xxh3(Box<Xxh3>),
+    Xxh3128(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Md5(md5::Md5),
+}
+
+impl FileHasher {
+    fn new(algorithm: HashAlgorithm) -> FileHasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => FileHasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Xxh3 => FileHasher::Xxh3(Box::new(Xxh3::new())),
+            HashAlgorithm::Xxh3128 => FileHasher::Xxh3128(Box::new(Xxh3::new())),
+            HashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Md5 => FileHasher::Md5(md5::Md5::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(h) => h.update(data),
+            FileHasher::Xxh3(h) | FileHasher::Xxh3128(h) => h.update(data),
+            FileHasher::Blake3(h) => {
+                h.update(data);
+            }
+            FileHasher::Md5(h) => h.update(data),
+        }
+    }
+
+    fn finish_hex(self) -> String {
+        match self {
+            FileHasher::Sha256(h) => to_hex(&h.finalize()),
+            FileHasher::Xxh3(h) => format!("{:016x}", h.digest()),
+            FileHasher::Xxh3128(h) => format!("{:032x}", h.digest128()),
+            FileHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            FileHasher::Md5(h) => to_hex(&h.finalize()),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Streams a file through the given algorithm and returns the lowercase hex
+/// digest.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    hash_file_with(path, algorithm, MEGABYTE1, false)
+}
+
+/// Streams everything `reader` yields through the given algorithm and
+/// returns the lowercase hex digest.
+pub fn hash_reader(
+    reader: &mut dyn Read,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> io::Result<String> {
+    let mut hasher = FileHasher::new(algorithm);
+    read_file_incremental_action(reader, buffer_size, |chunk: &[u8]| hasher.update(chunk))?;
+    Ok(hasher.finish_hex())
+}
+
+/// Hashes everything read through it, so that data can be hashed in the
+/// same pass that copies it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: FileHasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algorithm: HashAlgorithm) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: FileHasher::new(algorithm),
+        }
+    }
+
+    /// The lowercase hex digest of everything read so far.
+    pub fn finish_hex(self) -> String {
+        self.hasher.finish_hex()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Like `hash_file`, reading `buffer_size` bytes at a time or, with `mmap`,
+/// hashing a memory map of the whole file.
+pub fn hash_file_with(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    mmap: bool,
+) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = FileHasher::new(algorithm);
+
+    if mmap && file.metadata()?.len() > 0 {
+        // The map is only read while hashing; a concurrent writer can at
+        // worst produce a hash that no longer matches, which a later cycle
+        // will pick up.
+        let map = unsafe { Mmap::map(&file)? };
+        for chunk in map.chunks(buffer_size.max(1)) {
+            hasher.update(chunk);
+        }
+    } else {
+        read_file_incremental_action(&mut file, buffer_size, |chunk: &[u8]| hasher.update(chunk))?;
+    }
+    Ok(hasher.finish_hex())
+}
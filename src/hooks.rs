@@ -0,0 +1,188 @@
+use crate::configuration::ProgramOptions;
+use crate::paths::{ActionType, FileInfoParserActionList};
+use crate::report::TargetReport;
+
+use log::{error, info, warn};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookType {
+    PreCycle,
+    PostCycle,
+    OnError,
+    PerFileCopied,
+}
+
+impl Display for HookType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            HookType::PreCycle => "pre_cycle",
+            HookType::PostCycle => "post_cycle",
+            HookType::OnError => "on_error",
+            HookType::PerFileCopied => "per_file_copied",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/// Variables passed to hook commands. Values are `OsString` so that paths
+/// which are not valid Unicode reach the hook unchanged.
+pub type HookEnvironment = Vec<(String, OsString)>;
+
+pub struct Hooks {
+    program_options: ProgramOptions,
+}
+
+impl Hooks {
+    pub fn new(o: ProgramOptions) -> Hooks {
+        Hooks { program_options: o }
+    }
+
+    fn get_command(&self, hook_type: HookType) -> Option<String> {
+        match hook_type {
+            HookType::PreCycle => self.program_options.pre_cycle_hook.clone(),
+            HookType::PostCycle => self.program_options.post_cycle_hook.clone(),
+            HookType::OnError => self.program_options.on_error_hook.clone(),
+            HookType::PerFileCopied => self.program_options.per_file_copied_hook.clone(),
+        }
+    }
+
+    /// Variables for the pre-cycle hook, which runs before the source is
+    /// read.
+    pub fn cycle_environment(&self) -> HookEnvironment {
+        vec![
+            (
+                String::from("QC_JOB"),
+                OsString::from(&self.program_options.job_name),
+            ),
+            (
+                String::from("QC_SOURCE_DIR"),
+                OsString::from(self.program_options.get_source_directory()),
+            ),
+        ]
+    }
+
+    /// Variables describing what a finished target did, for the post-cycle
+    /// and on-error hooks of every kind of target.
+    pub fn report_environment(&self, report: &TargetReport) -> HookEnvironment {
+        vec![
+            (
+                String::from("QC_JOB"),
+                OsString::from(&self.program_options.job_name),
+            ),
+            (
+                String::from("QC_SOURCE_DIR"),
+                OsString::from(&report.source_directory),
+            ),
+            (
+                String::from("QC_TARGET_DIR"),
+                OsString::from(&report.target_directory),
+            ),
+            (
+                String::from("QC_CREATE_COUNT"),
+                OsString::from(report.creates.to_string()),
+            ),
+            (
+                String::from("QC_UPDATE_COUNT"),
+                OsString::from(report.updates.to_string()),
+            ),
+            (
+                String::from("QC_METADATA_UPDATE_COUNT"),
+                OsString::from(report.metadata_updates.to_string()),
+            ),
+            (
+                String::from("QC_DELETE_COUNT"),
+                OsString::from(report.deletes.to_string()),
+            ),
+        ]
+    }
+
+    pub fn target_environment(&self, action_list: &FileInfoParserActionList) -> HookEnvironment {
+        vec![
+            (
+                String::from("QC_JOB"),
+                OsString::from(&self.program_options.job_name),
+            ),
+            (
+                String::from("QC_SOURCE_DIR"),
+                OsString::from(&action_list.source_directory),
+            ),
+            (
+                String::from("QC_TARGET_DIR"),
+                OsString::from(&action_list.target_directory),
+            ),
+            (
+                String::from("QC_CREATE_COUNT"),
+                OsString::from(action_list.count(ActionType::Create).to_string()),
+            ),
+            (
+                String::from("QC_UPDATE_COUNT"),
+                OsString::from(action_list.count(ActionType::Update).to_string()),
+            ),
+            (
+                String::from("QC_METADATA_UPDATE_COUNT"),
+                OsString::from(action_list.count(ActionType::UpdateMetadata).to_string()),
+            ),
+            (
+                String::from("QC_DELETE_COUNT"),
+                OsString::from(action_list.count(ActionType::Delete).to_string()),
+            ),
+        ]
+    }
+
+    pub fn run_error(&self, environment: &HookEnvironment, message: &str) {
+        let mut error_environment = environment.clone();
+        error_environment.push((String::from("QC_ERROR"), OsString::from(message)));
+        self.run(HookType::OnError, &error_environment);
+    }
+
+    /// Runs the on-error hook for a target that had errors and the
+    /// post-cycle hook otherwise.
+    pub fn finish_target(&self, report: &TargetReport) {
+        let environment = self.report_environment(report);
+        if report.errors > 0 {
+            self.run_error(&environment, &report.error_messages.join("; "));
+        } else {
+            self.run(HookType::PostCycle, &environment);
+        }
+    }
+
+    /// Runs the configured command for a hook, returning false only when the
+    /// command could not be started or exited with a non-zero status.
+    pub fn run(&self, hook_type: HookType, environment: &HookEnvironment) -> bool {
+        let command = match self.get_command(hook_type) {
+            Some(c) => c,
+            None => return true,
+        };
+
+        info!("Running {} hook: {}", hook_type, &command);
+        let mut process = if cfg!(windows) {
+            let mut p = Command::new("cmd");
+            p.arg("/C").arg(&command);
+            p
+        } else {
+            let mut p = Command::new("sh");
+            p.arg("-c").arg(&command);
+            p
+        };
+
+        process.env("QC_HOOK", hook_type.to_string());
+        for (key, value) in environment {
+            process.env(key, value);
+        }
+
+        match process.status() {
+            Ok(status) if status.success() => true,
+            Ok(status) => {
+                warn!("{} hook exited with {}", hook_type, status);
+                false
+            }
+            Err(e) => {
+                error!("Unable to start {} hook: {}", hook_type, e);
+                false
+            }
+        }
+    }
+}
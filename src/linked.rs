@@ -0,0 +1,317 @@
+use crate::archive::{
+    archive_key, build_index_entry, entry_differs, entry_time, prepare_restore_path,
+    restore_file_metadata, restore_path, EntryKind, IndexEntry,
+};
+use crate::change_detector::ChangeDetector;
+use crate::comparison::CompareStrategy;
+use crate::configuration::ProgramOptions;
+use crate::files::{create_symlink, set_modified};
+use crate::hashing::hash_file_with;
+use crate::paths::ActionType;
+use crate::report::TargetReport;
+use crate::retention::{snapshot_name, snapshot_time, sort_snapshots, Retention};
+
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The link in a linked target pointing at its newest snapshot.
+const LATEST_LINK: &str = "latest";
+/// Appended to a snapshot directory until it is complete.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// How a path of the new snapshot gets its contents.
+enum Source {
+    /// Hard-linked to the same path in the previous snapshot.
+    Previous(PathBuf),
+    /// Copied from the source, or created for directories and links.
+    Current(PathBuf),
+}
+
+/// The snapshot directories of a linked target, oldest first. Leftovers of
+/// interrupted cycles are removed.
+fn list_snapshots(target: &Path) -> io::Result<Vec<String>> {
+    if !target.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::<String>::new();
+    for entry in fs::read_dir(target)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || snapshot_time(&name).is_none() {
+            continue;
+        }
+        if name.ends_with(PARTIAL_SUFFIX) {
+            warn!("Removing the incomplete snapshot {}", name);
+            fs::remove_dir_all(entry.path())?;
+            continue;
+        }
+        names.push(name);
+    }
+    sort_snapshots(&mut names);
+    Ok(names)
+}
+
+/// Points `latest` at `name`, replacing the link in one rename so it never
+/// goes missing.
+fn update_latest(target: &Path, name: &str) -> io::Result<()> {
+    let temporary = target.join(format!("{}{}", LATEST_LINK, PARTIAL_SUFFIX));
+    let _ = fs::remove_file(&temporary);
+    create_symlink(Path::new(name), &temporary)?;
+    fs::rename(&temporary, target.join(LATEST_LINK))
+}
+
+/// Removes the snapshot directories `retention` doesn't keep. Returns their
+/// names.
+pub fn prune_snapshots(target: &Path, retention: Retention) -> io::Result<Vec<String>> {
+    let names = list_snapshots(target)?
+        .into_iter()
+        .filter_map(|name| snapshot_time(&name).map(|time| (name, time)))
+        .collect::<Vec<(String, chrono::DateTime<chrono::Utc>)>>();
+    let kept = retention.keep(&names.iter().map(|(_, time)| *time).collect::<Vec<_>>());
+
+    let mut removed = Vec::<String>::new();
+    for (i, (name, _)) in names.into_iter().enumerate() {
+        if kept.contains(&i) {
+            continue;
+        }
+        info!("Pruning snapshot {}", name);
+        fs::remove_dir_all(target.join(&name))?;
+        removed.push(name);
+    }
+    Ok(removed)
+}
+
+/// Writes one entry of the new snapshot.
+fn write_entry(path: &Path, entry: &IndexEntry, source: &Source) -> io::Result<()> {
+    prepare_restore_path(path)?;
+    match (entry.kind, source) {
+        (EntryKind::Directory, _) => fs::create_dir_all(path),
+        (EntryKind::Symlink, _) => {
+            let target = entry.link_target.as_deref().unwrap_or_default();
+            create_symlink(Path::new(target), path)
+        }
+        (EntryKind::File, Source::Previous(previous)) => match fs::hard_link(previous, path) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!(
+                    "Unable to link {}, copying it instead: {}",
+                    previous.display(),
+                    e
+                );
+                fs::copy(previous, path)?;
+                restore_file_metadata(path, entry)
+            }
+        },
+        (EntryKind::File, Source::Current(current)) => {
+            fs::copy(current, path)?;
+            restore_file_metadata(path, entry)
+        }
+    }
+}
+
+/// Whether a source entry differs from its version in the previous
+/// snapshot. Any difference in permissions or owner counts, so the file is
+/// copied rather than linked and the older snapshots keep theirs. Contents
+/// are only hashed when everything else matches.
+fn differs_from_previous(
+    o: &ProgramOptions,
+    compare: &CompareStrategy,
+    entry: &IndexEntry,
+    path: &Path,
+    recorded: &IndexEntry,
+    previous_path: &Path,
+) -> io::Result<bool> {
+    let metadata_only = CompareStrategy {
+        hash: false,
+        ..*compare
+    };
+    if entry_differs(recorded, entry, &metadata_only) {
+        return Ok(true);
+    }
+    if !compare.hash || entry.kind != EntryKind::File {
+        return Ok(false);
+    }
+    let hash = |x: &Path| hash_file_with(x, o.hash_algorithm, o.hash_buffer_size, o.hash_mmap);
+    Ok(hash(path)? != hash(previous_path)?)
+}
+
+/// Writes a new snapshot directory if anything changed since the last one,
+/// points `latest` at it, then applies the `--keep-*` retention.
+fn sync_linked(
+    o: &ProgramOptions,
+    source_dir: &String,
+    target: &str,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let target_dir = Path::new(target);
+    let previous_name = list_snapshots(target_dir)?.pop();
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+
+    // Entries are described without hashes; contents are only hashed for
+    // files that match on everything else.
+    let metadata_only = CompareStrategy {
+        hash: false,
+        ..compare
+    };
+    let mut previous = BTreeMap::<String, (IndexEntry, PathBuf)>::new();
+    if let Some(name) = &previous_name {
+        let previous_dir = target_dir.join(name).to_string_lossy().to_string();
+        for file in change_detector.enumerate_target(&previous_dir, true) {
+            let key = match archive_key(&file) {
+                Some(key) => key,
+                None => continue,
+            };
+            match build_index_entry(o, &metadata_only, &file, 0) {
+                Ok(entry) => {
+                    previous.insert(key, (entry, file.get_path()));
+                }
+                Err(e) => {
+                    error!("Unable to read {}: {}", file.display_path(), e);
+                    report.record_error(format!("read {}: {}", file.display_path(), e));
+                }
+            }
+        }
+    }
+
+    let mut entries = BTreeMap::<String, (IndexEntry, Source)>::new();
+    let mut changed = previous_name.is_none();
+    let files = change_detector.enumerate_directory(source_dir, "source", true, o.symlinks);
+    for file in files {
+        if change_detector.find_skip_folder(&file).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        let key = match archive_key(&file) {
+            Some(key) => key,
+            None => {
+                warn!(
+                    "Skipped {} because snapshot paths must be valid UTF-8.",
+                    file.display_path()
+                );
+                report.skips += 1;
+                continue;
+            }
+        };
+
+        let entry = match build_index_entry(o, &metadata_only, &file, 0) {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Keep the previous version rather than dropping the file.
+                error!("Unable to read {}: {}", file.display_path(), e);
+                report.record_error(format!("read {}: {}", file.display_path(), e));
+                if let Some((recorded, path)) = previous.get(&key) {
+                    entries.insert(key, (recorded.clone(), Source::Previous(path.clone())));
+                }
+                continue;
+            }
+        };
+        let action_type = match previous.get(&key) {
+            None => ActionType::Create,
+            Some((recorded, path)) => {
+                match differs_from_previous(o, &compare, &entry, &file.get_path(), recorded, path) {
+                    Ok(false) => {
+                        entries.insert(key, (entry, Source::Previous(path.clone())));
+                        continue;
+                    }
+                    Ok(true) => ActionType::Update,
+                    Err(e) => {
+                        warn!("Unable to compare {}, copying it instead: {}", key, e);
+                        ActionType::Update
+                    }
+                }
+            }
+        };
+        report.record_change(&action_type, &key, entry.size);
+        entries.insert(key, (entry, Source::Current(file.get_path())));
+        changed = true;
+    }
+
+    for (key, (recorded, path)) in &previous {
+        if entries.contains_key(key) {
+            continue;
+        }
+        if o.enable_deletes {
+            report.record_change(&ActionType::Delete, key, 0);
+            changed = true;
+        } else {
+            report.skips += 1;
+            entries.insert(
+                key.clone(),
+                (recorded.clone(), Source::Previous(path.clone())),
+            );
+        }
+    }
+
+    if changed {
+        fs::create_dir_all(target_dir)?;
+        let name = snapshot_name(target_dir, chrono::Utc::now(), "");
+        let partial = target_dir.join(format!("{}{}", name, PARTIAL_SUFFIX));
+        fs::create_dir_all(&partial)?;
+        let mut failed = 0;
+        for (key, (entry, source)) in &entries {
+            let path = restore_path(&partial, key)?;
+            if let Err(e) = write_entry(&path, entry, source) {
+                error!("Unable to write {}: {}", key, e);
+                report.record_error(format!("snapshot {}: {}", key, e));
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            // An incomplete snapshot must not become the latest one, or
+            // retention could prune the last complete snapshot for it.
+            error!(
+                "Left {} unpublished because {} entries couldn't be written.",
+                partial.display(),
+                failed
+            );
+            return Ok(());
+        }
+        // Directory times last, deepest first, since writing their contents
+        // changes them.
+        for (key, (entry, _)) in entries.iter().rev() {
+            if entry.kind == EntryKind::Directory {
+                let path = restore_path(&partial, key)?;
+                if let Err(e) = set_modified(&path, entry_time(entry)) {
+                    warn!("Unable to set the modification time of {}: {}", key, e);
+                }
+            }
+        }
+        fs::rename(&partial, target_dir.join(&name))?;
+        update_latest(target_dir, &name)?;
+        info!(
+            job = o.job_name.as_str(),
+            target = target;
+            "Wrote snapshot {} with {} entries", name, entries.len()
+        );
+    } else {
+        info!("{} is up to date.", target);
+    }
+
+    let retention = Retention::from_options(o);
+    if !retention.is_empty() {
+        prune_snapshots(target_dir, retention)?;
+    }
+    Ok(())
+}
+
+/// Syncs the source into every `linked:` target.
+pub fn sync_linked_targets(o: &ProgramOptions) -> Vec<TargetReport> {
+    let source_dir = o.get_source_directory();
+    o.get_linked_targets()
+        .iter()
+        .map(|target| {
+            info!("Linked snapshot target is {}", target);
+            let mut report = TargetReport::new(&source_dir, target);
+            if let Err(e) = sync_linked(o, &source_dir, target, &mut report) {
+                error!("Unable to snapshot to {}: {}", target, e);
+                report.record_error(format!("snapshot {}: {}", target, e));
+            }
+            report.finish();
+            report
+        })
+        .collect()
+}
@@ -0,0 +1,254 @@
+use crate::configuration::{LogFormat, ProgramOptions};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use log::kv::{Key, Value, VisitSource};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn setup_logger(o: &ProgramOptions) -> Result<(), fern::InitError> {
+    let mut dispatch = fern::Dispatch::new().level(o.log_level.to_level_filter());
+
+    dispatch = match o.log_format {
+        LogFormat::Text => dispatch.format(|out, message, record| {
+            out.finish(format_args!(
+                "{}[{}] {}",
+                Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                record.level(),
+                message
+            ))
+        }),
+        LogFormat::Json => dispatch.format(|out, message, record| {
+            out.finish(format_args!("{}", format_json_line(message, record)))
+        }),
+    };
+
+    // Subcommands print their results on stdout, so their logs go to stderr.
+    dispatch = match o.command {
+        Some(_) => dispatch.chain(io::stderr()),
+        None => dispatch.chain(io::stdout()),
+    };
+
+    if let Some(log_file) = o.log_file.as_ref() {
+        match RotatingFile::open(
+            Path::new(log_file),
+            o.log_max_size,
+            o.log_rotate_daily,
+            o.log_retention,
+        ) {
+            Ok(file) => {
+                let writer: Box<dyn Write + Send> = Box::new(file);
+                dispatch = dispatch.chain(writer);
+            }
+            Err(e) => eprintln!(
+                "Unable to open log file {}: {}; logging to stdout only.",
+                log_file, e
+            ),
+        }
+    }
+
+    dispatch.apply()?;
+    Ok(())
+}
+
+struct JsonFieldVisitor<'a> {
+    fields: &'a mut serde_json::Map<String, serde_json::Value>,
+}
+
+impl<'kvs> VisitSource<'kvs> for JsonFieldVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let json_value = if let Some(n) = value.to_u64() {
+            serde_json::Value::from(n)
+        } else if let Some(n) = value.to_i64() {
+            serde_json::Value::from(n)
+        } else if let Some(b) = value.to_bool() {
+            serde_json::Value::from(b)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+        self.fields.insert(key.to_string(), json_value);
+        Ok(())
+    }
+}
+
+pub fn format_json_line(message: &std::fmt::Arguments, record: &log::Record) -> String {
+    let mut fields = serde_json::Map::new();
+    fields.insert(
+        String::from("timestamp"),
+        serde_json::Value::from(Local::now().to_rfc3339()),
+    );
+    fields.insert(
+        String::from("level"),
+        serde_json::Value::from(record.level().to_string()),
+    );
+    fields.insert(
+        String::from("message"),
+        serde_json::Value::from(message.to_string()),
+    );
+
+    let mut visitor = JsonFieldVisitor {
+        fields: &mut fields,
+    };
+    let _ = record.key_values().visit(&mut visitor);
+
+    serde_json::Value::Object(fields).to_string()
+}
+
+/// How rotated files are stamped.
+const ROTATION_FORMAT: &str = "%Y%m%d-%H%M%S";
+const ROTATION_STAMP_LENGTH: usize = 15;
+
+/// Where a file named `<log file>.<stamp>[-<counter>]` by `rotate` falls in
+/// the rotation order. Anything else sharing the prefix isn't a rotated file.
+fn rotation_order(name: &str, prefix: &str) -> Option<(NaiveDateTime, u64)> {
+    let suffix = name.strip_prefix(prefix)?;
+    let stamp = suffix.get(..ROTATION_STAMP_LENGTH)?;
+    let time = NaiveDateTime::parse_from_str(stamp, ROTATION_FORMAT).ok()?;
+    let counter = match &suffix[ROTATION_STAMP_LENGTH..] {
+        "" => 0,
+        rest => {
+            let digits = rest.strip_prefix('-')?;
+            if !digits.bytes().all(|x| x.is_ascii_digit()) {
+                return None;
+            }
+            digits.parse().ok()?
+        }
+    };
+    Some((time, counter))
+}
+
+/// A log file that is moved aside to `<path>.<timestamp>` once it grows past
+/// `max_size` bytes or, with `rotate_daily`, once the local date changes.
+/// Only the newest `retention` rotated files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    opened: NaiveDate,
+    line_complete: bool,
+    max_size: Option<u64>,
+    rotate_daily: bool,
+    retention: usize,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: &Path,
+        max_size: Option<u64>,
+        rotate_daily: bool,
+        retention: usize,
+    ) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let opened = match metadata.modified() {
+            Ok(m) if metadata.len() > 0 => DateTime::<Local>::from(m).naive_local().date(),
+            _ => Local::now().naive_local().date(),
+        };
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            file: Some(file),
+            opened,
+            line_complete: true,
+            max_size,
+            rotate_daily,
+            retention,
+        })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        if self.size == 0 || !self.line_complete {
+            return false;
+        }
+
+        let too_large = match self.max_size {
+            Some(max_size) => self.size + incoming as u64 > max_size,
+            None => false,
+        };
+        let new_day = self.rotate_daily && Local::now().naive_local().date() != self.opened;
+        too_large || new_day
+    }
+
+    /// Moves the file aside and starts a new one. The file at `path` is
+    /// reopened even if it couldn't be moved, so logging carries on in it.
+    fn rotate(&mut self) -> io::Result<()> {
+        // Closed first, since some platforms can't rename open files.
+        self.file.take();
+
+        let stamp = Local::now().format(ROTATION_FORMAT).to_string();
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), stamp));
+        let mut counter = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{}-{}", self.path.display(), stamp, counter));
+            counter += 1;
+        }
+        let renamed = fs::rename(&self.path, &rotated);
+
+        self.reopen()?;
+        self.opened = Local::now().naive_local().date();
+        renamed?;
+        self.prune()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let directory = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(()),
+        };
+
+        let mut rotated = fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let order = rotation_order(&entry.file_name().to_string_lossy(), &prefix)?;
+                Some((order, entry.path()))
+            })
+            .collect::<Vec<((NaiveDateTime, u64), PathBuf)>>();
+        rotated.sort();
+
+        while rotated.len() > self.retention {
+            fs::remove_file(rotated.remove(0).1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            if let Err(e) = self.rotate() {
+                eprintln!("Unable to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        let written = file.write(buf)?;
+        self.size += written as u64;
+        self.line_complete = buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
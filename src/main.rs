@@ -1,37 +1,94 @@
 #[macro_use]
 extern crate clap;
 
-use chrono;
 use log::{error, info};
 use std::{thread, time};
-use clap::Parser;
 
-mod constants;
+mod archive;
+mod archive_source;
+mod attributes;
 mod change_detector;
+mod comparison;
+mod compression;
 mod configuration;
+mod constants;
 mod copier;
+mod copy_engine;
+mod diff;
+mod encryption;
 mod files;
+mod hashing;
+mod hooks;
+mod linked;
+mod logging;
+mod manifest;
 mod paths;
+mod report;
+mod retention;
+mod sftp;
+mod snapshot;
+mod storage;
+#[cfg(test)]
+#[allow(clippy::empty_line_after_outer_attr, clippy::bool_assert_comparison)]
 mod tests;
+mod timestamps;
 mod utilities;
 
 use change_detector::ChangeDetector;
-use configuration::{ProgramOptions, RuntimeType};
+use configuration::{Command, ProgramOptions, RuntimeType};
 use copier::Copier;
+use hooks::{HookType, Hooks};
+use report::RunReport;
 
 fn main() {
-    setup_logger().unwrap();
+    let program_options = configuration::load_program_options();
+    logging::setup_logger(&program_options).unwrap();
     info!("{}", configuration::get_header());
+    setup_thread_pool(&program_options);
 
-    let program_options = ProgramOptions::parse();
-    match &program_options.runtime {
-        RuntimeType::Batch => run_batch_mode(program_options.clone()),
-        RuntimeType::Console => run_console_mode(program_options.clone()),
-        RuntimeType::Service => run_service_mode(program_options.clone()),
+    match &program_options.command {
+        Some(Command::Diff { format }) => {
+            std::process::exit(diff::run_diff(&program_options, *format));
+        }
+        Some(Command::Manifest { command }) => {
+            std::process::exit(manifest::run_manifest(&program_options, command));
+        }
+        Some(Command::Restore { archive, directory }) => {
+            std::process::exit(archive::run_restore(archive, directory));
+        }
+        Some(Command::Decrypt { target, directory }) => {
+            std::process::exit(encryption::run_decrypt(&program_options, target, directory));
+        }
+        Some(Command::Snapshots { command }) => {
+            std::process::exit(snapshot::run_snapshots(&program_options, command));
+        }
+        None => match &program_options.runtime {
+            RuntimeType::Batch => run_batch_mode(program_options.clone()),
+            RuntimeType::Console => run_console_mode(program_options.clone()),
+            RuntimeType::Service => run_service_mode(program_options.clone()),
+        },
     }
     info!("Done!");
 }
 
+/// Sizes the pool used for directory walks and hashing. Zero means one
+/// thread per CPU.
+fn setup_thread_pool(o: &ProgramOptions) {
+    let threads = match o.threads {
+        0 => thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(1),
+        n => n,
+    };
+    info!("Using {} worker thread(s)", threads);
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+    {
+        error!("Unable to size the thread pool: {}", e);
+    }
+}
+
 fn run_console_mode(o: ProgramOptions) {
     info!("Running in console mode");
     loop {
@@ -54,30 +111,48 @@ fn run_service_mode(_o: ProgramOptions) {
 }
 
 fn run_cycle(o: ProgramOptions) {
+    let started = chrono::Local::now();
+    let hooks = Hooks::new(o.clone());
+    let environment = hooks.cycle_environment();
+    if !hooks.run(HookType::PreCycle, &environment) && o.abort_on_pre_cycle_failure {
+        error!("Pre-cycle hook failed; aborting the cycle.");
+        hooks.run_error(&environment, "pre_cycle hook failed");
+        return;
+    }
+
+    if let Some(archive) = o.get_source_archive() {
+        let target_reports = archive_source::sync_from_archive(&o, &archive);
+        finish_cycle(&o, &hooks, started, target_reports);
+        return;
+    }
     let change_detector = ChangeDetector::new(o.clone());
     let copier = Copier::new(o.clone());
     let actions = change_detector.incremental_changes();
-    if actions.len() > 0 {
-        copier.incremental_copy(actions);
+    let mut target_reports = if !actions.is_empty() {
+        copier.incremental_copy(actions)
     } else {
-        info!("Nothing to do.")
-    }
+        info!("Nothing to do.");
+        Vec::new()
+    };
+    target_reports.append(&mut archive::sync_archives(&o));
+    target_reports.append(&mut encryption::sync_encrypted_targets(&o));
+    target_reports.append(&mut snapshot::sync_snapshot_targets(&o));
+    target_reports.append(&mut linked::sync_linked_targets(&o));
+    target_reports.append(&mut sftp::sync_sftp_targets(&o));
+    finish_cycle(&o, &hooks, started, target_reports);
 }
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                //record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .chain(fern::log_file("output.log")?)
-        .apply()?;
-    Ok(())
+/// Runs the post-cycle or on-error hook of every target, whatever its kind,
+/// and writes the run report.
+fn finish_cycle(
+    o: &ProgramOptions,
+    hooks: &Hooks,
+    started: chrono::DateTime<chrono::Local>,
+    target_reports: Vec<report::TargetReport>,
+) {
+    for target_report in &target_reports {
+        hooks.finish_target(target_report);
+    }
+    let run_report = RunReport::new(&o.job_name, started, chrono::Local::now(), target_reports);
+    report::write_report(o, &run_report);
 }
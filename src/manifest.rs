@@ -0,0 +1,292 @@
+use crate::change_detector::ChangeDetector;
+use crate::configuration::{ManifestCommand, OutputFormat, ProgramOptions};
+use crate::hashing::{hash_file, HashAlgorithm};
+use crate::paths::{FileInfoParser, UNIX_SPLITTER};
+use crate::utilities::os_str_bytes;
+
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ManifestVerification {
+    pub verified: usize,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub corrupted: Vec<String>,
+    /// Files that couldn't be read to check them.
+    pub failed: Vec<String>,
+}
+
+impl ManifestVerification {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.corrupted.is_empty()
+            && self.failed.is_empty()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for path in &self.missing {
+            text.push_str(&format!("missing: {}\n", path));
+        }
+        for path in &self.extra {
+            text.push_str(&format!("extra: {}\n", path));
+        }
+        for path in &self.corrupted {
+            text.push_str(&format!("corrupted: {}\n", path));
+        }
+        for path in &self.failed {
+            text.push_str(&format!("failed: {}\n", path));
+        }
+        text.push_str(&format!(
+            "{} verified, {} missing, {} extra, {} corrupted, {} failed\n",
+            self.verified,
+            self.missing.len(),
+            self.extra.len(),
+            self.corrupted.len(),
+            self.failed.len()
+        ));
+        text
+    }
+}
+
+fn default_manifest_path(directory: &str, algorithm: HashAlgorithm) -> PathBuf {
+    Path::new(directory).join(algorithm.manifest_file_name())
+}
+
+/// Lists every file under `directory` that a sync would consider, keyed by
+/// the raw bytes of its `/`-separated path relative to `directory`.
+fn enumerate_manifest_files(
+    o: &ProgramOptions,
+    directory: &String,
+    manifest_path: &Path,
+) -> BTreeMap<Vec<u8>, FileInfoParser> {
+    let change_detector = ChangeDetector::new_read_only(o.clone());
+    let manifest_path = fs::canonicalize(manifest_path).ok();
+
+    let mut files = BTreeMap::<Vec<u8>, FileInfoParser>::new();
+    let case_sensitive = change_detector.is_case_sensitive(directory);
+    let entries =
+        change_detector.enumerate_directory(directory, "manifest", case_sensitive, o.symlinks);
+    for file in entries {
+        // Links kept as links have no content of their own to hash.
+        if !file.is_file || file.is_symlink() || change_detector.find_skip_folder(&file).is_some() {
+            continue;
+        }
+        if manifest_path.is_some() && fs::canonicalize(file.get_path()).ok() == manifest_path {
+            continue;
+        }
+        let key = file
+            .get_relative_path()
+            .names()
+            .map(os_str_bytes)
+            .collect::<Vec<Vec<u8>>>()
+            .join(&(UNIX_SPLITTER as u8));
+        files.insert(key, file);
+    }
+    files
+}
+
+/// Escapes a path the way GNU `sha256sum` does. Returns whether anything
+/// was escaped, in which case the line starts with a `\`.
+fn escape_path(path: &[u8]) -> (bool, Vec<u8>) {
+    let mut escaped = Vec::<u8>::with_capacity(path.len());
+    for byte in path {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            x => escaped.push(*x),
+        }
+    }
+    (escaped.len() != path.len(), escaped)
+}
+
+/// Reverses `escape_path`. Returns `None` for an unknown escape.
+fn unescape_path(path: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::<u8>::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(byte) = bytes.next() {
+        unescaped.push(match byte {
+            b'\\' => match bytes.next()? {
+                b'\\' => b'\\',
+                b'n' => b'\n',
+                b'r' => b'\r',
+                _ => return None,
+            },
+            x => *x,
+        });
+    }
+    Some(unescaped)
+}
+
+pub fn create_manifest(
+    o: &ProgramOptions,
+    directory: &String,
+    output: &Path,
+    algorithm: HashAlgorithm,
+) -> io::Result<usize> {
+    if !Path::new(directory).is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} isn't a directory", directory),
+        ));
+    }
+    let files = enumerate_manifest_files(o, directory, output);
+    let mut contents = Vec::<u8>::new();
+    for (key, file) in &files {
+        let hash = hash_file(&file.get_path(), algorithm)?;
+        let (escaped, path) = escape_path(key);
+        if escaped {
+            contents.push(b'\\');
+        }
+        contents.extend_from_slice(format!("{}  ", hash).as_bytes());
+        contents.extend_from_slice(&path);
+        contents.push(b'\n');
+    }
+    fs::write(output, contents)?;
+    Ok(files.len())
+}
+
+/// Reads a `sha256sum`-style file of `<hash>  <path>` lines. A `*` before
+/// the path (binary mode) is accepted and ignored, and lines starting with
+/// a `\` have escaped paths. Paths are kept as raw bytes since they need
+/// not be valid UTF-8.
+pub fn read_manifest(manifest: &Path) -> io::Result<HashMap<Vec<u8>, String>> {
+    let contents = fs::read(manifest)?;
+    let mut entries = HashMap::<Vec<u8>, String>::new();
+    for line in contents.split(|x| *x == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.trim_ascii().is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let malformed = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed manifest line: {}", String::from_utf8_lossy(line)),
+            )
+        };
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+
+        let separator = line.iter().position(|x| *x == b' ');
+        match separator {
+            Some(index) if line[..index].is_ascii() => {
+                let hash = String::from_utf8_lossy(&line[..index]).to_lowercase();
+                let path = &line[index + 1..];
+                let path = path
+                    .strip_prefix(b" ")
+                    .or_else(|| path.strip_prefix(b"*"))
+                    .unwrap_or(path);
+                let path = if escaped {
+                    unescape_path(path).ok_or_else(malformed)?
+                } else {
+                    path.to_vec()
+                };
+                entries.insert(path, hash);
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn verify_manifest(
+    o: &ProgramOptions,
+    directory: &String,
+    manifest: &Path,
+    algorithm: HashAlgorithm,
+) -> io::Result<ManifestVerification> {
+    let expected = read_manifest(manifest)?;
+    let files = enumerate_manifest_files(o, directory, manifest);
+    let mut verification = ManifestVerification::default();
+
+    for (key, file) in &files {
+        let display_key = String::from_utf8_lossy(key).to_string();
+        match expected.get(key) {
+            Some(expected_hash) => match hash_file(&file.get_path(), algorithm) {
+                Ok(hash) if &hash == expected_hash => verification.verified += 1,
+                Ok(_) => {
+                    warn!("{} does not match the manifest.", display_key);
+                    verification.corrupted.push(display_key);
+                }
+                Err(e) => {
+                    error!("Unable to read {}: {}", display_key, e);
+                    verification.failed.push(display_key);
+                }
+            },
+            None => verification.extra.push(display_key),
+        }
+    }
+
+    verification.missing = expected
+        .keys()
+        .filter(|x| !files.contains_key(*x))
+        .map(|x| String::from_utf8_lossy(x).to_string())
+        .collect();
+    verification.missing.sort();
+    Ok(verification)
+}
+
+/// Runs a manifest subcommand and returns the process exit code.
+pub fn run_manifest(o: &ProgramOptions, command: &ManifestCommand) -> i32 {
+    match command {
+        ManifestCommand::Create {
+            directory,
+            output,
+            algorithm,
+        } => {
+            let output = match output {
+                Some(x) => PathBuf::from(x),
+                None => default_manifest_path(directory, *algorithm),
+            };
+            match create_manifest(o, directory, &output, *algorithm) {
+                Ok(count) => {
+                    info!("Wrote {} entries to {}", count, output.display());
+                    0
+                }
+                Err(e) => {
+                    error!("Unable to create manifest {}: {}", output.display(), e);
+                    2
+                }
+            }
+        }
+        ManifestCommand::Verify {
+            directory,
+            manifest,
+            algorithm,
+            format,
+        } => {
+            let manifest = match manifest {
+                Some(x) => PathBuf::from(x),
+                None => default_manifest_path(directory, *algorithm),
+            };
+            match verify_manifest(o, directory, &manifest, *algorithm) {
+                Ok(verification) => {
+                    match format {
+                        OutputFormat::Text => print!("{}", verification.to_text()),
+                        OutputFormat::Json => {
+                            println!("{}", serde_json::to_string_pretty(&verification).unwrap())
+                        }
+                    }
+                    if verification.is_ok() {
+                        0
+                    } else {
+                        1
+                    }
+                }
+                Err(e) => {
+                    error!("Unable to verify manifest {}: {}", manifest.display(), e);
+                    2
+                }
+            }
+        }
+    }
+}
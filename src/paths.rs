@@ -1,140 +1,172 @@
-use itertools::EitherOrBoth::{Both, Left, Right};
 use itertools::Itertools;
 use log::debug;
 use std::cmp::Ordering;
-use std::collections::VecDeque;
-use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
+use crate::compression::CompressedFile;
+use crate::files::{hard_link_id, FileId};
 use crate::utilities;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum ActionType {
     Create,
     Update,
+    UpdateMetadata,
     Delete,
 }
 
-#[derive(Clone, Debug)]
-pub enum MatchType<First, Second> {
-    Match(First, Second),
-    NoMatch(First, Second),
-    OnlyOneLeft(First),
-    OnlyOneRight(Second),
+impl Display for ActionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            ActionType::Create => "create",
+            ActionType::Update => "update",
+            ActionType::UpdateMetadata => "update_metadata",
+            ActionType::Delete => "delete",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UpdateReason {
+    Size,
+    Modified,
+    Hash,
+    LinkTarget,
+    HardLink,
+    Xattr,
+    Acl,
+    Mode,
+    Owner,
+    Times,
+    Compression,
+}
+
+impl UpdateReason {
+    /// Whether the difference can be fixed without copying the contents.
+    pub fn is_metadata(&self) -> bool {
+        matches!(
+            self,
+            UpdateReason::Xattr
+                | UpdateReason::Acl
+                | UpdateReason::Mode
+                | UpdateReason::Owner
+                | UpdateReason::Times
+        )
+    }
+}
+
+impl Display for UpdateReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match *self {
+            UpdateReason::Size => "size",
+            UpdateReason::Modified => "mtime",
+            UpdateReason::Hash => "hash",
+            UpdateReason::LinkTarget => "link",
+            UpdateReason::HardLink => "hardlink",
+            UpdateReason::Xattr => "xattr",
+            UpdateReason::Acl => "acl",
+            UpdateReason::Mode => "mode",
+            UpdateReason::Owner => "owner",
+            UpdateReason::Times => "times",
+            UpdateReason::Compression => "compression",
+        };
+        write!(f, "{}", value)
+    }
 }
 
 pub const WINDOWS_SPLITTER: char = '\\';
 pub const UNIX_SPLITTER: char = '/';
 pub const SPLITTER: char = '|';
 
+/// The names making up a path given on the command line or in the config
+/// file, such as a source, target or skip folder.
 #[derive(Clone, Debug)]
 pub struct PathSegment {
-    name: String,
-    next: Option<Box<PathSegment>>,
+    names: Vec<OsString>,
 }
 
 impl PathSegment {
-    pub fn get_remaining_segments(&self) -> Vec<PathSegment> {
-        let current_segment = self;
-        let mut results = Vec::<PathSegment>::new();
-        results.push(current_segment.clone());
-        let mut seg_option = current_segment.next.as_ref();
-        while let Some(i) = seg_option {
-            results.push(*i.clone());
-            seg_option = match &seg_option.unwrap().next {
-                Some(x) => Some(&x),
-                None => None,
-            };
-        }
-        results
-    }
-
+    /// Joins the segment names with `separator`. Names that are not valid
+    /// Unicode are converted lossily, so use this for display only.
     pub fn get_segment_string(&self, separator: char) -> String {
-        let remaining_segments = self.get_remaining_segments();
-        let mut segment_string = String::new();
-        for seg in remaining_segments {
-            segment_string.push_str(seg.name.as_str());
-            segment_string.push(separator);
-        }
-        segment_string.pop();
-        segment_string
-    }
-
-    pub fn get_segments(&self) -> Vec<String> {
-        let remaining_segments = self.get_remaining_segments();
-        let string_vec: Vec<String> = remaining_segments.iter().map(|x| x.name.clone()).collect();
-        string_vec
+        self.names
+            .iter()
+            .map(|x| x.to_string_lossy())
+            .join(&separator.to_string())
     }
 
     pub fn get_default_segment_string(&self) -> String {
         if cfg!(windows) {
             self.get_segment_string(WINDOWS_SPLITTER)
-        } else if cfg!(unix) {
-            self.get_segment_string(UNIX_SPLITTER)
         } else {
             self.get_segment_string(UNIX_SPLITTER)
         }
     }
 
-    pub fn get_segment_length(&self) -> usize {
-        let segment_string = self.get_default_segment_string();
-        let splitted: Vec<&str> = segment_string.split(SPLITTER).collect();
-        splitted.len()
-    }
-
+    /// Whether the names of `folder_segment` appear as consecutive whole
+    /// names in this segment, ignoring case.
+    #[allow(dead_code)]
     pub fn contains_all_of_segment(&self, folder_segment: &PathSegment) -> bool {
-        let str1 = self.get_segment_string(SPLITTER);
-        let str2 = folder_segment.get_segment_string(SPLITTER);
-        let split1 = str1.split(SPLITTER).collect::<Vec<&str>>();
-        let split2 = str2.split(SPLITTER).collect::<Vec<&str>>();
-        let mut split_ctr = 0;
-
-        for t in split1 {
-            if utilities::string_match_str(split2[split_ctr], t) {
-                split_ctr += 1;
-
-                if split_ctr == split2.len() {
-                    return true;
-                }
-            } else {
-                split_ctr = 0;
-            }
-        }
+        contains_run(
+            self.names.iter().map(|x| x.as_os_str()),
+            &folder_segment.names,
+            false,
+        )
+    }
 
-        false
+    pub fn identical(&self, other_segment: &PathSegment, case_sensitive: bool) -> bool {
+        self.names.len() == other_segment.names.len()
+            && self
+                .names
+                .iter()
+                .zip(other_segment.names.iter())
+                .all(|(x, y)| utilities::os_str_match(x, y, case_sensitive))
     }
+}
 
-    pub fn identical(&self, other_segment: &PathSegment) -> bool {
-        let str1 = self.get_segment_string(SPLITTER);
-        let str2 = other_segment.get_segment_string(SPLITTER);
-        let split1 = str1.split(SPLITTER).collect::<Vec<&str>>();
-        let split2 = str2.split(SPLITTER).collect::<Vec<&str>>();
+/// Whether `run` appears as consecutive whole names somewhere in `names`.
+fn contains_run<'a>(
+    names: impl Iterator<Item = &'a OsStr>,
+    run: &[OsString],
+    case_sensitive: bool,
+) -> bool {
+    if run.is_empty() {
+        return false;
+    }
 
-        if split1.len() != split2.len() {
-            return false;
-        }
+    let mut run_ctr = 0;
+    for name in names {
+        if utilities::os_str_match(&run[run_ctr], name, case_sensitive) {
+            run_ctr += 1;
 
-        for i in 0..split1.len() {
-            if !utilities::string_match_str(split1[i], split2[i]) {
-                return false;
+            if run_ctr == run.len() {
+                return true;
             }
+        } else {
+            run_ctr = 0;
         }
-        true
     }
+
+    false
 }
 
 #[derive(Clone, Debug)]
 pub struct PathParser {
-    segment: Option<Box<PathSegment>>,
+    segment: Option<PathSegment>,
 }
 
 impl PathParser {
     pub fn new(path: &String) -> PathParser {
-        PathParser::build_segments(&path)
+        PathParser::build_segments(path)
     }
 
-    fn normalized_splitter(path: &String) -> Vec<String> {
+    fn normalized_splitter(path: &str) -> Vec<String> {
         let fixed_string = path
             .replace(WINDOWS_SPLITTER, SPLITTER.to_string().as_str())
             .replace(UNIX_SPLITTER, SPLITTER.to_string().as_str());
@@ -146,183 +178,247 @@ impl PathParser {
         normalized
     }
 
-    fn build_segments(path: &String) -> PathParser {
-        let mut normalized = PathParser::normalized_splitter(&path);
-        normalized.reverse();
-        let mut next_segment: Option<Box<PathSegment>> = None;
+    /// Builds a parser from the components of a path without going through
+    /// a string, so names that are not valid Unicode survive intact.
+    pub fn from_path(path: &Path) -> PathParser {
+        let segments = path
+            .components()
+            .filter(|x| !matches!(x, Component::RootDir))
+            .map(|x| x.as_os_str().to_os_string())
+            .collect::<Vec<OsString>>();
+        PathParser::from_segments(segments)
+    }
 
+    fn build_segments(path: &String) -> PathParser {
         debug!("Build segments for: {}.", &path);
+        let normalized = PathParser::normalized_splitter(path)
+            .into_iter()
+            .map(OsString::from)
+            .collect::<Vec<OsString>>();
+        PathParser::from_segments(normalized)
+    }
 
-        for seg in normalized {
-            let new_item = PathSegment {
-                name: seg,
-                next: next_segment,
-            };
-            next_segment = Some(Box::new(new_item));
+    fn from_segments(names: Vec<OsString>) -> PathParser {
+        if names.is_empty() {
+            return PathParser { segment: None };
         }
 
         PathParser {
-            segment: next_segment,
+            segment: Some(PathSegment { names }),
         }
     }
 
-    pub fn get_differing_segment(&self, p: PathParser) -> Option<Box<PathSegment>> {
-        let other_segment_list = p.segment.as_ref().unwrap().get_remaining_segments();
-        let my_segments = self.segment.as_ref().unwrap().get_remaining_segments();
-
-        //dbg!(&my_segments);
-        //dbg!(&other_segment_list);
-
-        let zipped = my_segments.iter().zip_longest(other_segment_list);
-        //dbg!(&zipped);
-        for zip in zipped {
-            let zip_val = match zip {
-                Left(x) => MatchType::OnlyOneLeft(x.clone()),
-                Right(x) => MatchType::OnlyOneRight(x.clone()),
-                Both(x, y) => {
-                    if utilities::string_match_str(&x.name, &y.name) {
-                        MatchType::Match(x.clone(), y.clone())
-                    } else {
-                        MatchType::NoMatch(x.clone(), y.clone())
-                    }
-                }
-            };
-
-            if let MatchType::OnlyOneRight(x) = &zip_val {
-                return Some(Box::new(x.clone()));
-            }
-
-            if let MatchType::NoMatch(_, y) = &zip_val {
-                return Some(Box::new(y.clone()));
-            }
-        }
-
-        None
+    /// Returns the names of `p` from the first one that differs from this
+    /// path onwards.
+    #[allow(dead_code)]
+    pub fn get_differing_segment(
+        &self,
+        p: PathParser,
+        case_sensitive: bool,
+    ) -> Option<PathSegment> {
+        let other_names = p.segment?.names;
+        let my_names = self.segment.as_ref().map(|x| x.names.as_slice());
+        let common = other_names
+            .iter()
+            .zip(my_names.unwrap_or_default())
+            .take_while(|(x, y)| utilities::os_str_match(x, y, case_sensitive))
+            .count();
+        PathParser::from_segments(other_names[common..].to_vec()).segment
     }
 
+    #[allow(dead_code)]
     pub fn append_segment(&self, new_segment: &String) -> PathParser {
         let segment_pp = PathParser::new(new_segment);
-        let mut segs_pp_arr = segment_pp.segment.unwrap().get_segments();
-        let my_segment_string = self.segment.as_ref().unwrap().get_default_segment_string();
-        let my_pp = PathParser::new(&my_segment_string);
-        let mut my_pp_arr = my_pp.segment.unwrap().get_segments();
-        let mut new_arr: Vec<String> = Vec::new();
-        new_arr.append(&mut my_pp_arr);
-        new_arr.append(&mut segs_pp_arr);
-        let formatted_sep = format!("{}", SPLITTER);
-        let new_path = new_arr.join(&formatted_sep);
-        let final_pp = PathParser::new(&new_path);
-        final_pp
+        let mut new_arr = self.get_segment().names;
+        new_arr.extend(segment_pp.get_segment().names);
+        PathParser::from_segments(new_arr)
     }
 
-    #[allow(dead_code)]
-    fn get_last(&self) -> Option<PathSegment> {
-        let initial_segment = self.segment.clone();
-        let mut segment = initial_segment.clone();
-
-        let mut queue = VecDeque::<PathSegment>::new();
-        while let Some(ref x) = segment {
-            queue.push_back(*x.clone());
-            segment = match segment.unwrap().next {
-                Some(x) => Some(x),
-                None => None,
-            };
-        }
+    pub fn get_segment(&self) -> PathSegment {
+        self.segment.clone().unwrap()
+    }
+}
 
-        if queue.len() > 0 {
-            let last = queue.pop_back();
-            return last;
-        }
+/// A path relative to a source or target root, together with the key it is
+/// matched on. Clones share one allocation, and matched source and target
+/// entries are pointed at the same one, so each relative path is stored
+/// once however many times an entry is copied around.
+#[derive(Clone, Debug)]
+pub struct RelativePath(Arc<RelativePathInner>);
+
+#[derive(Debug)]
+struct RelativePathInner {
+    path: PathBuf,
+    key: OsString,
+    depth: usize,
+    case_sensitive: bool,
+}
 
-        None
+impl RelativePath {
+    pub fn new(path: &Path, case_sensitive: bool) -> RelativePath {
+        RelativePath(Arc::new(RelativePathInner {
+            key: utilities::path_key(path, case_sensitive),
+            depth: path.components().count(),
+            path: path.to_path_buf(),
+            case_sensitive,
+        }))
     }
 
-    #[allow(dead_code)]
-    fn remove_last(&self) -> Option<PathSegment> {
-        let initial_segment = self.segment.clone();
-        let mut segment = initial_segment.clone();
-
-        let mut queue = VecDeque::<PathSegment>::new();
-        while let Some(ref x) = segment {
-            queue.push_back(*x.clone());
-            segment = Some(segment.unwrap().next.unwrap());
-        }
+    pub fn as_path(&self) -> &Path {
+        &self.0.path
+    }
 
-        if queue.len() > 0 {
-            let last = queue.pop_back();
-            return last;
-        }
+    /// The key source and target entries are joined on.
+    pub fn key(&self) -> &OsStr {
+        &self.0.key
+    }
 
-        Some(*initial_segment.unwrap())
+    pub fn depth(&self) -> usize {
+        self.0.depth
     }
 
-    pub fn get_segment(&self) -> Box<PathSegment> {
-        let unwrapped_segment = self.segment.as_ref().unwrap();
-        unwrapped_segment.clone()
+    pub fn names(&self) -> impl Iterator<Item = &OsStr> {
+        self.0.path.components().map(|x| x.as_os_str())
+    }
+
+    /// The path for log messages and reports; lossy for non-Unicode names.
+    pub fn display(&self) -> String {
+        self.0.path.to_string_lossy().to_string()
+    }
+
+    /// Whether the path runs through `folder_segment`, matching names the
+    /// way the path's key does.
+    pub fn contains_all_of_segment(&self, folder_segment: &PathSegment) -> bool {
+        contains_run(self.names(), &folder_segment.names, self.0.case_sensitive)
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct FileInfoParser {
-    segment: Option<Box<PathSegment>>,
+    relative: RelativePath,
+    root: Arc<Path>,
     pub metadata: fs::Metadata,
     pub is_file: bool,
+    #[allow(dead_code)]
     pub is_unc_path: bool,
-    pub path: String,
     pub extension: Option<String>,
-    pub filename: Option<String>,
+    /// Where the entry points to, if it is a symbolic link.
+    pub link_target: Option<PathBuf>,
+    /// Set for regular files with more than one hard link.
+    pub hard_link_id: Option<FileId>,
+    /// Set for target files stored compressed, which are described by the
+    /// original they hold.
+    pub compressed: Option<Arc<CompressedFile>>,
 }
 
 impl FileInfoParser {
-    pub fn new(path: &String, base_directory: &String) -> FileInfoParser {
-        let md = fs::metadata(&path).unwrap();
-        let base_parser = PathParser::new(&base_directory);
-        let path_buf = Path::new(&path);
-        let mut extension = path_buf
-            .extension()
-            .and_then(OsStr::to_str)
-            .and_then(|x| Some(x.to_string()));
-        let path_string = path_buf.as_os_str().to_str().unwrap().to_string();
-        let sub_dir_parser = PathParser::new(&path_string);
-        let seg = base_parser.get_differing_segment(sub_dir_parser);
-        let filename = path_buf
-            .file_name()
-            .and_then(OsStr::to_str)
-            .and_then(|x| Some(x.to_string()));
-
-        let new_filename = match filename {
-            Some(f) => {
-                if f.starts_with(".") {
-                    let temp_extension = f.replace(".", "").to_string();
-                    extension = Some(temp_extension);
-                    None
-                } else {
-                    Some(f)
-                }
+    #[allow(dead_code)]
+    pub fn new(path: &Path, base_directory: &Path) -> FileInfoParser {
+        let md = fs::metadata(path).unwrap();
+        FileInfoParser::with_metadata(path, &Arc::from(base_directory), md, false)
+    }
+
+    /// Describes `path`, an entry found under `root`. Entries of one walk
+    /// should share the same `root`.
+    pub fn with_metadata(
+        path: &Path,
+        root: &Arc<Path>,
+        md: fs::Metadata,
+        case_sensitive: bool,
+    ) -> FileInfoParser {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => {
+                let root_parser = PathParser::from_path(root);
+                let names = root_parser
+                    .get_differing_segment(PathParser::from_path(path), case_sensitive)
+                    .map(|x| x.names)
+                    .unwrap_or_default();
+                names.iter().collect::<PathBuf>()
             }
-            _ => None,
         };
 
-        let result = FileInfoParser {
+        let link_target = if md.file_type().is_symlink() {
+            fs::read_link(path).ok()
+        } else {
+            None
+        };
+
+        FileInfoParser {
             is_file: !md.is_dir(),
+            link_target,
+            hard_link_id: hard_link_id(&md),
             metadata: md,
-            segment: seg,
-            is_unc_path: utilities::path_is_unc(base_directory),
-            path: path.to_string(),
-            extension: extension,
-            filename: new_filename,
-        };
+            relative: RelativePath::new(&relative, case_sensitive),
+            is_unc_path: utilities::path_is_unc(&root.to_string_lossy()),
+            root: root.clone(),
+            extension: extension_of(path),
+            compressed: None,
+        }
+    }
+
+    /// Describes a file stored compressed as the original it holds, e.g.
+    /// `a.log` for `a.log.zst`. Names not ending in the recorded suffix are
+    /// left as they are.
+    pub fn into_compressed(
+        mut self,
+        compressed: CompressedFile,
+        case_sensitive: bool,
+    ) -> FileInfoParser {
+        let original = utilities::strip_path_suffix(self.relative.as_path(), &compressed.suffix)
+            .filter(|x| !x.as_os_str().is_empty());
+        if let Some(original) = original {
+            self.extension = extension_of(&original);
+            self.relative = RelativePath::new(&original, case_sensitive);
+            self.compressed = Some(Arc::new(compressed));
+        }
+        self
+    }
 
-        result
+    /// The path of the entry on disk.
+    pub fn get_path(&self) -> PathBuf {
+        let path = self.get_original_path();
+        match self.compressed.as_ref() {
+            Some(compressed) => {
+                let mut name = path.into_os_string();
+                name.push(&compressed.suffix);
+                PathBuf::from(name)
+            }
+            None => path,
+        }
     }
 
-    pub fn get_path(&self) -> String {
-        self.path.clone()
+    /// The path the entry has uncompressed; the same as `get_path` unless
+    /// it is stored compressed.
+    pub fn get_original_path(&self) -> PathBuf {
+        self.root.join(self.relative.as_path())
     }
 
-    pub fn get_segment(&self) -> Box<PathSegment> {
-        let unwrapped_segment = self.segment.as_ref().unwrap();
-        unwrapped_segment.clone()
+    /// The size of the contents, before any compression.
+    pub fn content_length(&self) -> u64 {
+        match self.compressed.as_ref() {
+            Some(compressed) => compressed.size,
+            None => self.metadata.len(),
+        }
+    }
+
+    /// The path for log messages and reports; lossy for non-Unicode names.
+    pub fn display_path(&self) -> String {
+        self.get_path().to_string_lossy().to_string()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.metadata.file_type().is_symlink()
+    }
+
+    pub fn get_relative_path(&self) -> &RelativePath {
+        &self.relative
+    }
+
+    /// Points this entry at the relative path of `other`, which has the
+    /// same key, so that both share one allocation.
+    pub fn share_relative_path(&mut self, other: &FileInfoParser) {
+        self.relative = other.relative.clone();
     }
 
     pub fn match_extension(&self, extensions: Vec<String>) -> bool {
@@ -330,11 +426,30 @@ impl FileInfoParser {
             return true;
         }
 
-        match self.extension.as_ref() {
-            Some(extension) => utilities::match_list_or_all(&extension, extensions),
-            None => false,
+        extension_matches(self.extension.as_ref(), extensions)
+    }
+}
+
+/// Whether a file with `extension`, as given by `extension_of`, passes the
+/// extensions filter.
+pub fn extension_matches(extension: Option<&String>, extensions: Vec<String>) -> bool {
+    match extension {
+        Some(extension) => utilities::match_list_or_all(extension, extensions),
+        None => extensions.is_empty(),
+    }
+}
+
+/// The extension extensions filters match against; for dot files, the
+/// whole name without dots.
+pub fn extension_of(path: &Path) -> Option<String> {
+    if let Some(f) = path.file_name().map(OsStr::to_string_lossy) {
+        if f.starts_with('.') {
+            return Some(f.replace('.', ""));
         }
     }
+    path.extension()
+        .map(OsStr::to_string_lossy)
+        .map(|x| x.to_string())
 }
 
 #[derive(Clone, Debug)]
@@ -342,12 +457,14 @@ pub struct FileInfoParserAction {
     pub source: Option<FileInfoParser>,
     pub destination: Option<FileInfoParser>,
     pub action_type: ActionType,
+    pub reasons: Vec<UpdateReason>,
 }
 
 impl PartialEq for FileInfoParserAction {
     fn eq(&self, other: &Self) -> bool {
-        self.source.as_ref().unwrap().path == other.source.as_ref().unwrap().path
-            && self.destination.as_ref().unwrap().path == other.destination.as_ref().unwrap().path
+        self.source.as_ref().unwrap().get_path() == other.source.as_ref().unwrap().get_path()
+            && self.destination.as_ref().unwrap().get_path()
+                == other.destination.as_ref().unwrap().get_path()
     }
 }
 
@@ -363,18 +480,9 @@ impl PartialOrd for FileInfoParserAction {
             None => other.destination.as_ref().unwrap(),
         };
 
-        let self_seg_len = self_seg.segment.as_ref().unwrap().get_segment_length();
-
-        let other_seg_len = other_seg.segment.as_ref().unwrap().get_segment_length();
-        if self_seg_len > other_seg_len {
-            return Some(Ordering::Greater);
-        } else if self_seg_len < other_seg_len {
-            return Some(Ordering::Less);
-        } else if self_seg_len == other_seg_len {
-            return Some(Ordering::Equal);
-        } else {
-            return None;
-        }
+        let self_seg_len = self_seg.relative.depth();
+        let other_seg_len = other_seg.relative.depth();
+        Some(self_seg_len.cmp(&other_seg_len))
     }
 }
 
@@ -388,6 +496,7 @@ impl FileInfoParserAction {
             source: Some(source),
             destination: Some(dest),
             action_type: t,
+            reasons: Vec::new(),
         }
     }
 
@@ -396,6 +505,7 @@ impl FileInfoParserAction {
             source: Some(source),
             destination: None,
             action_type: t,
+            reasons: Vec::new(),
         }
     }
 
@@ -404,32 +514,28 @@ impl FileInfoParserAction {
             source: None,
             destination: Some(dest),
             action_type: t,
+            reasons: Vec::new(),
         }
     }
 
     #[allow(dead_code)]
     pub fn get_source_length(&self) -> usize {
-        self.source.clone().unwrap().path.len()
+        self.source.as_ref().unwrap().get_path().as_os_str().len()
     }
 
     #[allow(dead_code)]
     pub fn get_destination_length(&self) -> usize {
-        self.destination.clone().unwrap().path.len()
-    }
-
-    pub fn get_destination_from_segment(&self, target_directory: &String) -> String {
-        let mut pp = PathParser::new(target_directory);
-        let segment_string = self
-            .source
-            .clone()
-            .unwrap()
-            .segment
+        self.destination
             .as_ref()
             .unwrap()
-            .get_default_segment_string();
-        pp = pp.append_segment(&segment_string);
-        let destination_segment = pp.segment.unwrap().get_default_segment_string();
-        destination_segment
+            .get_path()
+            .as_os_str()
+            .len()
+    }
+
+    pub fn get_destination_from_segment(&self, target_directory: &String) -> PathBuf {
+        let relative = self.source.as_ref().unwrap().get_relative_path();
+        Path::new(target_directory).join(relative.as_path())
     }
 }
 
@@ -437,4 +543,23 @@ pub struct FileInfoParserActionList {
     pub source_directory: String,
     pub target_directory: String,
     pub actions: Vec<FileInfoParserAction>,
+    pub skipped: usize,
+    /// For source hard link groups already on the target, the target path
+    /// new members of the group should be linked to.
+    pub hard_links: HashMap<FileId, PathBuf>,
+    /// Groups of source paths that only differ by case and so can't all be
+    /// stored in a case-insensitive target.
+    pub collisions: Vec<Vec<String>>,
+    /// Differing files left alone by the update policy because the target
+    /// copy is newer.
+    pub newer_on_target: Vec<String>,
+}
+
+impl FileInfoParserActionList {
+    pub fn count(&self, action_type: ActionType) -> usize {
+        self.actions
+            .iter()
+            .filter(|x| x.action_type == action_type)
+            .count()
+    }
 }
@@ -0,0 +1,286 @@
+use crate::configuration::ProgramOptions;
+use crate::paths::ActionType;
+
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChangedPath {
+    pub action: String,
+    pub path: String,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TargetReport {
+    pub source_directory: String,
+    pub target_directory: String,
+    pub creates: usize,
+    pub updates: usize,
+    pub metadata_updates: usize,
+    pub deletes: usize,
+    pub skips: usize,
+    pub errors: usize,
+    pub bytes_transferred: u64,
+    pub duration_seconds: f64,
+    pub throughput_bytes_per_second: f64,
+    pub changed_paths: Vec<ChangedPath>,
+    pub error_messages: Vec<String>,
+    pub collisions: Vec<Vec<String>>,
+    pub newer_on_target: Vec<String>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl TargetReport {
+    pub fn new(source_directory: &str, target_directory: &str) -> TargetReport {
+        TargetReport {
+            source_directory: source_directory.to_string(),
+            target_directory: target_directory.to_string(),
+            creates: 0,
+            updates: 0,
+            metadata_updates: 0,
+            deletes: 0,
+            skips: 0,
+            errors: 0,
+            bytes_transferred: 0,
+            duration_seconds: 0.0,
+            throughput_bytes_per_second: 0.0,
+            changed_paths: Vec::new(),
+            error_messages: Vec::new(),
+            collisions: Vec::new(),
+            newer_on_target: Vec::new(),
+            started: Some(Instant::now()),
+        }
+    }
+
+    pub fn record_change(&mut self, action_type: &ActionType, path: &str, bytes: u64) {
+        match action_type {
+            ActionType::Create => self.creates += 1,
+            ActionType::Update => self.updates += 1,
+            ActionType::UpdateMetadata => self.metadata_updates += 1,
+            ActionType::Delete => self.deletes += 1,
+        }
+        self.bytes_transferred += bytes;
+        self.changed_paths.push(ChangedPath {
+            action: action_type.to_string(),
+            path: path.to_string(),
+            bytes,
+        });
+    }
+
+    pub fn record_error(&mut self, message: String) {
+        self.errors += 1;
+        self.error_messages.push(message);
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.duration_seconds = started.elapsed().as_secs_f64();
+        }
+        if self.duration_seconds > 0.0 {
+            self.throughput_bytes_per_second =
+                self.bytes_transferred as f64 / self.duration_seconds;
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RunReport {
+    pub job: String,
+    pub started: String,
+    pub finished: String,
+    pub duration_seconds: f64,
+    pub targets: Vec<TargetReport>,
+}
+
+impl RunReport {
+    pub fn new(
+        job: &str,
+        started: DateTime<Local>,
+        finished: DateTime<Local>,
+        targets: Vec<TargetReport>,
+    ) -> RunReport {
+        RunReport {
+            job: job.to_string(),
+            started: started.to_rfc3339(),
+            finished: finished.to_rfc3339(),
+            duration_seconds: (finished - started).num_microseconds().unwrap_or(0) as f64
+                / 1_000_000.0,
+            targets,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str(&format!(
+            "<title>quick-copy report: {}</title>\n",
+            escape_html(&self.job)
+        ));
+        html.push_str(
+            "<style>body{font-family:sans-serif}table{border-collapse:collapse}\
+             td,th{border:1px solid #999;padding:2px 8px;text-align:left}</style>\n",
+        );
+        html.push_str("</head>\n<body>\n");
+        html.push_str(&format!("<h1>Job {}</h1>\n", escape_html(&self.job)));
+        html.push_str(&format!(
+            "<p>Started {}<br>Finished {}<br>Duration {:.3} s</p>\n",
+            escape_html(&self.started),
+            escape_html(&self.finished),
+            self.duration_seconds
+        ));
+
+        for target in &self.targets {
+            html.push_str(&format!(
+                "<h2>{} &rarr; {}</h2>\n",
+                escape_html(&target.source_directory),
+                escape_html(&target.target_directory)
+            ));
+            html.push_str("<table>\n");
+            for (name, value) in [
+                ("Creates", target.creates.to_string()),
+                ("Updates", target.updates.to_string()),
+                ("Metadata updates", target.metadata_updates.to_string()),
+                ("Deletes", target.deletes.to_string()),
+                ("Skips", target.skips.to_string()),
+                (
+                    "Skipped (target newer)",
+                    target.newer_on_target.len().to_string(),
+                ),
+                ("Errors", target.errors.to_string()),
+                ("Bytes transferred", target.bytes_transferred.to_string()),
+                (
+                    "Throughput",
+                    format!("{:.0} bytes/s", target.throughput_bytes_per_second),
+                ),
+            ] {
+                html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", name, value));
+            }
+            html.push_str("</table>\n");
+
+            if !target.changed_paths.is_empty() {
+                html.push_str("<h3>Changed paths</h3>\n<table>\n");
+                html.push_str("<tr><th>Action</th><th>Path</th><th>Bytes</th></tr>\n");
+                for changed in &target.changed_paths {
+                    html.push_str(&format!(
+                        "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                        escape_html(&changed.action),
+                        escape_html(&changed.path),
+                        changed.bytes
+                    ));
+                }
+                html.push_str("</table>\n");
+            }
+
+            if !target.collisions.is_empty() {
+                html.push_str("<h3>Collisions</h3>\n<ul>\n");
+                for names in &target.collisions {
+                    html.push_str(&format!("<li>{}</li>\n", escape_html(&names.join(", "))));
+                }
+                html.push_str("</ul>\n");
+            }
+
+            if !target.newer_on_target.is_empty() {
+                html.push_str("<h3>Newer in target</h3>\n<ul>\n");
+                for path in &target.newer_on_target {
+                    html.push_str(&format!("<li>{}</li>\n", escape_html(path)));
+                }
+                html.push_str("</ul>\n");
+            }
+
+            if !target.error_messages.is_empty() {
+                html.push_str("<h3>Errors</h3>\n<ul>\n");
+                for message in &target.error_messages {
+                    html.push_str(&format!("<li>{}</li>\n", escape_html(message)));
+                }
+                html.push_str("</ul>\n");
+            }
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn write_report(o: &ProgramOptions, report: &RunReport) {
+    let report_dir = match o.report_dir.as_ref() {
+        Some(d) => d,
+        None => return,
+    };
+
+    match write_report_files(Path::new(report_dir), o.report_html, report) {
+        Ok(paths) => {
+            for path in paths {
+                info!("Wrote run report {}", path.display());
+            }
+        }
+        Err(e) => error!("Unable to write run report to {}: {}", report_dir, e),
+    }
+}
+
+pub fn write_report_files(
+    report_dir: &Path,
+    html: bool,
+    report: &RunReport,
+) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(report_dir)?;
+    // The job name is free text, so it can't be allowed to leave the report
+    // directory.
+    let job = report
+        .job
+        .chars()
+        .map(|x| {
+            if x.is_alphanumeric() || x == '-' || x == '_' {
+                x
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let stamp = Local::now().format("%Y%m%d-%H%M%S%.3f");
+
+    // Runs finishing in the same millisecond get a counter rather than
+    // overwriting each other.
+    let mut base_name = format!("report-{}-{}", job, stamp);
+    let mut counter = 1;
+    let (json_path, mut json_file) = loop {
+        let path = report_dir.join(format!("{}.json", base_name));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => break (path, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                base_name = format!("report-{}-{}-{}", job, stamp, counter);
+                counter += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut written = Vec::<PathBuf>::new();
+    json_file.write_all(serde_json::to_string_pretty(report)?.as_bytes())?;
+    written.push(json_path);
+
+    if html {
+        let html_path = report_dir.join(format!("{}.html", base_name));
+        fs::write(&html_path, report.to_html())?;
+        written.push(html_path);
+    }
+    Ok(written)
+}
@@ -0,0 +1,101 @@
+use crate::configuration::ProgramOptions;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// How snapshots are named: their creation time in UTC, to the millisecond,
+/// which also sorts them by age and never repeats or skips across daylight
+/// saving changes.
+const NAME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%.3fZ";
+
+/// Which snapshots survive pruning: the newest `last`, plus the newest one
+/// in each of the latest `hourly` hours, `daily` days and `weekly` ISO
+/// weeks (in UTC) that have a snapshot. Without any counts everything is
+/// kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub last: usize,
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Retention {
+    pub fn from_options(o: &ProgramOptions) -> Retention {
+        Retention {
+            last: o.keep_last,
+            hourly: o.keep_hourly,
+            daily: o.keep_daily,
+            weekly: o.keep_weekly,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+
+    /// Returns the positions in `times` of the snapshots to keep. Of two
+    /// snapshots created at the same time, the later position is newer.
+    pub fn keep(&self, times: &[DateTime<Utc>]) -> HashSet<usize> {
+        let mut newest_first = (0..times.len()).collect::<Vec<usize>>();
+        newest_first.sort_by(|a, b| (times[*b], *b).cmp(&(times[*a], *a)));
+        if self.is_empty() {
+            return newest_first.into_iter().collect();
+        }
+
+        let mut kept = newest_first
+            .iter()
+            .take(self.last)
+            .copied()
+            .collect::<HashSet<usize>>();
+        for (count, format) in [
+            (self.hourly, "%Y-%m-%d %H"),
+            (self.daily, "%Y-%m-%d"),
+            (self.weekly, "%G-%V"),
+        ] {
+            let mut periods = Vec::<String>::new();
+            for i in &newest_first {
+                let period = times[*i].format(format).to_string();
+                if periods.len() == count {
+                    break;
+                }
+                if periods.last() != Some(&period) {
+                    periods.push(period);
+                    kept.insert(*i);
+                }
+            }
+        }
+        kept
+    }
+}
+
+/// A name for a snapshot created at `time` that isn't taken in `directory`.
+pub fn snapshot_name(directory: &Path, time: DateTime<Utc>, extension: &str) -> String {
+    let base = time.format(NAME_FORMAT).to_string();
+    let mut name = format!("{}{}", base, extension);
+    let mut counter = 1;
+    while directory.join(&name).exists() {
+        name = format!("{}-{}{}", base, counter, extension);
+        counter += 1;
+    }
+    name
+}
+
+/// Sorts snapshot names oldest first, with names taken in the same
+/// millisecond in the order of their counters.
+pub fn sort_snapshots(names: &mut [String]) {
+    names.sort_by_cached_key(|name| {
+        let parsed = NaiveDateTime::parse_and_remainder(name, NAME_FORMAT).ok();
+        let counter = parsed
+            .and_then(|(_, rest)| rest.strip_prefix('-')?.parse::<u64>().ok())
+            .unwrap_or(0);
+        (parsed.map(|(time, _)| time), counter, name.clone())
+    });
+}
+
+/// The creation time of a snapshot named by `snapshot_name`.
+pub fn snapshot_time(name: &str) -> Option<DateTime<Utc>> {
+    let (time, _) = NaiveDateTime::parse_and_remainder(name, NAME_FORMAT).ok()?;
+    Some(time.and_utc())
+}
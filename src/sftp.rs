@@ -0,0 +1,336 @@
+use crate::archive::{EntryKind, IndexEntry};
+use crate::configuration::ProgramOptions;
+use crate::constants::SFTP_TARGET_PREFIX;
+use crate::report::TargetReport;
+use crate::storage::{sync_storage, Storage};
+
+use log::{error, info, warn};
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PORT: u16 = 22;
+/// Appended to a file's name while it is uploaded.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// A target given as `sftp://[user@]host[:port]:/path`. The URL form
+/// `sftp://[user@]host[:port]/path` is accepted too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpTarget {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl SftpTarget {
+    pub fn parse(spec: &str) -> Option<SftpTarget> {
+        let rest = spec.strip_prefix(SFTP_TARGET_PREFIX)?;
+        // Only the part before the path names the user and host, so paths
+        // may contain `@`.
+        let start = rest.find('/')?;
+        let (authority, path) = rest.split_at(start);
+        let (user, address) = match authority.rsplit_once('@') {
+            Some((user, address)) => (user.to_string(), address),
+            None => (
+                std::env::var("USER")
+                    .or_else(|_| std::env::var("USERNAME"))
+                    .ok()?,
+                authority,
+            ),
+        };
+        let address = address.trim_end_matches(':');
+        let (host, port) = match address.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (address, DEFAULT_PORT),
+        };
+        if user.is_empty() || host.is_empty() {
+            return None;
+        }
+        let path = match path.trim_end_matches('/') {
+            "" => "/",
+            path => path,
+        };
+        Some(SftpTarget {
+            user,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Display for SftpTarget {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}@{}:{}:{}",
+            SFTP_TARGET_PREFIX, self.user, self.host, self.port, self.path
+        )
+    }
+}
+
+/// A directory on a server reached over SSH.
+pub struct SftpStorage {
+    /// Kept so the connection outlives `sftp`.
+    _session: Session,
+    sftp: Sftp,
+    root: PathBuf,
+}
+
+fn ssh_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+/// The default OpenSSH file under the home directory.
+fn home_file(name: &str) -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|x| PathBuf::from(x).join(".ssh").join(name))
+}
+
+impl SftpStorage {
+    /// Connects and authenticates with `key_file`, refusing servers whose
+    /// host key isn't the one `known_hosts` lists for them.
+    pub fn connect(
+        target: &SftpTarget,
+        key_file: &Path,
+        known_hosts: &Path,
+    ) -> io::Result<SftpStorage> {
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((target.host.as_str(), target.port))?);
+        session.handshake()?;
+
+        let mut hosts = session.known_hosts()?;
+        hosts.read_file(known_hosts, KnownHostFileKind::OpenSSH)?;
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| ssh_error(format!("{} sent no host key", target.host)))?;
+        match hosts.check_port(&target.host, target.port, key) {
+            CheckResult::Match => {}
+            CheckResult::NotFound => {
+                return Err(ssh_error(format!(
+                    "{} isn't in {}",
+                    target.host,
+                    known_hosts.display()
+                )))
+            }
+            CheckResult::Mismatch => {
+                return Err(ssh_error(format!(
+                    "the host key of {} doesn't match {}",
+                    target.host,
+                    known_hosts.display()
+                )))
+            }
+            CheckResult::Failure => {
+                return Err(ssh_error(format!(
+                    "unable to check the host key of {}",
+                    target.host
+                )))
+            }
+        }
+
+        session.userauth_pubkey_file(&target.user, None, key_file, None)?;
+        if !session.authenticated() {
+            return Err(ssh_error(format!(
+                "{} rejected the key for {}",
+                target.host, target.user
+            )));
+        }
+        let sftp = session.sftp()?;
+        let storage = SftpStorage {
+            _session: session,
+            sftp,
+            root: PathBuf::from(&target.path),
+        };
+        storage.create_root()?;
+        Ok(storage)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Creates the target directory and any missing parents.
+    fn create_root(&self) -> io::Result<()> {
+        let mut path = PathBuf::new();
+        for component in self.root.components() {
+            path.push(component);
+            if self.sftp.stat(&path).is_err() {
+                self.sftp.mkdir(&path, 0o755)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list_into(
+        &self,
+        directory: &Path,
+        entries: &mut BTreeMap<String, IndexEntry>,
+    ) -> io::Result<()> {
+        for (path, stat) in self.sftp.readdir(directory)? {
+            let key = match path.strip_prefix(&self.root).ok().and_then(Path::to_str) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            let kind = if stat.file_type().is_symlink() {
+                EntryKind::Symlink
+            } else if stat.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            };
+            let link_target = match kind {
+                EntryKind::Symlink => {
+                    Some(self.sftp.readlink(&path)?.to_string_lossy().to_string())
+                }
+                _ => None,
+            };
+            entries.insert(
+                key,
+                IndexEntry {
+                    volume: 0,
+                    kind,
+                    size: match kind {
+                        EntryKind::File => stat.size.unwrap_or_default(),
+                        _ => 0,
+                    },
+                    modified: stat.mtime.unwrap_or_default(),
+                    modified_nanos: 0,
+                    mode: stat.perm.unwrap_or_default() & 0o7777,
+                    owner: None,
+                    link_target,
+                    hash: None,
+                },
+            );
+            if kind == EntryKind::Directory {
+                self.list_into(&path, entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_stat(&self, path: &Path, entry: &IndexEntry, permissions: bool) -> io::Result<()> {
+        self.sftp.setstat(
+            path,
+            FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: permissions.then_some(entry.mode),
+                atime: Some(entry.modified),
+                mtime: Some(entry.modified),
+            },
+        )?;
+        Ok(())
+    }
+}
+
+impl Storage for SftpStorage {
+    fn list(&self) -> io::Result<BTreeMap<String, IndexEntry>> {
+        let mut entries = BTreeMap::new();
+        self.list_into(&self.root, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn open(&self, key: &str) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.sftp.open(self.path(key))?))
+    }
+
+    fn create_dir(&self, key: &str) -> io::Result<()> {
+        Ok(self.sftp.mkdir(&self.path(key), 0o755)?)
+    }
+
+    fn write_file(
+        &self,
+        key: &str,
+        contents: &mut dyn Read,
+        entry: &IndexEntry,
+        permissions: bool,
+    ) -> io::Result<u64> {
+        let path = self.path(key);
+        let partial = self.path(&format!("{}{}", key, PARTIAL_SUFFIX));
+        let mut file = self.sftp.create(&partial)?;
+        let bytes = io::copy(contents, &mut file)?;
+        drop(file);
+        self.set_stat(&partial, entry, permissions)?;
+
+        // Servers speaking SFTP version 3, OpenSSH among them, won't rename
+        // over an existing file, so the old one is removed first there.
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if self.sftp.rename(&partial, &path, Some(flags)).is_err() {
+            let _ = self.sftp.unlink(&path);
+            self.sftp.rename(&partial, &path, None)?;
+        }
+        Ok(bytes)
+    }
+
+    fn create_symlink(&self, key: &str, target: &str) -> io::Result<()> {
+        Ok(self.sftp.symlink(Path::new(target), &self.path(key))?)
+    }
+
+    fn set_metadata(&self, key: &str, entry: &IndexEntry, permissions: bool) -> io::Result<()> {
+        self.set_stat(&self.path(key), entry, permissions)
+    }
+
+    fn remove(&self, key: &str, kind: EntryKind) -> io::Result<()> {
+        match kind {
+            EntryKind::Directory => Ok(self.sftp.rmdir(&self.path(key))?),
+            _ => Ok(self.sftp.unlink(&self.path(key))?),
+        }
+    }
+}
+
+fn sync_sftp(
+    o: &ProgramOptions,
+    source_dir: &String,
+    spec: &str,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let target = SftpTarget::parse(spec).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't of the form sftp://user@host:/path", spec),
+        )
+    })?;
+    let key_file = o
+        .ssh_key_file
+        .as_ref()
+        .map(PathBuf::from)
+        .ok_or_else(|| ssh_error("SFTP targets need --ssh-key-file".to_string()))?;
+    let known_hosts = o
+        .known_hosts
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| home_file("known_hosts"))
+        .ok_or_else(|| ssh_error("unable to find known_hosts; use --known-hosts".to_string()))?;
+
+    info!("Connecting to {}", target);
+    let storage = SftpStorage::connect(&target, &key_file, &known_hosts)?;
+    sync_storage(o, source_dir, &storage, report)
+}
+
+/// Syncs the source into every `sftp://` target.
+pub fn sync_sftp_targets(o: &ProgramOptions) -> Vec<TargetReport> {
+    let source_dir = o.get_source_directory();
+    let ignored = o.get_directory_only_options();
+    o.get_sftp_targets()
+        .iter()
+        .map(|target| {
+            info!("SFTP target is {}", target);
+            if !ignored.is_empty() {
+                warn!("{} ignores {}.", target, ignored.join(", "));
+            }
+            let mut report = TargetReport::new(&source_dir, target);
+            if let Err(e) = sync_sftp(o, &source_dir, target, &mut report) {
+                error!("Unable to sync to {}: {}", target, e);
+                report.record_error(format!("sync {}: {}", target, e));
+            }
+            report.finish();
+            report
+        })
+        .collect()
+}
@@ -0,0 +1,523 @@
+use crate::archive::{
+    archive_key, build_index_entry, entry_differs, entry_time, prepare_restore_path,
+    restore_file_metadata, restore_path, EntryKind, IndexEntry,
+};
+use crate::change_detector::ChangeDetector;
+use crate::comparison::CompareStrategy;
+use crate::configuration::{ProgramOptions, SnapshotCommand};
+use crate::files::{create_symlink, set_modified};
+use crate::paths::ActionType;
+use crate::report::TargetReport;
+use crate::retention::{snapshot_name, snapshot_time, sort_snapshots, Retention};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use xxhash_rust::xxh3::{xxh3_128, xxh3_64_with_seed};
+
+const CHUNKS_DIRECTORY: &str = "chunks";
+const SNAPSHOTS_DIRECTORY: &str = "snapshots";
+const MANIFEST_EXTENSION: &str = ".json";
+
+/// Chunk boundaries are found with a gear rolling hash, so an insertion
+/// only changes the chunks around it. Chunks average about 1 MiB.
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// A boundary follows any byte where the top 20 bits of the hash are zero.
+const BOUNDARY_MASK: u64 = 0xfffff << 44;
+
+/// One path in a snapshot. Snapshots have no volumes, so `volume` is 0.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SnapshotEntry {
+    #[serde(flatten)]
+    entry: IndexEntry,
+    /// The ids of the chunks holding a file's contents, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<String>,
+}
+
+/// The state of the source at one cycle. Paths are `/`-separated and
+/// relative to the source.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SnapshotManifest {
+    source: String,
+    created: String,
+    entries: BTreeMap<String, SnapshotEntry>,
+}
+
+/// A snapshot target: content-addressed chunks under `chunks/`, stored
+/// zstd-compressed and named by their xxh3-128 hash, so identical contents
+/// are stored once however many files and snapshots hold them, and one
+/// manifest per snapshot under `snapshots/`.
+pub struct SnapshotStore {
+    directory: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(directory: &Path) -> SnapshotStore {
+        SnapshotStore {
+            directory: directory.to_path_buf(),
+        }
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.directory
+            .join(CHUNKS_DIRECTORY)
+            .join(&id[..2.min(id.len())])
+            .join(id)
+    }
+
+    /// The snapshot names, oldest first.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let directory = self.directory.join(SNAPSHOTS_DIRECTORY);
+        if !directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::<String>::new();
+        for entry in fs::read_dir(directory)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            match name.strip_suffix(MANIFEST_EXTENSION) {
+                Some(name) if snapshot_time(name).is_some() => names.push(name.to_string()),
+                _ => {}
+            }
+        }
+        sort_snapshots(&mut names);
+        Ok(names)
+    }
+
+    /// Reads the manifest of the snapshot `name`, which must be a name
+    /// `list` could return, so nothing outside `snapshots/` is read.
+    fn read_manifest(&self, name: &str) -> io::Result<SnapshotManifest> {
+        if snapshot_time(name).is_none() || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a snapshot name", name),
+            ));
+        }
+        let path = self
+            .directory
+            .join(SNAPSHOTS_DIRECTORY)
+            .join(format!("{}{}", name, MANIFEST_EXTENSION));
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Writes a manifest under a new name, which only appears once it is
+    /// complete. Returns the name.
+    fn write_manifest(&self, manifest: &SnapshotManifest) -> io::Result<String> {
+        let directory = self.directory.join(SNAPSHOTS_DIRECTORY);
+        fs::create_dir_all(&directory)?;
+        let name = snapshot_name(&directory, chrono::Utc::now(), MANIFEST_EXTENSION);
+        let path = directory.join(&name);
+        let partial = directory.join(format!("{}.partial", name));
+        fs::write(&partial, serde_json::to_vec_pretty(manifest)?)?;
+        fs::rename(&partial, &path)?;
+        Ok(name[..name.len() - MANIFEST_EXTENSION.len()].to_string())
+    }
+
+    /// Stores a chunk unless the store already has it. Returns its id and
+    /// whether it was new.
+    fn store_chunk(&self, data: &[u8]) -> io::Result<(String, bool)> {
+        let id = format!("{:032x}", xxh3_128(data));
+        let path = self.chunk_path(&id);
+        if path.exists() {
+            return Ok((id, false));
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        let partial = path.with_extension("partial");
+        fs::write(&partial, zstd::encode_all(data, 0)?)?;
+        fs::rename(&partial, &path)?;
+        Ok((id, true))
+    }
+
+    /// Reads a chunk back, checking it still has the hash it is named by.
+    fn read_chunk(&self, id: &str) -> io::Result<Vec<u8>> {
+        let data = zstd::decode_all(File::open(self.chunk_path(id))?)?;
+        if format!("{:032x}", xxh3_128(&data)) != id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk {} is corrupted", id),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Removes the snapshots `retention` doesn't keep. Returns their names.
+    pub fn prune(&self, retention: Retention) -> io::Result<Vec<String>> {
+        let names = self
+            .list()?
+            .into_iter()
+            .filter_map(|name| snapshot_time(&name).map(|time| (name, time)))
+            .collect::<Vec<(String, chrono::DateTime<chrono::Utc>)>>();
+        let kept = retention.keep(&names.iter().map(|(_, time)| *time).collect::<Vec<_>>());
+
+        let mut removed = Vec::<String>::new();
+        for (i, (name, _)) in names.into_iter().enumerate() {
+            if kept.contains(&i) {
+                continue;
+            }
+            info!("Pruning snapshot {}", name);
+            fs::remove_file(
+                self.directory
+                    .join(SNAPSHOTS_DIRECTORY)
+                    .join(format!("{}{}", name, MANIFEST_EXTENSION)),
+            )?;
+            removed.push(name);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the chunks no snapshot refers to, and any left partially
+    /// written. Returns the number removed and the bytes freed.
+    pub fn collect_garbage(&self) -> io::Result<(usize, u64)> {
+        let mut referenced = HashSet::<String>::new();
+        for name in self.list()? {
+            for entry in self.read_manifest(&name)?.entries.into_values() {
+                referenced.extend(entry.chunks);
+            }
+        }
+
+        let mut removed = 0;
+        let mut freed = 0;
+        let chunks = self.directory.join(CHUNKS_DIRECTORY);
+        if !chunks.exists() {
+            return Ok((0, 0));
+        }
+        for prefix in fs::read_dir(chunks)? {
+            let prefix = prefix?.path();
+            for chunk in fs::read_dir(&prefix)? {
+                let chunk = chunk?;
+                let id = chunk.file_name().to_string_lossy().to_string();
+                if referenced.contains(&id) {
+                    continue;
+                }
+                freed += chunk.metadata()?.len();
+                fs::remove_file(chunk.path())?;
+                removed += 1;
+            }
+            // Only succeeds once the prefix directory is empty.
+            let _ = fs::remove_dir(&prefix);
+        }
+        Ok((removed, freed))
+    }
+}
+
+fn gear_table() -> &'static [u64; 256] {
+    static TABLE: OnceLock<[u64; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = xxh3_64_with_seed(&[i as u8], 0x5eed);
+        }
+        table
+    })
+}
+
+/// The length of the chunk at the start of `data`, which holds at least
+/// `MAX_CHUNK_SIZE` bytes unless it is the end of the file.
+fn chunk_length(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let gear = gear_table();
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(gear[*byte as usize]);
+        if hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits a file into chunks and stores them. Returns the chunk ids and the
+/// number of bytes in chunks the store didn't have yet.
+fn store_file(store: &SnapshotStore, path: &Path) -> io::Result<(Vec<String>, u64)> {
+    let mut file = File::open(path)?;
+    let mut buffer = Vec::<u8>::with_capacity(MAX_CHUNK_SIZE);
+    let mut chunks = Vec::<String>::new();
+    let mut stored = 0;
+    let mut end_of_file = false;
+    loop {
+        while !end_of_file && buffer.len() < MAX_CHUNK_SIZE {
+            let start = buffer.len();
+            buffer.resize(MAX_CHUNK_SIZE, 0);
+            let read = match file.read(&mut buffer[start..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                result => result?,
+            };
+            buffer.truncate(start + read);
+            end_of_file = read == 0 && start == buffer.len();
+        }
+        if buffer.is_empty() {
+            return Ok((chunks, stored));
+        }
+        let length = chunk_length(&buffer);
+        let (id, new) = store.store_chunk(&buffer[..length])?;
+        if new {
+            stored += length as u64;
+        }
+        chunks.push(id);
+        buffer.drain(..length);
+    }
+}
+
+/// Writes a snapshot of the source to `target` if anything changed since
+/// the last one, then applies the `--keep-*` retention.
+fn sync_snapshot(
+    o: &ProgramOptions,
+    source_dir: &String,
+    target: &str,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let store = SnapshotStore::new(Path::new(target));
+    let previous = match store.list()?.last() {
+        Some(name) => store.read_manifest(name)?,
+        None => SnapshotManifest::default(),
+    };
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+
+    let mut manifest = SnapshotManifest {
+        source: source_dir.clone(),
+        created: chrono::Local::now().to_rfc3339(),
+        entries: BTreeMap::new(),
+    };
+    let mut changed = false;
+    let files = change_detector.enumerate_directory(source_dir, "source", true, o.symlinks);
+    for file in files {
+        if change_detector.find_skip_folder(&file).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        let key = match archive_key(&file) {
+            Some(key) => key,
+            None => {
+                warn!(
+                    "Skipped {} because snapshot paths must be valid UTF-8.",
+                    file.display_path()
+                );
+                report.skips += 1;
+                continue;
+            }
+        };
+
+        let entry = match build_index_entry(o, &compare, &file, 0) {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Keep the previous version rather than dropping the file.
+                error!("Unable to read {}: {}", file.display_path(), e);
+                report.record_error(format!("read {}: {}", file.display_path(), e));
+                if let Some(recorded) = previous.entries.get(&key) {
+                    manifest.entries.insert(key, recorded.clone());
+                }
+                continue;
+            }
+        };
+        let action_type = match previous.entries.get(&key) {
+            None => ActionType::Create,
+            Some(recorded) if entry_differs(&recorded.entry, &entry, &compare) => {
+                ActionType::Update
+            }
+            Some(recorded) => {
+                manifest.entries.insert(key, recorded.clone());
+                continue;
+            }
+        };
+
+        let (chunks, stored) = match entry.kind {
+            EntryKind::File => match store_file(&store, &file.get_path()) {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Unable to {} {}: {}", action_type, file.display_path(), e);
+                    report.record_error(format!("{} {}: {}", action_type, file.display_path(), e));
+                    if let Some(recorded) = previous.entries.get(&key) {
+                        manifest.entries.insert(key, recorded.clone());
+                    }
+                    continue;
+                }
+            },
+            _ => (Vec::new(), 0),
+        };
+        report.record_change(&action_type, &key, stored);
+        manifest
+            .entries
+            .insert(key, SnapshotEntry { entry, chunks });
+        changed = true;
+    }
+
+    for (key, recorded) in &previous.entries {
+        if manifest.entries.contains_key(key) {
+            continue;
+        }
+        if o.enable_deletes {
+            report.record_change(&ActionType::Delete, key, 0);
+            changed = true;
+        } else {
+            report.skips += 1;
+            manifest.entries.insert(key.clone(), recorded.clone());
+        }
+    }
+
+    if changed || store.list()?.is_empty() {
+        let name = store.write_manifest(&manifest)?;
+        info!(
+            job = o.job_name.as_str(),
+            target = target;
+            "Wrote snapshot {} with {} entries", name, manifest.entries.len()
+        );
+    } else {
+        info!("{} is up to date.", target);
+    }
+
+    let retention = Retention::from_options(o);
+    if !retention.is_empty() {
+        store.prune(retention)?;
+        let (removed, freed) = store.collect_garbage()?;
+        info!("Removed {} unused chunks, freeing {} bytes", removed, freed);
+    }
+    Ok(())
+}
+
+/// Syncs the source into every `snapshot:` target.
+pub fn sync_snapshot_targets(o: &ProgramOptions) -> Vec<TargetReport> {
+    let source_dir = o.get_source_directory();
+    o.get_snapshot_targets()
+        .iter()
+        .map(|target| {
+            info!("Snapshot target is {}", target);
+            let mut report = TargetReport::new(&source_dir, target);
+            if let Err(e) = sync_snapshot(o, &source_dir, target, &mut report) {
+                error!("Unable to snapshot to {}: {}", target, e);
+                report.record_error(format!("snapshot {}: {}", target, e));
+            }
+            report.finish();
+            report
+        })
+        .collect()
+}
+
+/// Rebuilds the tree a snapshot holds into `directory`, the latest one
+/// unless `name` is given. Returns the number of entries restored.
+pub fn restore_snapshot(
+    store_path: &Path,
+    name: Option<&str>,
+    directory: &Path,
+) -> io::Result<usize> {
+    let store = SnapshotStore::new(store_path);
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => store.list()?.pop().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no snapshots", store_path.display()),
+            )
+        })?,
+    };
+    let manifest = store.read_manifest(&name)?;
+    info!("Restoring snapshot {}", name);
+
+    fs::create_dir_all(directory)?;
+    for (key, recorded) in &manifest.entries {
+        let path = restore_path(directory, key)?;
+        prepare_restore_path(&path)?;
+        let entry = &recorded.entry;
+        match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&path)?,
+            EntryKind::Symlink => {
+                let target = entry.link_target.as_deref().unwrap_or_default();
+                create_symlink(Path::new(target), &path)?;
+            }
+            EntryKind::File => {
+                let mut file = File::create(&path)?;
+                for id in &recorded.chunks {
+                    file.write_all(&store.read_chunk(id)?)?;
+                }
+                drop(file);
+                restore_file_metadata(&path, entry)?;
+            }
+        }
+    }
+
+    // Directory times last, deepest first, since restoring their contents
+    // changes them.
+    for (key, recorded) in manifest.entries.iter().rev() {
+        if recorded.entry.kind == EntryKind::Directory {
+            set_modified(&restore_path(directory, key)?, entry_time(&recorded.entry))?;
+        }
+    }
+    Ok(manifest.entries.len())
+}
+
+/// Runs a snapshots subcommand and returns the process exit code.
+pub fn run_snapshots(o: &ProgramOptions, command: &SnapshotCommand) -> i32 {
+    match command {
+        SnapshotCommand::List { store } => {
+            let store = SnapshotStore::new(Path::new(store));
+            let result = store.list().and_then(|names| {
+                for name in names {
+                    let manifest = store.read_manifest(&name)?;
+                    let size = manifest.entries.values().map(|x| x.entry.size).sum::<u64>();
+                    println!(
+                        "{}  {} entries  {} bytes",
+                        name,
+                        manifest.entries.len(),
+                        size
+                    );
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    error!("Unable to list snapshots: {}", e);
+                    2
+                }
+            }
+        }
+        SnapshotCommand::Restore {
+            store,
+            directory,
+            snapshot,
+        } => match restore_snapshot(Path::new(store), snapshot.as_deref(), Path::new(directory)) {
+            Ok(count) => {
+                info!("Restored {} entries to {}", count, directory);
+                0
+            }
+            Err(e) => {
+                error!("Unable to restore from {}: {}", store, e);
+                2
+            }
+        },
+        SnapshotCommand::Prune { store } => {
+            let retention = Retention::from_options(o);
+            if retention.is_empty() {
+                error!("Pruning needs at least one of the --keep-* options.");
+                return 2;
+            }
+            let store = SnapshotStore::new(Path::new(store));
+            match store
+                .prune(retention)
+                .and_then(|removed| Ok((removed, store.collect_garbage()?)))
+            {
+                Ok((removed, (chunks, freed))) => {
+                    info!(
+                        "Pruned {} snapshots and {} unused chunks, freeing {} bytes",
+                        removed.len(),
+                        chunks,
+                        freed
+                    );
+                    0
+                }
+                Err(e) => {
+                    error!("Unable to prune snapshots: {}", e);
+                    2
+                }
+            }
+        }
+    }
+}
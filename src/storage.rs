@@ -0,0 +1,327 @@
+use crate::archive::{archive_key, build_index_entry, entry_time, EntryKind, IndexEntry};
+use crate::change_detector::ChangeDetector;
+use crate::comparison::CompareStrategy;
+use crate::configuration::{ProgramOptions, UpdatePolicy};
+use crate::hashing::{hash_file_with, hash_reader};
+use crate::paths::{extension_matches, extension_of, ActionType, RelativePath, UNIX_SPLITTER};
+use crate::report::TargetReport;
+use crate::timestamps::TimeTolerance;
+
+use log::{error, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How precisely remote servers store modification times: SFTP only
+/// carries whole seconds.
+const STORED_TIME_GRANULARITY: Duration = Duration::from_secs(1);
+
+/// A target that isn't a local directory. Entries are addressed by their
+/// `/`-separated path relative to the target root, the way archive keys
+/// are, and described by `IndexEntry`s with a `volume` of 0.
+pub trait Storage {
+    /// Every entry under the root, parents before children. Links are
+    /// listed as links, never followed.
+    fn list(&self) -> io::Result<BTreeMap<String, IndexEntry>>;
+
+    /// Reads a stored file.
+    fn open(&self, key: &str) -> io::Result<Box<dyn Read + '_>>;
+
+    fn create_dir(&self, key: &str) -> io::Result<()>;
+
+    /// Replaces a file with `contents` so that it never appears partially
+    /// written, then gives it the entry's modification time and, with
+    /// `permissions`, its permissions. Returns the bytes written.
+    fn write_file(
+        &self,
+        key: &str,
+        contents: &mut dyn Read,
+        entry: &IndexEntry,
+        permissions: bool,
+    ) -> io::Result<u64>;
+
+    fn create_symlink(&self, key: &str, target: &str) -> io::Result<()>;
+
+    /// Gives a stored file or directory the entry's modification time and,
+    /// with `permissions`, its permissions.
+    fn set_metadata(&self, key: &str, entry: &IndexEntry, permissions: bool) -> io::Result<()>;
+
+    /// Removes a file, link or empty directory.
+    fn remove(&self, key: &str, kind: EntryKind) -> io::Result<()>;
+}
+
+/// Whether a stored path is one the sync manages, rather than one the skip
+/// folders or extensions filters leave alone.
+fn is_managed(
+    change_detector: &ChangeDetector,
+    o: &ProgramOptions,
+    key: &str,
+    kind: EntryKind,
+) -> bool {
+    let relative = RelativePath::new(Path::new(key), true);
+    if change_detector.find_skip_folder_of(&relative).is_some() {
+        return false;
+    }
+    match kind {
+        EntryKind::File => {
+            extension_matches(extension_of(Path::new(key)).as_ref(), o.extensions.clone())
+        }
+        _ => true,
+    }
+}
+
+/// How a stored entry must change to match the source one, if at all,
+/// compared the way directory targets are: files by the comparison
+/// strategy with times matched within `tolerance`, and permissions only
+/// with `--preserve-permissions`, which are set alone when nothing else
+/// differs. Stored files are only read back when hashes are compared.
+fn stored_change(
+    storage: &dyn Storage,
+    o: &ProgramOptions,
+    compare: &CompareStrategy,
+    tolerance: TimeTolerance,
+    key: &str,
+    stored: &IndexEntry,
+    entry: &IndexEntry,
+) -> io::Result<Option<ActionType>> {
+    let differs = stored.kind != entry.kind
+        || match entry.kind {
+            EntryKind::Directory => false,
+            EntryKind::Symlink => stored.link_target != entry.link_target,
+            EntryKind::File => {
+                (compare.size && stored.size != entry.size)
+                    || (compare.modified && !tolerance.same(entry_time(stored), entry_time(entry)))
+                    || (compare.hash
+                        && (stored.size != entry.size
+                            || Some(hash_reader(
+                                &mut storage.open(key)?,
+                                o.hash_algorithm,
+                                o.hash_buffer_size,
+                            )?) != entry.hash))
+            }
+        };
+    if differs {
+        return Ok(Some(ActionType::Update));
+    }
+    if o.preserve_permissions && entry.kind != EntryKind::Symlink && stored.mode != entry.mode {
+        return Ok(Some(ActionType::UpdateMetadata));
+    }
+    Ok(None)
+}
+
+/// Whether `--update-policy` lets a stored entry be replaced by the source
+/// one, as it does for directory targets.
+fn update_permitted(
+    policy: UpdatePolicy,
+    tolerance: TimeTolerance,
+    stored: &IndexEntry,
+    entry: &IndexEntry,
+) -> bool {
+    match policy {
+        UpdatePolicy::Always => true,
+        UpdatePolicy::Never => false,
+        UpdatePolicy::Newer => tolerance.newer(entry_time(entry), entry_time(stored)),
+        UpdatePolicy::Larger => entry.size > stored.size,
+    }
+}
+
+/// Removes a stored entry and, for directories, everything under it,
+/// deepest first.
+fn remove_tree(
+    storage: &dyn Storage,
+    stored: &mut BTreeMap<String, IndexEntry>,
+    key: &str,
+) -> io::Result<()> {
+    let prefix = format!("{}{}", key, UNIX_SPLITTER);
+    let descendants = stored
+        .range(prefix.clone()..)
+        .take_while(|(x, _)| x.starts_with(&prefix))
+        .map(|(x, entry)| (x.clone(), entry.kind))
+        .collect::<Vec<(String, EntryKind)>>();
+    for (descendant, kind) in descendants.into_iter().rev() {
+        storage.remove(&descendant, kind)?;
+        stored.remove(&descendant);
+    }
+    if let Some(entry) = stored.remove(key) {
+        storage.remove(key, entry.kind)?;
+    }
+    Ok(())
+}
+
+/// Uploads a source file, reading it back to check its hash when the
+/// comparison asks for verification.
+fn upload(
+    storage: &dyn Storage,
+    o: &ProgramOptions,
+    compare: &CompareStrategy,
+    key: &str,
+    path: &Path,
+    entry: &IndexEntry,
+) -> io::Result<u64> {
+    let bytes = storage.write_file(key, &mut File::open(path)?, entry, o.preserve_permissions)?;
+    if compare.verify {
+        let expected = hash_file_with(path, o.hash_algorithm, o.hash_buffer_size, o.hash_mmap)?;
+        let actual = hash_reader(
+            &mut storage.open(key)?,
+            o.hash_algorithm,
+            o.hash_buffer_size,
+        )?;
+        if expected != actual {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("verification failed for {}", key),
+            ));
+        }
+    }
+    Ok(bytes)
+}
+
+/// Creates, updates and deletes entries in `storage` so that it mirrors the
+/// source, the way a sync does for a local directory target.
+pub fn sync_storage(
+    o: &ProgramOptions,
+    source_dir: &String,
+    storage: &dyn Storage,
+    report: &mut TargetReport,
+) -> io::Result<()> {
+    let change_detector = ChangeDetector::new(o.clone());
+    let compare = CompareStrategy::from_options(o);
+    let tolerance = TimeTolerance::for_target(
+        Some(STORED_TIME_GRANULARITY),
+        o.modify_window,
+        o.ignore_time_shifts,
+    );
+    let mut stored = storage.list()?;
+    info!("{} item(s) found in target.", stored.len());
+
+    let mut sources = BTreeMap::<String, (IndexEntry, PathBuf)>::new();
+    // Source paths that couldn't be read, whose stored copies are kept.
+    let mut unreadable = HashSet::<String>::new();
+    for file in change_detector.enumerate_directory(source_dir, "source", true, o.symlinks) {
+        if change_detector.find_skip_folder(&file).is_some() {
+            report.skips += 1;
+            continue;
+        }
+        match archive_key(&file) {
+            Some(key) => match build_index_entry(o, &compare, &file, 0) {
+                Ok(entry) => {
+                    sources.insert(key, (entry, file.get_path()));
+                }
+                Err(e) => {
+                    error!("Unable to read {}: {}", file.display_path(), e);
+                    report.record_error(format!("read {}: {}", file.display_path(), e));
+                    unreadable.insert(key);
+                }
+            },
+            None => {
+                warn!(
+                    "Skipped {} because remote paths must be valid UTF-8.",
+                    file.display_path()
+                );
+                report.skips += 1;
+            }
+        }
+    }
+
+    // Deletes first, deepest first, so that nothing is left in the way.
+    let deletes = stored
+        .iter()
+        .rev()
+        .filter(|(key, _)| !sources.contains_key(*key) && !unreadable.contains(*key))
+        .filter(|(key, entry)| is_managed(&change_detector, o, key, entry.kind))
+        .map(|(key, entry)| (key.clone(), entry.kind))
+        .collect::<Vec<(String, EntryKind)>>();
+    for (key, kind) in deletes {
+        if !o.enable_deletes {
+            report.skips += 1;
+            continue;
+        }
+        match storage.remove(&key, kind) {
+            Ok(()) => {
+                stored.remove(&key);
+                report.record_change(&ActionType::Delete, &key, 0);
+            }
+            Err(e) => {
+                error!("Unable to delete {}: {}", key, e);
+                report.record_error(format!("delete {}: {}", key, e));
+            }
+        }
+    }
+
+    for (key, (entry, path)) in &sources {
+        let action_type = match stored.get(key) {
+            None => ActionType::Create,
+            Some(recorded) => {
+                match stored_change(storage, o, &compare, tolerance, key, recorded, entry) {
+                    Ok(None) => continue,
+                    Ok(Some(ActionType::Update))
+                        if !update_permitted(o.update_policy, tolerance, recorded, entry) =>
+                    {
+                        if tolerance.newer(entry_time(recorded), entry_time(entry)) {
+                            warn!(
+                                "Skipped {} because the target copy is newer than the source.",
+                                key
+                            );
+                            report.newer_on_target.push(key.clone());
+                        } else {
+                            info!(
+                                "Skipped {} because of the {} update policy.",
+                                key, o.update_policy
+                            );
+                        }
+                        report.skips += 1;
+                        continue;
+                    }
+                    Ok(Some(action_type)) => action_type,
+                    Err(e) => {
+                        error!("Unable to compare {}: {}", key, e);
+                        report.record_error(format!("compare {}: {}", key, e));
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let result = (|| -> io::Result<u64> {
+            if action_type == ActionType::UpdateMetadata {
+                return storage
+                    .set_metadata(key, entry, o.preserve_permissions)
+                    .map(|_| 0);
+            }
+            if stored.get(key).is_some_and(|x| x.kind != entry.kind) {
+                remove_tree(storage, &mut stored, key)?;
+            }
+            match entry.kind {
+                EntryKind::Directory => storage.create_dir(key).map(|_| 0),
+                EntryKind::Symlink => {
+                    if stored.contains_key(key) {
+                        storage.remove(key, EntryKind::Symlink)?;
+                    }
+                    let target = entry.link_target.as_deref().unwrap_or_default();
+                    storage.create_symlink(key, target).map(|_| 0)
+                }
+                EntryKind::File => upload(storage, o, &compare, key, path, entry),
+            }
+        })();
+        match result {
+            Ok(bytes) => report.record_change(&action_type, key, bytes),
+            Err(e) => {
+                error!("Unable to {} {}: {}", action_type, key, e);
+                report.record_error(format!("{} {}: {}", action_type, key, e));
+            }
+        }
+    }
+
+    // Directory times last, deepest first, since writing their contents
+    // changes them.
+    for (key, (entry, _)) in sources.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            if let Err(e) = storage.set_metadata(key, entry, o.preserve_permissions) {
+                warn!("Unable to set the modification time of {}: {}", key, e);
+            }
+        }
+    }
+    Ok(())
+}
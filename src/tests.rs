@@ -28,3 +28,2374 @@ fn test_path_comparison() {
     assert_eq!(contains, true);
 }
 
+/// Names are matched whole, and case only matters when asked to.
+#[test]
+fn test_whole_name_matching() {
+    use crate::paths::RelativePath;
+
+    let pp1_segment = PathParser::new(&String::from("C:\\Users\\jfast\\Desktop")).get_segment();
+    let upper = PathParser::new(&String::from("C:\\USERS\\jfast\\Desktop")).get_segment();
+    assert!(pp1_segment.identical(&upper, false));
+    assert!(!pp1_segment.identical(&upper, true));
+    let partial = PathParser::new(&String::from("Desk")).get_segment();
+    assert!(!pp1_segment.contains_all_of_segment(&partial));
+
+    // Skip folders follow the case sensitivity of the entry's path.
+    let skip = PathParser::new(&String::from("node")).get_segment();
+    let path = std::path::Path::new("a/Node/b.txt");
+    assert!(RelativePath::new(path, false).contains_all_of_segment(&skip));
+    assert!(!RelativePath::new(path, true).contains_all_of_segment(&skip));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hook_environment_and_exit_status() {
+    use crate::configuration::ProgramOptions;
+    use crate::hooks::{HookType, Hooks};
+    use clap::Parser;
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        "source",
+        "--job-name",
+        "nightly",
+        "--pre-cycle-hook",
+        "test \"$QC_JOB\" = nightly && test \"$QC_HOOK\" = pre_cycle",
+        "--post-cycle-hook",
+        "exit 3",
+    ]);
+    let hooks = Hooks::new(o.clone());
+    let environment = vec![(String::from("QC_JOB"), o.job_name.clone().into())];
+    assert!(hooks.run(HookType::PreCycle, &environment));
+    assert!(!hooks.run(HookType::PostCycle, &environment));
+    assert!(hooks.run(HookType::OnError, &environment));
+}
+
+/// The pre-cycle hook runs before the source is read, and the post-cycle
+/// hook runs for special targets as well as directories.
+#[cfg(unix)]
+#[test]
+fn test_cycle_hooks() {
+    use crate::configuration::ProgramOptions;
+    use clap::Parser;
+
+    let dir = test_directory("cycle-hooks");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    let archive = format!("archive:{}", dir.join("out.tar").to_str().unwrap());
+    let posted = dir.join("posted");
+    let post_hook = format!("echo \"$QC_TARGET_DIR\" >> {}", posted.to_str().unwrap());
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "-t",
+        archive.as_str(),
+        "--pre-cycle-hook",
+        "echo dumped > \"$QC_SOURCE_DIR/dump.sql\"",
+        "--post-cycle-hook",
+        post_hook.as_str(),
+    ]);
+    crate::run_cycle(o);
+
+    assert_eq!(
+        std::fs::read_to_string(target.join("dump.sql")).unwrap(),
+        "dumped\n"
+    );
+    let posted = std::fs::read_to_string(posted).unwrap();
+    assert_eq!(posted.lines().count(), 2, "{}", posted);
+    assert!(posted.contains("out.tar"));
+}
+
+fn test_directory(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("quick-copy-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_parse_config_args() {
+    let contents = "# comment\n\n--log-level debug\n--log-file=/var/log/qc.log\n";
+    let args = crate::configuration::parse_config_args(contents);
+    assert_eq!(
+        args,
+        vec!["--log-level", "debug", "--log-file", "/var/log/qc.log"]
+    );
+}
+
+#[test]
+fn test_rotating_file_retention() {
+    use crate::logging::RotatingFile;
+    use std::io::Write;
+
+    let dir = test_directory("rotation");
+    let path = dir.join("output.log");
+    let mut file = RotatingFile::open(&path, Some(16), false, 2).unwrap();
+    for _ in 0..5 {
+        file.write_all(b"0123456789\n").unwrap();
+    }
+    file.flush().unwrap();
+
+    let entries = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(entries, 3);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789\n");
+
+    // Counters order numerically, and files that merely share the prefix
+    // are left alone.
+    for name in [
+        "output.log.20000101-000000-9",
+        "output.log.20000101-000000-10",
+    ] {
+        std::fs::write(dir.join(name), "").unwrap();
+    }
+    std::fs::write(dir.join("output.log.bak"), "").unwrap();
+    let mut file = RotatingFile::open(&path, Some(16), false, 4).unwrap();
+    file.write_all(b"0123456789\n").unwrap();
+    file.flush().unwrap();
+    assert!(dir.join("output.log.bak").exists());
+    assert!(dir.join("output.log.20000101-000000-10").exists());
+    assert!(!dir.join("output.log.20000101-000000-9").exists());
+
+    // Logging carries on when the file can't be moved aside.
+    file.write_all(b"0123456789\n").unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(b"0123456789\n").unwrap();
+    file.flush().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_run_report_files() {
+    use crate::paths::ActionType;
+    use crate::report::{write_report_files, RunReport, TargetReport};
+
+    let dir = test_directory("report");
+    let mut target = TargetReport::new("/src", "/dst");
+    target.record_change(&ActionType::Create, "/dst/<a>.txt", 10);
+    target.record_change(&ActionType::Delete, "/dst/b.txt", 0);
+    target.record_error(String::from("update /src/c.txt: denied"));
+    target.finish();
+    let now = chrono::Local::now();
+    let report = RunReport::new("nightly", now, now, vec![target]);
+
+    let written = write_report_files(&dir, true, &report).unwrap();
+    assert_eq!(written.len(), 2);
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&written[0]).unwrap()).unwrap();
+    assert_eq!(json["targets"][0]["creates"], 1);
+    assert_eq!(json["targets"][0]["deletes"], 1);
+    assert_eq!(json["targets"][0]["errors"], 1);
+    assert_eq!(json["targets"][0]["bytes_transferred"], 10);
+    let html = std::fs::read_to_string(&written[1]).unwrap();
+    assert!(html.contains("/dst/&lt;a&gt;.txt"));
+
+    // Job names can't escape the directory, and runs never overwrite each
+    // other.
+    let report = RunReport::new("../../nightly", now, now, Vec::new());
+    let first = write_report_files(&dir, false, &report).unwrap();
+    let second = write_report_files(&dir, false, &report).unwrap();
+    assert_ne!(first, second);
+    for path in first.iter().chain(&second) {
+        assert_eq!(path.parent().unwrap(), dir);
+        assert!(path.exists());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_diff_reports_differences_read_only() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::{OutputFormat, ProgramOptions};
+    use crate::diff::{run_diff, TargetDiff};
+    use clap::Parser;
+
+    let dir = test_directory("diff");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    let missing = dir.join("missing");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("same.txt"), "same").unwrap();
+    std::fs::write(target.join("same.txt"), "same").unwrap();
+    std::fs::write(source.join("changed.txt"), "longer").unwrap();
+    std::fs::write(target.join("changed.txt"), "short").unwrap();
+    std::fs::write(source.join("new.txt"), "new").unwrap();
+    std::fs::write(target.join("old.txt"), "old").unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "diff",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "-t",
+        missing.to_str().unwrap(),
+        "--update-compare-size",
+    ]);
+    let diffs = ChangeDetector::new_read_only(o)
+        .three_way_merge()
+        .iter()
+        .map(TargetDiff::from_action_list)
+        .collect::<Vec<TargetDiff>>();
+
+    assert_eq!(diffs[0].only_in_source, vec!["new.txt"]);
+    assert_eq!(diffs[0].only_in_target, vec!["old.txt"]);
+    assert_eq!(diffs[0].differing.len(), 1);
+    assert_eq!(diffs[0].differing[0].path, "changed.txt");
+    assert_eq!(diffs[0].differing[0].reasons, vec!["size"]);
+    assert_eq!(diffs[1].only_in_source.len(), 3);
+    assert!(!missing.exists());
+
+    // Targets diff can't read fail the run rather than being left out.
+    let encrypted = format!("encrypted:{}", missing.to_str().unwrap());
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "diff",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "-t",
+        &encrypted,
+    ]);
+    assert_eq!(run_diff(&o, OutputFormat::Json), 2);
+    assert!(!missing.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_manifest_create_and_verify() {
+    use crate::configuration::ProgramOptions;
+    use crate::hashing::HashAlgorithm;
+    use crate::manifest::{create_manifest, read_manifest, verify_manifest};
+    use clap::Parser;
+
+    let dir = test_directory("manifest");
+    let tree = dir.join("tree");
+    std::fs::create_dir_all(tree.join("sub")).unwrap();
+    std::fs::write(tree.join("a.txt"), "abc").unwrap();
+    std::fs::write(tree.join("sub").join("b.txt"), "b").unwrap();
+    let tree_string = tree.to_str().unwrap().to_string();
+    let manifest = tree.join("SHA256SUMS");
+
+    let o = ProgramOptions::parse_from(["quick-copy", "manifest", "create", &tree_string]);
+    assert_eq!(
+        create_manifest(&o, &tree_string, &manifest, HashAlgorithm::Sha256).unwrap(),
+        2
+    );
+    let entries = read_manifest(&manifest).unwrap();
+    assert_eq!(
+        entries[b"a.txt".as_slice()],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    std::fs::write(tree.join("a.txt"), "abd").unwrap();
+    std::fs::remove_file(tree.join("sub").join("b.txt")).unwrap();
+    std::fs::write(tree.join("c.txt"), "c").unwrap();
+    let verification = verify_manifest(&o, &tree_string, &manifest, HashAlgorithm::Sha256).unwrap();
+    assert_eq!(verification.corrupted, vec!["a.txt"]);
+    assert_eq!(verification.missing, vec!["sub/b.txt"]);
+    assert_eq!(verification.extra, vec!["c.txt"]);
+    assert!(verification.failed.is_empty());
+
+    // Newlines and backslashes in names are escaped as GNU tools do.
+    #[cfg(unix)]
+    {
+        let escaped = dir.join("escaped");
+        let escaped_string = escaped.to_str().unwrap().to_string();
+        std::fs::create_dir_all(&escaped).unwrap();
+        std::fs::write(escaped.join("new\nline"), "abc").unwrap();
+        std::fs::write(escaped.join("back\\slash"), "abc").unwrap();
+        let manifest = dir.join("ESCAPED");
+        create_manifest(&o, &escaped_string, &manifest, HashAlgorithm::Sha256).unwrap();
+        let contents = std::fs::read_to_string(&manifest).unwrap();
+        assert!(contents.starts_with("\\ba7816bf"));
+        assert!(contents.contains("  back\\\\slash\n"));
+        assert!(contents.contains("  new\\nline\n"));
+        let verification =
+            verify_manifest(&o, &escaped_string, &manifest, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(verification.verified, 2);
+        assert!(verification.is_ok());
+    }
+
+    let missing = dir.join("missing").to_str().unwrap().to_string();
+    assert!(create_manifest(&o, &missing, &dir.join("MISSING"), HashAlgorithm::Sha256).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hash_algorithms() {
+    use crate::configuration::ProgramOptions;
+    use crate::hashing::{hash_file, hash_file_with, HashAlgorithm};
+    use clap::Parser;
+
+    let dir = test_directory("hashing");
+    let path = dir.join("abc.txt");
+    std::fs::write(&path, "abc").unwrap();
+
+    assert_eq!(
+        hash_file(&path, HashAlgorithm::Md5).unwrap(),
+        "900150983cd24fb0d6963f7d28e17f72"
+    );
+    assert_eq!(
+        hash_file(&path, HashAlgorithm::Blake3).unwrap(),
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+    let xxh3_128 = hash_file(&path, HashAlgorithm::Xxh3128).unwrap();
+    assert_eq!(xxh3_128.len(), 32);
+    assert_eq!(
+        hash_file_with(&path, HashAlgorithm::Xxh3128, 2, true).unwrap(),
+        xxh3_128
+    );
+    assert_eq!(
+        hash_file_with(&path, HashAlgorithm::Xxh3128, 1, false).unwrap(),
+        xxh3_128
+    );
+
+    let o = ProgramOptions::parse_from(["quick-copy", "-s", "a", "--compare-md5"]);
+    assert!(o.update_compare_hash);
+    assert_eq!(o.hash_algorithm, HashAlgorithm::Xxh3128);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_get_all_files_is_ordered() {
+    let dir = test_directory("walk");
+    std::fs::create_dir_all(dir.join("b").join("c")).unwrap();
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::write(dir.join("b").join("c").join("z.txt"), "z").unwrap();
+    std::fs::write(dir.join("b").join("y.txt"), "yy").unwrap();
+    std::fs::write(dir.join("a").join("x.txt"), "x").unwrap();
+
+    let files =
+        crate::files::get_all_files(&dir, crate::configuration::SymlinkPolicy::Follow).unwrap();
+    let relative = files
+        .iter()
+        .map(|(path, _)| {
+            let relative = path.strip_prefix(&dir).unwrap();
+            relative.to_str().unwrap().replace('\\', "/")
+        })
+        .collect::<Vec<String>>();
+    assert_eq!(
+        relative,
+        vec!["a", "a/x.txt", "b", "b/c", "b/c/z.txt", "b/y.txt"]
+    );
+    assert_eq!(files[5].1.len(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_file_names_are_synced() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use clap::Parser;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = test_directory("latin1");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+    std::fs::create_dir_all(source.join(name)).unwrap();
+    std::fs::write(source.join(name).join(name), "latin-1").unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "--update-compare-size",
+    ]);
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    assert_eq!(actions[0].actions.len(), 2);
+    let reports = Copier::new(o.clone()).incremental_copy(actions);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(
+        std::fs::read_to_string(target.join(name).join(name)).unwrap(),
+        "latin-1"
+    );
+
+    let actions = ChangeDetector::new(o).incremental_changes();
+    assert!(actions[0].actions.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_case_collisions_on_insensitive_target() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::paths::ActionType;
+    use clap::Parser;
+
+    let dir = test_directory("case");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("Docs")).unwrap();
+    std::fs::create_dir_all(source.join("docs")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("Docs").join("a.txt"), "a").unwrap();
+    std::fs::write(source.join("Report.txt"), "upper").unwrap();
+    std::fs::write(source.join("report.txt"), "lower").unwrap();
+    std::fs::write(source.join("unique.txt"), "unique").unwrap();
+    std::fs::write(target.join("REPORT.TXT"), "old").unwrap();
+    assert_eq!(
+        crate::files::probe_case_sensitive(&source, false),
+        Some(true)
+    );
+
+    let options = |case_sensitivity: &str| {
+        ProgramOptions::parse_from([
+            "quick-copy",
+            "diff",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "--case-sensitivity",
+            case_sensitivity,
+        ])
+    };
+
+    let insensitive = ChangeDetector::new_read_only(options("insensitive")).three_way_merge();
+    let mut collisions = insensitive[0].collisions.clone();
+    collisions.sort();
+    assert_eq!(
+        collisions,
+        vec![vec!["Docs", "docs"], vec!["Report.txt", "report.txt"]]
+    );
+    assert_eq!(insensitive[0].actions.len(), 1);
+    assert_eq!(insensitive[0].actions[0].action_type, ActionType::Create);
+    assert_eq!(insensitive[0].skipped, 6);
+
+    let sensitive = ChangeDetector::new_read_only(options("sensitive")).three_way_merge();
+    assert!(sensitive[0].collisions.is_empty());
+    assert_eq!(sensitive[0].count(ActionType::Create), 6);
+    assert_eq!(sensitive[0].count(ActionType::Delete), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_policies() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::{ProgramOptions, SymlinkPolicy};
+    use crate::copier::Copier;
+    use crate::files::get_all_files;
+    use crate::paths::UpdateReason;
+    use clap::Parser;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    let dir = test_directory("symlinks");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(dir.join("outside")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+    symlink("a.txt", source.join("inside")).unwrap();
+    symlink("..", source.join("sub").join("loop")).unwrap();
+    symlink(dir.join("outside"), source.join("outside")).unwrap();
+    symlink("missing", source.join("broken")).unwrap();
+
+    let listed = |policy: SymlinkPolicy| {
+        get_all_files(&source, policy)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path.strip_prefix(&source).unwrap().to_path_buf())
+            .collect::<Vec<PathBuf>>()
+    };
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    assert_eq!(
+        listed(SymlinkPolicy::Follow),
+        paths(&["a.txt", "inside", "outside", "sub"])
+    );
+    assert_eq!(
+        listed(SymlinkPolicy::FollowSafe),
+        paths(&["a.txt", "inside", "sub"])
+    );
+    assert_eq!(listed(SymlinkPolicy::Skip), paths(&["a.txt", "sub"]));
+    assert_eq!(listed(SymlinkPolicy::CopyAsLink).len(), 6);
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "--symlinks",
+        "copy-as-link",
+    ]);
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    let reports = Copier::new(o.clone()).incremental_copy(actions);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(
+        std::fs::read_link(target.join("sub").join("loop")).unwrap(),
+        Path::new("..")
+    );
+    assert_eq!(
+        std::fs::read_link(target.join("broken")).unwrap(),
+        Path::new("missing")
+    );
+    assert!(ChangeDetector::new(o.clone()).incremental_changes()[0]
+        .actions
+        .is_empty());
+
+    std::fs::remove_file(source.join("inside")).unwrap();
+    symlink("sub", source.join("inside")).unwrap();
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    assert_eq!(actions[0].actions.len(), 1);
+    assert_eq!(
+        actions[0].actions[0].reasons,
+        vec![UpdateReason::LinkTarget]
+    );
+    Copier::new(o).incremental_copy(actions);
+    assert_eq!(
+        std::fs::read_link(target.join("inside")).unwrap(),
+        Path::new("sub")
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hard_links_are_preserved() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::paths::UpdateReason;
+    use clap::Parser;
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = test_directory("hardlinks");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("a.txt"), "shared").unwrap();
+    std::fs::hard_link(source.join("a.txt"), source.join("b.txt")).unwrap();
+    std::fs::hard_link(source.join("a.txt"), source.join("sub").join("c.txt")).unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "--preserve-hard-links",
+        "--update-compare-size",
+    ]);
+    let sync = || {
+        let actions = ChangeDetector::new(o.clone()).incremental_changes();
+        let reports = Copier::new(o.clone()).incremental_copy(actions);
+        assert_eq!(reports[0].errors, 0);
+    };
+    let inode = |name: &str| std::fs::metadata(target.join(name)).unwrap().ino();
+    let linked = |names: &[&str]| names.iter().all(|x| inode(x) == inode(names[0]));
+
+    sync();
+    assert!(linked(&["a.txt", "b.txt", "sub/c.txt"]));
+    assert!(ChangeDetector::new(o.clone()).incremental_changes()[0]
+        .actions
+        .is_empty());
+
+    std::fs::hard_link(source.join("a.txt"), source.join("d.txt")).unwrap();
+    std::fs::remove_file(target.join("b.txt")).unwrap();
+    std::fs::write(target.join("b.txt"), "shared").unwrap();
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    assert_eq!(actions[0].actions.len(), 2);
+    assert!(actions[0]
+        .actions
+        .iter()
+        .any(|x| x.reasons == vec![UpdateReason::HardLink]));
+    sync();
+    assert!(linked(&["a.txt", "b.txt", "sub/c.txt", "d.txt"]));
+
+    std::fs::write(source.join("a.txt"), "changed content").unwrap();
+    sync();
+    assert!(linked(&["a.txt", "b.txt", "sub/c.txt", "d.txt"]));
+    assert_eq!(
+        std::fs::read_to_string(target.join("d.txt")).unwrap(),
+        "changed content"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_file_preserves_holes() {
+    use crate::copy_engine::copy_file;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = test_directory("sparse");
+    let source = dir.join("sparse.db");
+    let target = dir.join("copy.db");
+    let length = 64 * 1_048_576;
+    let mut file = std::fs::File::create(&source).unwrap();
+    file.write_all(b"head").unwrap();
+    file.seek(SeekFrom::Start(length / 2)).unwrap();
+    file.write_all(b"middle").unwrap();
+    file.set_len(length).unwrap();
+    drop(file);
+
+    let (copied, _) = copy_file(&source, &target, 4096).unwrap();
+    assert_eq!(copied, length);
+    let contents = std::fs::read(&target).unwrap();
+    assert_eq!(contents.len() as u64, length);
+    assert_eq!(&contents[..4], b"head");
+    assert_eq!(
+        &contents[length as usize / 2..length as usize / 2 + 6],
+        b"middle"
+    );
+
+    // Only check allocation where the filesystem kept the source sparse.
+    let source_metadata = std::fs::metadata(&source).unwrap();
+    if source_metadata.blocks() * 512 < length / 2 {
+        let target_metadata = std::fs::metadata(&target).unwrap();
+        assert!(target_metadata.blocks() * 512 < length / 2);
+    }
+
+    std::fs::write(&source, "").unwrap();
+    assert_eq!(copy_file(&source, &target, 4096).unwrap().0, 0);
+    assert!(std::fs::read(&target).unwrap().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_xattrs_and_acls_are_replicated() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::paths::{ActionType, UpdateReason};
+    use clap::Parser;
+
+    let dir = test_directory("xattrs");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("a.txt"), "contents").unwrap();
+    if xattr::set(source.join("a.txt"), "user.color", b"red").is_err() {
+        // The filesystem doesn't support user attributes.
+        std::fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+    xattr::set(source.join("a.txt"), "user.tag", b"x").unwrap();
+    xattr::set(source.join("sub"), "user.color", b"blue").unwrap();
+
+    // An access ACL granting uid 12345 read: version 2, then (tag, perm, id)
+    // entries for the owner, that user, the group, the mask and others.
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [
+        (0x01u16, 6u16, u32::MAX),
+        (0x02, 4, 12345),
+        (0x04, 4, u32::MAX),
+        (0x10, 4, u32::MAX),
+        (0x20, 4, u32::MAX),
+    ] {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    let has_acl = xattr::set(source.join("a.txt"), "system.posix_acl_access", &acl).is_ok();
+
+    let options = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "--update-compare-size",
+        ];
+        args.extend_from_slice(extra);
+        ProgramOptions::parse_from(args)
+    };
+    let sync = |o: &ProgramOptions| {
+        let actions = ChangeDetector::new(o.clone()).incremental_changes();
+        let reports = Copier::new(o.clone()).incremental_copy(actions);
+        assert_eq!(reports[0].errors, 0);
+    };
+    let get = |path: &str, name: &str| xattr::get(target.join(path), name).unwrap();
+
+    let o = options(&["--xattrs", "--acls"]);
+    sync(&o);
+    assert_eq!(get("a.txt", "user.color"), Some(b"red".to_vec()));
+    assert_eq!(get("sub", "user.color"), Some(b"blue".to_vec()));
+    if has_acl {
+        assert!(get("a.txt", "system.posix_acl_access").is_some());
+    }
+    assert!(ChangeDetector::new(o.clone()).incremental_changes()[0]
+        .actions
+        .is_empty());
+
+    // Attribute-only changes are applied without copying the contents.
+    xattr::set(source.join("a.txt"), "user.color", b"green").unwrap();
+    xattr::remove(source.join("a.txt"), "user.tag").unwrap();
+    std::fs::write(target.join("a.txt"), "CONTENTS").unwrap();
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    assert_eq!(actions[0].actions.len(), 1);
+    assert_eq!(
+        actions[0].actions[0].action_type,
+        ActionType::UpdateMetadata
+    );
+    assert_eq!(actions[0].actions[0].reasons, vec![UpdateReason::Xattr]);
+    sync(&o);
+    assert_eq!(get("a.txt", "user.color"), Some(b"green".to_vec()));
+    assert_eq!(get("a.txt", "user.tag"), None);
+    assert_eq!(
+        std::fs::read_to_string(target.join("a.txt")).unwrap(),
+        "CONTENTS"
+    );
+
+    // Only the selected namespaces are compared.
+    xattr::set(source.join("a.txt"), "user.color", b"red").unwrap();
+    let o = options(&["--xattrs", "--xattr-namespaces", "trusted"]);
+    assert!(ChangeDetector::new(o.clone()).incremental_changes()[0]
+        .actions
+        .is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_only_updates() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::files::set_modified;
+    use crate::paths::{ActionType, UpdateReason};
+    use clap::Parser;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    let dir = test_directory("metadata");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("a.txt"), "contents").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), "b").unwrap();
+    let past = SystemTime::now() - Duration::from_secs(86_400);
+    set_modified(&source.join("sub"), past).unwrap();
+
+    let options = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "--update-compare-size",
+            "--preserve-permissions",
+            "--preserve-times",
+        ];
+        args.extend_from_slice(extra);
+        ProgramOptions::parse_from(args)
+    };
+    let sync = |o: &ProgramOptions| {
+        let actions = ChangeDetector::new(o.clone()).incremental_changes();
+        let reports = Copier::new(o.clone()).incremental_copy(actions);
+        assert_eq!(reports[0].errors, 0);
+    };
+    let actions = |o: &ProgramOptions| {
+        ChangeDetector::new(o.clone()).incremental_changes()[0]
+            .actions
+            .iter()
+            .map(|x| (x.action_type.clone(), x.reasons.clone()))
+            .collect::<Vec<(ActionType, Vec<UpdateReason>)>>()
+    };
+    let modified = |path: &std::path::Path| std::fs::metadata(path).unwrap().modified().unwrap();
+
+    let o = options(&[]);
+    sync(&o);
+    assert_eq!(modified(&target.join("sub")), past);
+    assert_eq!(
+        modified(&target.join("a.txt")),
+        modified(&source.join("a.txt"))
+    );
+    assert!(actions(&o).is_empty());
+
+    // A permission change is applied in place, leaving the contents alone.
+    let mode = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(source.join("a.txt"), mode).unwrap();
+    std::fs::write(target.join("a.txt"), "CONTENTS").unwrap();
+    set_modified(&target.join("a.txt"), modified(&source.join("a.txt"))).unwrap();
+    assert_eq!(
+        actions(&o),
+        vec![(ActionType::UpdateMetadata, vec![UpdateReason::Mode])]
+    );
+    sync(&o);
+    let target_mode = std::fs::metadata(target.join("a.txt"))
+        .unwrap()
+        .permissions();
+    assert_eq!(target_mode.mode() & 0o7777, 0o600);
+    assert_eq!(
+        std::fs::read_to_string(target.join("a.txt")).unwrap(),
+        "CONTENTS"
+    );
+
+    // An mtime-only difference is a content change unless a hash shows
+    // the contents match.
+    std::fs::write(target.join("a.txt"), "contents").unwrap();
+    set_modified(&source.join("a.txt"), past).unwrap();
+    assert_eq!(
+        actions(&options(&["--update-compare-modified"])),
+        vec![(ActionType::Update, vec![UpdateReason::Modified])]
+    );
+    let o = options(&["--update-compare-modified", "--compare-hash"]);
+    assert_eq!(
+        actions(&o),
+        vec![(ActionType::UpdateMetadata, vec![UpdateReason::Times])]
+    );
+    sync(&o);
+    assert_eq!(modified(&target.join("a.txt")), past);
+    assert!(actions(&o).is_empty());
+
+    // A mode without owner read is applied after the times, which no
+    // longer need the file opened.
+    let mode = std::fs::Permissions::from_mode(0o200);
+    std::fs::set_permissions(source.join("a.txt"), mode).unwrap();
+    sync(&o);
+    let target_mode = std::fs::metadata(target.join("a.txt"))
+        .unwrap()
+        .permissions();
+    assert_eq!(target_mode.mode() & 0o7777, 0o200);
+    assert_eq!(modified(&target.join("a.txt")), past);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_update_policies() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::files::set_modified;
+    use crate::paths::ActionType;
+    use clap::Parser;
+    use std::time::{Duration, SystemTime};
+
+    let dir = test_directory("update-policy");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let now = SystemTime::now();
+    let past = now - Duration::from_secs(86_400);
+    for (name, source_contents, source_time, target_contents, target_time) in [
+        ("a.txt", "aaaa", now, "a", past),
+        ("b.txt", "b", past, "bbbb", now),
+        ("c.txt", "cccc", past, "c", now),
+    ] {
+        std::fs::write(source.join(name), source_contents).unwrap();
+        set_modified(&source.join(name), source_time).unwrap();
+        std::fs::write(target.join(name), target_contents).unwrap();
+        set_modified(&target.join(name), target_time).unwrap();
+    }
+    std::fs::write(source.join("d.txt"), "d").unwrap();
+
+    let options = |policy: &str| {
+        ProgramOptions::parse_from([
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "--update-compare-size",
+            "--update-compare-modified",
+            "--update-policy",
+            policy,
+        ])
+    };
+    let plan = |policy: &str| {
+        let action_list = ChangeDetector::new(options(policy))
+            .incremental_changes()
+            .remove(0);
+        let mut updated = action_list
+            .actions
+            .iter()
+            .filter(|x| x.action_type == ActionType::Update)
+            .map(|x| x.source.as_ref().unwrap().get_relative_path().display())
+            .collect::<Vec<String>>();
+        updated.sort();
+        assert_eq!(action_list.count(ActionType::Create), 1);
+        (updated, action_list.newer_on_target)
+    };
+
+    assert_eq!(plan("always").0, vec!["a.txt", "b.txt", "c.txt"]);
+    assert!(plan("always").1.is_empty());
+    assert_eq!(plan("newer").0, vec!["a.txt"]);
+    assert_eq!(plan("newer").1, vec!["b.txt", "c.txt"]);
+    assert_eq!(plan("larger").0, vec!["a.txt", "c.txt"]);
+    assert_eq!(plan("larger").1, vec!["b.txt"]);
+    assert!(plan("never").0.is_empty());
+    assert_eq!(plan("never").1, vec!["b.txt", "c.txt"]);
+
+    let o = options("newer");
+    let actions = ChangeDetector::new(o.clone()).incremental_changes();
+    let reports = Copier::new(o).incremental_copy(actions);
+    assert_eq!(reports[0].newer_on_target, vec!["b.txt", "c.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(target.join("a.txt")).unwrap(),
+        "aaaa"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.join("b.txt")).unwrap(),
+        "bbbb"
+    );
+    assert_eq!(std::fs::read_to_string(target.join("d.txt")).unwrap(), "d");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_modify_window_and_time_shifts() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::files::set_modified;
+    use crate::timestamps::{infer_time_granularity, probe_time_granularity, TimeTolerance};
+    use clap::Parser;
+    use std::time::{Duration, SystemTime};
+
+    for window in ["inf", "1e30", "-1", "NaN"] {
+        assert!(ProgramOptions::try_parse_from(["quick-copy", "--modify-window", window]).is_err());
+    }
+
+    let now = SystemTime::now();
+    // FAT targets accept whole-hour shifts on their own; others only when
+    // asked to.
+    let fat = TimeTolerance::for_target(Some(Duration::from_secs(2)), None, false);
+    assert_eq!(
+        fat,
+        TimeTolerance {
+            window: Duration::from_secs(2),
+            shifts: true,
+        }
+    );
+    let ext4 = TimeTolerance::for_target(Some(Duration::ZERO), None, false);
+    assert!(!ext4.same(now, now + Duration::from_secs(3_600)));
+    let window = Some(Duration::from_secs(2));
+    assert!(!TimeTolerance::for_target(Some(Duration::from_secs(1)), window, false).shifts);
+    assert!(TimeTolerance::for_target(None, window, true).shifts);
+    assert!(fat.same(now, now + Duration::from_millis(1_999)));
+    assert!(fat.same(now, now - Duration::from_secs(3_601)));
+    assert!(fat.same(now, now + Duration::from_secs(5 * 3_600 + 1)));
+    assert!(!fat.same(now, now + Duration::from_secs(5 * 3_600 + 30 * 60)));
+    assert!(!fat.same(now, now + Duration::from_secs(15 * 60)));
+    assert!(!fat.same(now, now + Duration::from_secs(3_610)));
+    assert!(!fat.same(now, now + Duration::from_secs(15 * 3_600)));
+    assert!(fat.newer(now + Duration::from_secs(3), now));
+    assert!(!fat.newer(now + Duration::from_secs(1), now));
+    let exact = TimeTolerance {
+        window: Duration::ZERO,
+        shifts: false,
+    };
+    assert!(!exact.same(now, now + Duration::from_nanos(1)));
+    assert!(!exact.same(now, now + Duration::from_secs(3_600)));
+
+    let dir = test_directory("modify-window");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let granularity = probe_time_granularity(&target, true).unwrap();
+    assert!(granularity <= Duration::from_secs(2));
+    assert!(probe_time_granularity(&target, false).is_none());
+    assert_eq!(std::fs::read_dir(&target).unwrap().count(), 0);
+
+    // Target times truncated to the second, as some SMB servers store them.
+    let modified = now - Duration::from_secs(60);
+    let truncated = SystemTime::UNIX_EPOCH
+        + Duration::from_secs(
+            modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        );
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+    set_modified(&source.join("a.txt"), modified).unwrap();
+    std::fs::write(target.join("a.txt"), "a").unwrap();
+    set_modified(&target.join("a.txt"), truncated).unwrap();
+
+    let changes = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "--update-compare-modified",
+        ];
+        args.extend_from_slice(extra);
+        ChangeDetector::new(ProgramOptions::parse_from(args)).incremental_changes()[0]
+            .actions
+            .len()
+    };
+    let subsecond = modified != truncated;
+    assert_eq!(changes(&["--modify-window", "0"]), subsecond as usize);
+    assert_eq!(changes(&["--modify-window", "1"]), 0);
+
+    // Read-only runs can't probe, so they go by the times already there.
+    assert!(infer_time_granularity(&target).unwrap() >= Duration::from_secs(1));
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "diff",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "--update-compare-modified",
+    ]);
+    assert_eq!(
+        ChangeDetector::new_read_only(o).three_way_merge()[0]
+            .actions
+            .len(),
+        0
+    );
+
+    // A target written an hour off, as after a DST change on FAT.
+    set_modified(&target.join("a.txt"), modified + Duration::from_secs(3_600)).unwrap();
+    assert_eq!(changes(&["--modify-window", "2"]), 1);
+    assert_eq!(
+        changes(&["--modify-window", "2", "--ignore-time-shifts"]),
+        0
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compare_strategies() {
+    use crate::change_detector::ChangeDetector;
+    use crate::comparison::CompareStrategy;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::files::set_modified;
+    use crate::paths::UpdateReason;
+    use clap::Parser;
+    use std::time::{Duration, SystemTime};
+
+    let dir = test_directory("compare");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    std::fs::write(source.join("a.txt"), "contents").unwrap();
+
+    let options = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra);
+        ProgramOptions::parse_from(args)
+    };
+    let strategy = |extra: &[&str]| CompareStrategy::from_options(&options(extra)).to_string();
+    assert_eq!(strategy(&[]), "size, mtime");
+    assert_eq!(strategy(&["--update-compare-size"]), "size");
+    assert_eq!(strategy(&["--compare", "checksum"]), "size, hash");
+    assert_eq!(
+        strategy(&["--compare", "paranoid"]),
+        "size, mtime, hash (copies verified)"
+    );
+    assert_eq!(
+        strategy(&["--compare", "quick", "--compare-hash"]),
+        "size, mtime, hash"
+    );
+
+    let reasons = |extra: &[&str]| {
+        ChangeDetector::new(options(extra)).incremental_changes()[0]
+            .actions
+            .iter()
+            .map(|x| x.reasons.clone())
+            .collect::<Vec<Vec<UpdateReason>>>()
+    };
+
+    // Copies take the source mtime, so nothing looks changed afterwards.
+    let o = options(&["--compare", "paranoid"]);
+    let reports = Copier::new(o.clone())
+        .incremental_copy(ChangeDetector::new(o.clone()).incremental_changes());
+    assert_eq!(reports[0].errors, 0);
+    assert!(reasons(&[]).is_empty());
+    assert!(reasons(&["--compare", "paranoid"]).is_empty());
+
+    // Without any flags a same-size edit is still caught by its mtime.
+    std::fs::write(source.join("a.txt"), "CONTENTS").unwrap();
+    set_modified(
+        &source.join("a.txt"),
+        SystemTime::now() + Duration::from_secs(60),
+    )
+    .unwrap();
+    assert_eq!(reasons(&[]), vec![vec![UpdateReason::Modified]]);
+    assert_eq!(
+        reasons(&["--compare", "checksum"]),
+        vec![vec![UpdateReason::Hash]]
+    );
+
+    // A touched but unchanged file only differs by mtime, which the
+    // checksum preset ignores.
+    std::fs::write(source.join("a.txt"), "contents").unwrap();
+    assert_eq!(reasons(&[]), vec![vec![UpdateReason::Modified]]);
+    assert!(reasons(&["--compare", "checksum"]).is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_archive_targets_and_restore() {
+    use crate::archive::{restore_archive, sync_archives};
+    use crate::configuration::ProgramOptions;
+    use clap::Parser;
+
+    let dir = test_directory("archive");
+    let source = dir.join("source");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), "b").unwrap();
+    std::fs::write(source.join("c.txt"), "c").unwrap();
+
+    let tree = |root: &std::path::Path| {
+        let mut entries = Vec::<(String, String)>::new();
+        for path in ["a.txt", "sub/b.txt", "c.txt", "d.txt"] {
+            if let Ok(contents) = std::fs::read_to_string(root.join(path)) {
+                entries.push((path.to_string(), contents));
+            }
+        }
+        entries
+    };
+
+    for name in ["project.tar.zst", "project.zip", "project.tar"] {
+        let archive = dir.join("archives").join(name);
+        std::fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        std::fs::write(source.join("a.txt"), "a").unwrap();
+        std::fs::write(source.join("c.txt"), "c").unwrap();
+        let _ = std::fs::remove_file(source.join("d.txt"));
+        let target = format!("archive:{}", archive.to_str().unwrap());
+        let o = ProgramOptions::parse_from([
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.as_str(),
+            "--enable-deletes",
+        ]);
+        assert!(o.get_target_directories().is_empty());
+
+        let reports = sync_archives(&o);
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!(reports[0].creates, 4);
+        assert!(archive.exists());
+        assert_eq!(sync_archives(&o)[0].creates, 0);
+
+        // Only the changes go into the next volume.
+        std::fs::write(source.join("a.txt"), "changed").unwrap();
+        std::fs::write(source.join("d.txt"), "d").unwrap();
+        std::fs::remove_file(source.join("c.txt")).unwrap();
+        let reports = sync_archives(&o);
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!(
+            (reports[0].creates, reports[0].updates, reports[0].deletes),
+            (1, 1, 1)
+        );
+        let increment = archive.with_file_name(name.replacen('.', ".001.", 1));
+        assert!(increment.exists());
+
+        // A permission change alone is archived too.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &std::path::Path| {
+                std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+            };
+            let permissions = std::fs::Permissions::from_mode(0o600);
+            std::fs::set_permissions(source.join("a.txt"), permissions).unwrap();
+            assert_eq!(sync_archives(&o)[0].updates, 1);
+            assert_eq!(sync_archives(&o)[0].updates, 0);
+            let restored = dir.join("restored-mode").join(name);
+            restore_archive(&archive, &restored).unwrap();
+            assert_eq!(mode(&restored.join("a.txt")), 0o600);
+            let permissions = std::fs::Permissions::from_mode(0o644);
+            std::fs::set_permissions(source.join("a.txt"), permissions).unwrap();
+            sync_archives(&o);
+        }
+
+        let restored = dir.join("restored").join(name);
+        assert_eq!(restore_archive(&archive, &restored).unwrap(), 4);
+        assert_eq!(tree(&restored), tree(&source));
+        assert!(restored.join("sub").is_dir());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_archive_sources() {
+    use crate::archive_source::sync_from_archive;
+    use crate::configuration::ProgramOptions;
+    use clap::Parser;
+    use std::io::Write;
+
+    let dir = test_directory("archive-source");
+    let build = |archive: &std::path::Path, entries: &[(&str, &str)]| {
+        let file = std::fs::File::create(archive).unwrap();
+        if archive.extension().unwrap() == "zip" {
+            let mut zip = zip::ZipWriter::new(file);
+            let options = zip::write::SimpleFileOptions::default().last_modified_time(
+                zip::DateTime::from_date_and_time(2024, 5, 1, 12, 0, 0).unwrap(),
+            );
+            for (name, contents) in entries {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        } else {
+            let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            let mut tar = tar::Builder::new(encoder);
+            for (name, contents) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(1_714_564_800);
+                header.set_cksum();
+                tar.append_data(&mut header, name, contents.as_bytes())
+                    .unwrap();
+            }
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+    };
+
+    for name in ["drop.zip", "drop.tar.gz"] {
+        let archive = dir.join(name);
+        let target = dir.join(format!("{}-target", name));
+        std::fs::create_dir_all(&target).unwrap();
+        build(
+            &archive,
+            &[("a.txt", "a"), ("sub/b.txt", "b"), ("./c.txt", "c")],
+        );
+        let source = format!("archive:{}", archive.to_str().unwrap());
+        let o = ProgramOptions::parse_from([
+            "quick-copy",
+            "-s",
+            source.as_str(),
+            "-t",
+            target.to_str().unwrap(),
+            "--enable-deletes",
+        ]);
+
+        let reports = sync_from_archive(&o, archive.to_str().unwrap());
+        assert_eq!(reports[0].errors, 0);
+        // The missing `sub` directory is created too.
+        assert_eq!(reports[0].creates, 4);
+        assert_eq!(
+            std::fs::read_to_string(target.join("sub").join("b.txt")).unwrap(),
+            "b"
+        );
+        assert_eq!(std::fs::read_to_string(target.join("c.txt")).unwrap(), "c");
+        let reports = sync_from_archive(&o, archive.to_str().unwrap());
+        assert_eq!((reports[0].creates, reports[0].updates), (0, 0));
+
+        build(
+            &archive,
+            &[("a.txt", "changed"), ("sub/b.txt", "b"), ("d.txt", "d")],
+        );
+        let reports = sync_from_archive(&o, archive.to_str().unwrap());
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!(
+            (reports[0].creates, reports[0].updates, reports[0].deletes),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.join("a.txt")).unwrap(),
+            "changed"
+        );
+        assert!(!target.join("c.txt").exists());
+        let reports = sync_from_archive(&o, archive.to_str().unwrap());
+        assert_eq!(
+            (reports[0].creates, reports[0].updates, reports[0].deletes),
+            (0, 0, 0)
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compressed_targets() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use crate::files::set_modified;
+    use crate::paths::UpdateReason;
+    use clap::Parser;
+
+    let dir = test_directory("compressed");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&target).unwrap();
+    let log = "GET /index.html 200\n".repeat(100);
+    std::fs::write(source.join("a.log"), &log).unwrap();
+    std::fs::write(source.join("b.txt"), "b").unwrap();
+
+    let options = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            target.to_str().unwrap(),
+            "-e",
+            "--compare",
+            "paranoid",
+            "--compress-extensions",
+            "log",
+        ];
+        args.extend_from_slice(extra);
+        ProgramOptions::parse_from(args)
+    };
+    let sync = |extra: &[&str]| {
+        let o = options(extra);
+        let reports = Copier::new(o.clone())
+            .incremental_copy(ChangeDetector::new(o.clone()).incremental_changes());
+        assert_eq!(reports[0].errors, 0);
+    };
+    let reasons = |extra: &[&str]| {
+        ChangeDetector::new(options(extra)).incremental_changes()[0]
+            .actions
+            .iter()
+            .map(|x| x.reasons.clone())
+            .collect::<Vec<Vec<UpdateReason>>>()
+    };
+    let names = || {
+        let mut names = std::fs::read_dir(&target)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    };
+
+    sync(&["--compress", "zstd"]);
+    assert_eq!(names(), ["a.log.zst", "a.log.zst.qcmeta", "b.txt"]);
+    let stored = std::fs::read(target.join("a.log.zst")).unwrap();
+    assert!(stored.len() < log.len());
+    assert_eq!(zstd::decode_all(stored.as_slice()).unwrap(), log.as_bytes());
+    assert!(reasons(&["--compress", "zstd"]).is_empty());
+
+    // A same-size edit is found through the hash in the sidecar.
+    let modified = std::fs::metadata(source.join("a.log"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    std::fs::write(source.join("a.log"), log.replace("200", "404")).unwrap();
+    set_modified(&source.join("a.log"), modified).unwrap();
+    assert_eq!(reasons(&["--compress", "zstd"]), [[UpdateReason::Hash]]);
+    sync(&["--compress", "zstd"]);
+    assert!(reasons(&["--compress", "zstd"]).is_empty());
+
+    // Changing how files are stored replaces them.
+    assert_eq!(
+        reasons(&["--compress", "gzip"]),
+        [[UpdateReason::Compression]]
+    );
+    sync(&["--compress", "gzip"]);
+    assert_eq!(names(), ["a.log.gz", "a.log.gz.qcmeta", "b.txt"]);
+    sync(&[]);
+    assert_eq!(names(), ["a.log", "b.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(target.join("a.log")).unwrap(),
+        log.replace("200", "404")
+    );
+
+    sync(&["--compress", "zstd"]);
+    assert_eq!(names(), ["a.log.zst", "a.log.zst.qcmeta", "b.txt"]);
+
+    // A source file named like a compressed one is a collision, and
+    // neither is touched.
+    std::fs::write(source.join("a.log.zst"), "plain").unwrap();
+    let changes = ChangeDetector::new(options(&["--compress", "zstd"])).incremental_changes();
+    assert_eq!(changes[0].collisions, [["a.log", "a.log.zst"]]);
+    assert!(changes[0].actions.is_empty());
+    sync(&["--compress", "zstd"]);
+    assert_eq!(names(), ["a.log.zst", "a.log.zst.qcmeta", "b.txt"]);
+    assert_eq!(
+        zstd::decode_all(std::fs::File::open(target.join("a.log.zst")).unwrap()).unwrap(),
+        log.replace("200", "404").as_bytes()
+    );
+    std::fs::remove_file(source.join("a.log.zst")).unwrap();
+
+    std::fs::remove_file(source.join("a.log")).unwrap();
+    sync(&["--compress", "zstd"]);
+    assert_eq!(names(), ["b.txt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_encrypted_targets() {
+    use crate::configuration::ProgramOptions;
+    use crate::encryption::{decrypt_target, sync_encrypted_targets};
+    use clap::Parser;
+
+    let dir = test_directory("encrypted");
+    let source = dir.join("source");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    let large = "secret ".repeat(20000);
+    std::fs::write(source.join("a.txt"), "secret a").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), &large).unwrap();
+    let key_file = dir.join("key");
+    std::fs::write(&key_file, "11".repeat(32)).unwrap();
+
+    let tree = |root: &std::path::Path| {
+        let mut entries = Vec::<(String, String)>::new();
+        for path in ["a.txt", "sub/b.txt", "c.txt"] {
+            if let Ok(contents) = std::fs::read_to_string(root.join(path)) {
+                entries.push((path.to_string(), contents));
+            }
+        }
+        entries
+    };
+
+    for encrypt_names in [false, true] {
+        let target = dir.join(format!("target-{}", encrypt_names));
+        let spec = format!("encrypted:{}", target.to_str().unwrap());
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            spec.as_str(),
+            "-e",
+            "--key-file",
+            key_file.to_str().unwrap(),
+        ];
+        if encrypt_names {
+            args.push("--encrypt-names");
+        }
+        let o = ProgramOptions::parse_from(args);
+        assert!(o.get_target_directories().is_empty());
+        std::fs::write(source.join("a.txt"), "secret a").unwrap();
+        let _ = std::fs::remove_file(source.join("c.txt"));
+
+        let reports = sync_encrypted_targets(&o);
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!(reports[0].creates, 3);
+        assert_eq!(sync_encrypted_targets(&o)[0].creates, 0);
+        assert_eq!(target.join("a.txt.qce").exists(), !encrypt_names);
+        let stored = std::fs::read_dir(&target)
+            .unwrap()
+            .map(|x| std::fs::read(x.unwrap().path()).unwrap_or_default())
+            .collect::<Vec<Vec<u8>>>();
+        assert!(stored
+            .iter()
+            .all(|x| !String::from_utf8_lossy(x).contains("secret")));
+
+        std::fs::write(source.join("a.txt"), "changed").unwrap();
+        std::fs::write(source.join("c.txt"), "c").unwrap();
+        std::fs::remove_file(source.join("sub").join("b.txt")).unwrap();
+        let reports = sync_encrypted_targets(&o);
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!(
+            (reports[0].creates, reports[0].updates, reports[0].deletes),
+            (1, 1, 1)
+        );
+
+        let restored = dir.join(format!("restored-{}", encrypt_names));
+        assert_eq!(decrypt_target(&target, &restored, &key_file).unwrap(), 3);
+        assert_eq!(tree(&restored), tree(&source));
+        std::fs::write(source.join("sub").join("b.txt"), &large).unwrap();
+
+        // A directory that becomes a file doesn't stop the sync, and its
+        // old contents go with the deletes.
+        std::fs::create_dir_all(source.join("d")).unwrap();
+        std::fs::write(source.join("d").join("e.txt"), "e").unwrap();
+        assert_eq!(sync_encrypted_targets(&o)[0].errors, 0);
+        std::fs::remove_dir_all(source.join("d")).unwrap();
+        std::fs::write(source.join("d"), "d").unwrap();
+        let reports = sync_encrypted_targets(&o);
+        assert_eq!(reports[0].errors, 0);
+        assert_eq!((reports[0].updates, reports[0].deletes), (1, 1));
+        assert_eq!(sync_encrypted_targets(&o)[0].updates, 0);
+        let restored = dir.join(format!("restored-d-{}", encrypt_names));
+        decrypt_target(&target, &restored, &key_file).unwrap();
+        assert_eq!(std::fs::read_to_string(restored.join("d")).unwrap(), "d");
+        std::fs::remove_file(source.join("d")).unwrap();
+
+        // The wrong key can't read the target.
+        let wrong_key = dir.join("wrong-key");
+        std::fs::write(&wrong_key, "22".repeat(32)).unwrap();
+        assert!(decrypt_target(&target, &dir.join("wrong"), &wrong_key).is_err());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Files whose names aren't valid Unicode are recognised as stored
+/// compressed, so they aren't created again or deleted as target-only.
+#[cfg(unix)]
+#[test]
+fn test_compressed_non_utf8_names() {
+    use crate::change_detector::ChangeDetector;
+    use crate::configuration::ProgramOptions;
+    use crate::copier::Copier;
+    use clap::Parser;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = test_directory("compressed-latin1");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    let name = OsStr::from_bytes(b"caf\xe9.log");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join(name), "latin-1").unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.to_str().unwrap(),
+        "-e",
+        "--compress",
+        "zstd",
+    ]);
+    for _ in 0..2 {
+        let reports = Copier::new(o.clone())
+            .incremental_copy(ChangeDetector::new(o.clone()).incremental_changes());
+        assert_eq!(reports[0].errors, 0);
+    }
+    assert!(ChangeDetector::new(o.clone()).incremental_changes()[0]
+        .actions
+        .is_empty());
+    let stored = std::fs::read(target.join(OsStr::from_bytes(b"caf\xe9.log.zst"))).unwrap();
+    assert_eq!(zstd::decode_all(stored.as_slice()).unwrap(), b"latin-1");
+    assert!(target
+        .join(OsStr::from_bytes(b"caf\xe9.log.zst.qcmeta"))
+        .exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A link in an archive can't be used to write outside the target, and
+/// options an archive source can't honour are refused.
+#[cfg(unix)]
+#[test]
+fn test_archive_source_links_stay_inside() {
+    use crate::archive::restore_path;
+    use crate::archive_source::sync_from_archive;
+    use crate::configuration::ProgramOptions;
+    use clap::Parser;
+
+    let dir = test_directory("archive-source-links");
+    let target = dir.join("target");
+    std::fs::create_dir_all(&target).unwrap();
+    let victim = dir.join("victim");
+    std::fs::create_dir_all(&victim).unwrap();
+    let archive = dir.join("links.tar");
+    let mut tar = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    header.set_mode(0o777);
+    tar.append_link(&mut header, "a", &victim).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(4);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, "a/evil.txt", "evil".as_bytes())
+        .unwrap();
+    tar.into_inner().unwrap();
+
+    let source = format!("archive:{}", archive.to_str().unwrap());
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.as_str(),
+        "-t",
+        target.to_str().unwrap(),
+    ]);
+    assert!(o.get_archive_source_conflicts().is_empty());
+    let reports = sync_from_archive(&o, archive.to_str().unwrap());
+    assert_eq!(reports[0].errors, 1);
+    assert_eq!(std::fs::read_link(target.join("a")).unwrap(), victim);
+    assert_eq!(std::fs::read_dir(&victim).unwrap().count(), 0);
+    assert!(restore_path(&target, "a/evil.txt").is_err());
+    assert!(restore_path(&target, "a").is_ok());
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.as_str(),
+        "-t",
+        target.to_str().unwrap(),
+        "-t",
+        "linked:/tmp/x",
+        "--update-policy",
+        "newer",
+        "--compress",
+        "zstd",
+    ]);
+    assert_eq!(
+        o.get_archive_source_conflicts(),
+        vec!["the target linked:/tmp/x", "--update-policy", "--compress"]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A missing target is created, extensions are filtered as for directory
+/// sources, and names differing only by case are reported as collisions.
+#[test]
+fn test_archive_source_targets_and_collisions() {
+    use crate::archive_source::sync_from_archive;
+    use crate::configuration::ProgramOptions;
+    use clap::Parser;
+
+    let dir = test_directory("archive-source-collisions");
+    let target = dir.join("target");
+    let archive = dir.join("names.tar");
+    let mut tar = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+    for name in ["a.txt", "A.txt", "b.txt", ".txt", "c.log"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, "x".as_bytes()).unwrap();
+    }
+    tar.into_inner().unwrap();
+
+    let source = format!("archive:{}", archive.to_str().unwrap());
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.as_str(),
+        "-t",
+        target.to_str().unwrap(),
+        "--case-sensitivity",
+        "insensitive",
+        "-x",
+        "txt",
+    ]);
+    let reports = sync_from_archive(&o, archive.to_str().unwrap());
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(reports[0].collisions, [["A.txt", "a.txt"]]);
+    let mut names = std::fs::read_dir(&target)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(names, [".txt", "b.txt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_retention() {
+    use crate::retention::{snapshot_time, sort_snapshots, Retention};
+
+    let mut names = [
+        "2026-03-29T01-30-00.000Z-10",
+        "2026-03-29T01-30-00.000Z-2",
+        "2026-03-29T01-30-00.000Z",
+        "2026-03-28T23-59-59.999Z",
+    ]
+    .map(String::from);
+    sort_snapshots(&mut names);
+    assert_eq!(
+        names,
+        [
+            "2026-03-28T23-59-59.999Z",
+            "2026-03-29T01-30-00.000Z",
+            "2026-03-29T01-30-00.000Z-2",
+            "2026-03-29T01-30-00.000Z-10",
+        ]
+    );
+    let times = names
+        .iter()
+        .map(|x| snapshot_time(x).unwrap())
+        .collect::<Vec<_>>();
+
+    // Same-millisecond snapshots are newest by position; days are UTC days.
+    let last = Retention {
+        last: 1,
+        ..Retention::default()
+    };
+    assert_eq!(last.keep(&times), [3].into());
+    let daily = Retention {
+        daily: 2,
+        ..Retention::default()
+    };
+    assert_eq!(daily.keep(&times), [0, 3].into());
+}
+
+#[test]
+fn test_snapshot_targets() {
+    use crate::configuration::ProgramOptions;
+    use crate::retention::Retention;
+    use crate::snapshot::{restore_snapshot, sync_snapshot_targets, SnapshotStore};
+    use clap::Parser;
+
+    let dir = test_directory("snapshot");
+    let source = dir.join("source");
+    let store = dir.join("store");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    // Incompressible enough to span several chunks.
+    let large = (0..1_500_000u64)
+        .flat_map(|i| (xxhash_rust::xxh3::xxh3_64(&i.to_le_bytes()) as u32).to_le_bytes())
+        .collect::<Vec<u8>>();
+    std::fs::write(source.join("a.txt"), "first").unwrap();
+    std::fs::write(source.join("sub").join("b.bin"), &large).unwrap();
+    std::fs::write(source.join("copy.bin"), &large).unwrap();
+
+    let spec = format!("snapshot:{}", store.to_str().unwrap());
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        spec.as_str(),
+        "-e",
+    ]);
+    assert!(o.get_target_directories().is_empty());
+
+    let chunk_count = || {
+        std::fs::read_dir(store.join("chunks"))
+            .unwrap()
+            .map(|x| std::fs::read_dir(x.unwrap().path()).unwrap().count())
+            .sum::<usize>()
+    };
+    let tree = |root: &std::path::Path| {
+        ["a.txt", "sub/b.bin", "copy.bin"]
+            .iter()
+            .map(|x| std::fs::read(root.join(x)).ok())
+            .collect::<Vec<Option<Vec<u8>>>>()
+    };
+
+    let reports = sync_snapshot_targets(&o);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(reports[0].creates, 4);
+    // The copy is stored once.
+    assert!(reports[0].bytes_transferred < 2 * large.len() as u64);
+    let chunks = chunk_count();
+    assert!(chunks > 2);
+    assert_eq!(sync_snapshot_targets(&o)[0].creates, 0);
+    let snapshots = SnapshotStore::new(&store).list().unwrap();
+    assert_eq!(snapshots.len(), 1);
+
+    // An insertion only changes the chunks around it.
+    let mut shifted = b"inserted".to_vec();
+    shifted.extend_from_slice(&large);
+    std::fs::write(source.join("copy.bin"), &shifted).unwrap();
+    std::fs::write(source.join("a.txt"), "second!").unwrap();
+    let reports = sync_snapshot_targets(&o);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(reports[0].updates, 2);
+    assert!(reports[0].bytes_transferred < large.len() as u64 / 2);
+    assert_eq!(SnapshotStore::new(&store).list().unwrap().len(), 2);
+
+    let restored = dir.join("restored-latest");
+    assert_eq!(restore_snapshot(&store, None, &restored).unwrap(), 4);
+    assert_eq!(tree(&restored), tree(&source));
+    let restored = dir.join("restored-first");
+    restore_snapshot(&store, Some(&snapshots[0]), &restored).unwrap();
+    assert_eq!(std::fs::read(restored.join("a.txt")).unwrap(), b"first");
+    assert_eq!(std::fs::read(restored.join("copy.bin")).unwrap(), large);
+    let outside = dir.join("outside.json");
+    std::fs::write(&outside, "{}").unwrap();
+    assert!(restore_snapshot(&store, Some("../../outside"), &restored).is_err());
+    assert!(restore_snapshot(&store, Some(&format!("{}/../x", snapshots[0])), &restored).is_err());
+
+    // Pruning to the latest snapshot drops the chunks only the first used.
+    let before = chunk_count();
+    let snapshot_store = SnapshotStore::new(&store);
+    let retention = Retention {
+        last: 1,
+        ..Retention::default()
+    };
+    assert_eq!(snapshot_store.prune(retention).unwrap(), snapshots);
+    let (removed, _) = snapshot_store.collect_garbage().unwrap();
+    assert!(removed > 0);
+    assert_eq!(chunk_count(), before - removed);
+    let restored = dir.join("restored-pruned");
+    restore_snapshot(&store, None, &restored).unwrap();
+    assert_eq!(tree(&restored), tree(&source));
+
+    // A permission change alone is recorded, and restored.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o600);
+        std::fs::set_permissions(source.join("a.txt"), permissions).unwrap();
+        assert_eq!(sync_snapshot_targets(&o)[0].updates, 1);
+        let restored = dir.join("restored-mode");
+        restore_snapshot(&store, None, &restored).unwrap();
+        let metadata = std::fs::metadata(restored.join("a.txt")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    // A file that can't be stored keeps its previous version instead of
+    // being recorded as deleted.
+    let chunks = store.join("chunks");
+    let moved = dir.join("chunks-moved");
+    std::fs::rename(&chunks, &moved).unwrap();
+    std::fs::write(&chunks, "in the way").unwrap();
+    std::fs::write(source.join("a.txt"), "third, longer").unwrap();
+    std::fs::create_dir(source.join("new")).unwrap();
+    let reports = sync_snapshot_targets(&o);
+    assert_eq!(reports[0].errors, 1);
+    assert_eq!((reports[0].creates, reports[0].deletes), (1, 0));
+    std::fs::remove_file(&chunks).unwrap();
+    std::fs::rename(&moved, &chunks).unwrap();
+    let restored = dir.join("restored-failed");
+    restore_snapshot(&store, None, &restored).unwrap();
+    assert_eq!(std::fs::read(restored.join("a.txt")).unwrap(), b"second!");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_linked_targets() {
+    use crate::configuration::ProgramOptions;
+    use crate::linked::{prune_snapshots, sync_linked_targets};
+    use crate::retention::Retention;
+    use clap::Parser;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    let dir = test_directory("linked");
+    let source = dir.join("source");
+    let target = dir.join("target");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a.txt"), "first").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), "unchanged").unwrap();
+
+    let spec = format!("linked:{}", target.to_str().unwrap());
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        spec.as_str(),
+        "-e",
+    ]);
+    assert!(o.get_target_directories().is_empty());
+    let snapshots = || {
+        let mut names = std::fs::read_dir(&target)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|x| x != "latest")
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    };
+
+    let reports = sync_linked_targets(&o);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!(reports[0].creates, 3);
+    assert_eq!(sync_linked_targets(&o)[0].creates, 0);
+    assert_eq!(snapshots().len(), 1);
+
+    std::fs::write(source.join("a.txt"), "second!").unwrap();
+    std::fs::write(source.join("c.txt"), "c").unwrap();
+    let reports = sync_linked_targets(&o);
+    assert_eq!(reports[0].errors, 0);
+    assert_eq!((reports[0].creates, reports[0].updates), (1, 1));
+
+    let names = snapshots();
+    assert_eq!(names.len(), 2);
+    let (first, second) = (target.join(&names[0]), target.join(&names[1]));
+    assert_eq!(
+        std::fs::read_link(target.join("latest")).unwrap(),
+        Path::new(&names[1])
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.join("latest").join("c.txt")).unwrap(),
+        "c"
+    );
+    // Unchanged files share an inode; changed ones don't touch the old copy.
+    let inode = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+    assert_eq!(
+        inode(first.join("sub").join("b.txt")),
+        inode(second.join("sub").join("b.txt"))
+    );
+    assert_ne!(inode(first.join("a.txt")), inode(second.join("a.txt")));
+    assert_eq!(
+        std::fs::read_to_string(first.join("a.txt")).unwrap(),
+        "first"
+    );
+
+    std::fs::remove_file(source.join("c.txt")).unwrap();
+    assert_eq!(sync_linked_targets(&o)[0].deletes, 1);
+    assert!(!target.join("latest").join("c.txt").exists());
+
+    let retention = Retention {
+        last: 1,
+        ..Retention::default()
+    };
+    assert_eq!(prune_snapshots(&target, retention).unwrap(), names);
+    assert_eq!(snapshots().len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(target.join("latest").join("sub").join("b.txt")).unwrap(),
+        "unchanged"
+    );
+
+    // A permission change alone makes a new snapshot, copying the file so
+    // the older snapshot keeps its permissions.
+    use std::os::unix::fs::PermissionsExt;
+    let b = PathBuf::from("sub").join("b.txt");
+    let permissions = std::fs::Permissions::from_mode(0o600);
+    std::fs::set_permissions(source.join(&b), permissions).unwrap();
+    let reports = sync_linked_targets(&o);
+    assert_eq!((reports[0].errors, reports[0].updates), (0, 1));
+    let names = snapshots();
+    let (first, second) = (target.join(&names[0]), target.join(&names[1]));
+    assert_eq!(
+        std::fs::metadata(second.join(&b)).unwrap().mode() & 0o777,
+        0o600
+    );
+    assert_ne!(
+        std::fs::metadata(first.join(&b)).unwrap().mode() & 0o777,
+        0o600
+    );
+    assert_ne!(inode(first.join(&b)), inode(second.join(&b)));
+
+    // Comparing contents links unchanged files again.
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        spec.as_str(),
+        "--compare",
+        "checksum",
+    ]);
+    let reports = sync_linked_targets(&o);
+    assert_eq!((reports[0].errors, reports[0].updates), (0, 0));
+
+    // A snapshot missing a file isn't published; reading /proc/self/mem
+    // from its start always fails.
+    #[cfg(target_os = "linux")]
+    {
+        std::os::unix::fs::symlink("/proc/self/mem", source.join("mem")).unwrap();
+        let o = ProgramOptions::parse_from([
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            spec.as_str(),
+            "--symlinks",
+            "follow",
+        ]);
+        let latest = std::fs::read_link(target.join("latest")).unwrap();
+        let reports = sync_linked_targets(&o);
+        assert_eq!(reports[0].errors, 1);
+        let partial = snapshots()
+            .iter()
+            .filter(|x| x.ends_with(".partial"))
+            .count();
+        assert_eq!((snapshots().len(), partial), (3, 1));
+        assert_eq!(std::fs::read_link(target.join("latest")).unwrap(), latest);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A `Storage` over a local directory, so the remote sync logic can be
+/// tested without a server.
+#[cfg(unix)]
+struct DirectoryStorage(std::path::PathBuf);
+
+#[cfg(unix)]
+impl crate::storage::Storage for DirectoryStorage {
+    fn list(
+        &self,
+    ) -> std::io::Result<std::collections::BTreeMap<String, crate::archive::IndexEntry>> {
+        use crate::archive::{EntryKind, IndexEntry};
+        use std::os::unix::fs::PermissionsExt;
+
+        fn walk(
+            root: &std::path::Path,
+            directory: &std::path::Path,
+            entries: &mut std::collections::BTreeMap<String, IndexEntry>,
+        ) -> std::io::Result<()> {
+            for entry in std::fs::read_dir(directory)? {
+                let path = entry?.path();
+                let metadata = std::fs::symlink_metadata(&path)?;
+                let kind = if metadata.is_symlink() {
+                    EntryKind::Symlink
+                } else if metadata.is_dir() {
+                    EntryKind::Directory
+                } else {
+                    EntryKind::File
+                };
+                let key = path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                entries.insert(
+                    key,
+                    IndexEntry {
+                        volume: 0,
+                        kind,
+                        size: if kind == EntryKind::File {
+                            metadata.len()
+                        } else {
+                            0
+                        },
+                        modified: metadata
+                            .modified()?
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                        modified_nanos: 0,
+                        mode: metadata.permissions().mode() & 0o7777,
+                        owner: None,
+                        link_target: std::fs::read_link(&path)
+                            .ok()
+                            .map(|x| x.to_string_lossy().to_string()),
+                        hash: None,
+                    },
+                );
+                if kind == EntryKind::Directory {
+                    walk(root, &path, entries)?;
+                }
+            }
+            Ok(())
+        }
+
+        let mut entries = std::collections::BTreeMap::new();
+        walk(&self.0, &self.0, &mut entries)?;
+        Ok(entries)
+    }
+
+    fn open(&self, key: &str) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+        Ok(Box::new(std::fs::File::open(self.0.join(key))?))
+    }
+
+    fn create_dir(&self, key: &str) -> std::io::Result<()> {
+        std::fs::create_dir(self.0.join(key))
+    }
+
+    fn write_file(
+        &self,
+        key: &str,
+        contents: &mut dyn std::io::Read,
+        entry: &crate::archive::IndexEntry,
+        permissions: bool,
+    ) -> std::io::Result<u64> {
+        let bytes = std::io::copy(contents, &mut std::fs::File::create(self.0.join(key))?)?;
+        self.set_metadata(key, entry, permissions)?;
+        Ok(bytes)
+    }
+
+    fn create_symlink(&self, key: &str, target: &str) -> std::io::Result<()> {
+        std::os::unix::fs::symlink(target, self.0.join(key))
+    }
+
+    fn set_metadata(
+        &self,
+        key: &str,
+        entry: &crate::archive::IndexEntry,
+        permissions: bool,
+    ) -> std::io::Result<()> {
+        let path = self.0.join(key);
+        match permissions {
+            true => crate::archive::restore_file_metadata(&path, entry),
+            false => crate::files::set_modified(&path, crate::archive::entry_time(entry)),
+        }
+    }
+
+    fn remove(&self, key: &str, kind: crate::archive::EntryKind) -> std::io::Result<()> {
+        match kind {
+            crate::archive::EntryKind::Directory => std::fs::remove_dir(self.0.join(key)),
+            _ => std::fs::remove_file(self.0.join(key)),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_remote_storage() {
+    use crate::configuration::ProgramOptions;
+    use crate::report::TargetReport;
+    use crate::sftp::SftpTarget;
+    use crate::storage::sync_storage;
+    use clap::Parser;
+
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com:/srv/mirror/"),
+        Some(SftpTarget {
+            user: "backup".to_string(),
+            host: "example.com".to_string(),
+            port: 22,
+            path: "/srv/mirror".to_string(),
+        })
+    );
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com:2222/srv").map(|x| (x.port, x.path)),
+        Some((2222, "/srv".to_string()))
+    );
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com:2222:/srv").map(|x| x.port),
+        Some(2222)
+    );
+    assert_eq!(SftpTarget::parse("sftp://backup@example.com"), None);
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com:ssh:/srv"),
+        None
+    );
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com/srv/a@b").map(|x| (x.user, x.path)),
+        Some(("backup".to_string(), "/srv/a@b".to_string()))
+    );
+    assert_eq!(
+        SftpTarget::parse("sftp://backup@example.com:/").map(|x| x.path),
+        Some("/".to_string())
+    );
+
+    let dir = test_directory("remote");
+    let source = dir.join("source");
+    let remote = dir.join("remote");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(remote.join("stale")).unwrap();
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), "b").unwrap();
+    std::os::unix::fs::symlink("a.txt", source.join("link")).unwrap();
+    std::fs::write(remote.join("stale").join("old.txt"), "old").unwrap();
+    // A file where the source has a directory is replaced.
+    std::fs::write(remote.join("sub"), "in the way").unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        "sftp://backup@example.com:/srv/mirror",
+        "-e",
+        "--symlinks",
+        "copy-as-link",
+        "--preserve-permissions",
+    ]);
+    assert!(o.get_target_directories().is_empty());
+    assert_eq!(o.get_sftp_targets().len(), 1);
+    let storage = DirectoryStorage(remote.clone());
+    let sync_with = |o: &ProgramOptions| {
+        let mut report = TargetReport::new(&o.get_source_directory(), "remote");
+        sync_storage(o, &o.get_source_directory(), &storage, &mut report).unwrap();
+        assert_eq!(report.errors, 0, "{:?}", report.error_messages);
+        report
+    };
+    let sync = || {
+        let report = sync_with(&o);
+        (report.creates, report.updates, report.deletes)
+    };
+
+    assert_eq!(sync(), (3, 1, 2));
+    assert_eq!(
+        std::fs::read_to_string(remote.join("sub").join("b.txt")).unwrap(),
+        "b"
+    );
+    assert_eq!(
+        std::fs::read_link(remote.join("link")).unwrap(),
+        std::path::Path::new("a.txt")
+    );
+    assert!(!remote.join("stale").exists());
+    assert_eq!(sync(), (0, 0, 0));
+
+    std::fs::write(source.join("a.txt"), "changed").unwrap();
+    std::fs::remove_file(source.join("sub").join("b.txt")).unwrap();
+    assert_eq!(sync(), (0, 1, 1));
+    assert_eq!(
+        std::fs::read_to_string(remote.join("a.txt")).unwrap(),
+        "changed"
+    );
+    assert!(!remote.join("sub").join("b.txt").exists());
+
+    // Permission-only changes are pushed without uploading again.
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(source.join("a.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+    std::fs::set_permissions(source.join("sub"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    let report = sync_with(&o);
+    assert_eq!((report.updates, report.metadata_updates), (0, 2));
+    assert_eq!(report.bytes_transferred, 0);
+    let mode = |x: &str| {
+        std::fs::metadata(remote.join(x))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+    assert_eq!((mode("a.txt"), mode("sub")), (0o600, 0o700));
+    assert_eq!(sync(), (0, 0, 0));
+
+    // Other options are honoured as for directory targets: permissions
+    // only with --preserve-permissions, times within --modify-window, and
+    // --update-policy.
+    let with = |extra: &[&str]| {
+        let mut args = vec![
+            "quick-copy",
+            "-s",
+            source.to_str().unwrap(),
+            "-t",
+            "sftp://backup@example.com:/srv/mirror",
+            "--symlinks",
+            "copy-as-link",
+        ];
+        args.extend_from_slice(extra);
+        ProgramOptions::parse_from(args)
+    };
+    std::fs::set_permissions(source.join("a.txt"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    let report = sync_with(&with(&[]));
+    assert_eq!((report.updates, report.metadata_updates), (0, 0));
+    assert_eq!(mode("a.txt"), 0o600);
+
+    let stored = std::fs::metadata(remote.join("a.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let later = stored + std::time::Duration::from_secs(3);
+    crate::files::set_modified(&source.join("a.txt"), later).unwrap();
+    assert_eq!(sync_with(&with(&["--modify-window", "5"])).updates, 0);
+    let report = sync_with(&with(&["--update-policy", "never"]));
+    assert_eq!((report.updates, report.skips), (0, 1));
+    crate::files::set_modified(
+        &source.join("a.txt"),
+        stored - std::time::Duration::from_secs(3),
+    )
+    .unwrap();
+    let report = sync_with(&with(&["--update-policy", "newer"]));
+    assert_eq!(report.updates, 0);
+    assert_eq!(report.newer_on_target, vec!["a.txt"]);
+    crate::files::set_modified(&source.join("a.txt"), later).unwrap();
+    assert_eq!(sync_with(&with(&["--update-policy", "newer"])).updates, 1);
+    assert_eq!(mode("a.txt"), 0o600);
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        "sftp://backup@example.com:/srv/mirror",
+        "--compress",
+        "zstd",
+        "--preserve-owner",
+        "--update-policy",
+        "newer",
+    ]);
+    assert_eq!(
+        o.get_sftp_conflicts(),
+        vec!["--preserve-owner", "--compress"]
+    );
+    // Directory targets alongside take the options the SFTP ones ignore.
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        "sftp://backup@example.com:/srv/mirror",
+        "-t",
+        dir.join("local").to_str().unwrap(),
+        "--compress",
+        "zstd",
+    ]);
+    assert!(o.get_sftp_conflicts().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Needs an SSH server. Run with
+/// `QUICK_COPY_SFTP_TARGET=sftp://user@localhost:/tmp/qc QUICK_COPY_SSH_KEY=~/.ssh/id_ed25519 cargo test sftp_localhost -- --ignored`.
+#[test]
+#[ignore]
+fn test_sftp_localhost() {
+    use crate::configuration::ProgramOptions;
+    use crate::sftp::sync_sftp_targets;
+    use clap::Parser;
+
+    let target = std::env::var("QUICK_COPY_SFTP_TARGET").unwrap();
+    let key_file = std::env::var("QUICK_COPY_SSH_KEY").unwrap();
+    let dir = test_directory("sftp");
+    let source = dir.join("source");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+    std::fs::write(source.join("sub").join("b.txt"), "b").unwrap();
+
+    let o = ProgramOptions::parse_from([
+        "quick-copy",
+        "-s",
+        source.to_str().unwrap(),
+        "-t",
+        target.as_str(),
+        "-e",
+        "--ssh-key-file",
+        key_file.as_str(),
+    ]);
+    let reports = sync_sftp_targets(&o);
+    assert_eq!(reports[0].errors, 0, "{:?}", reports[0].error_messages);
+    let reports = sync_sftp_targets(&o);
+    assert_eq!(
+        (reports[0].creates, reports[0].updates, reports[0].deletes),
+        (0, 0, 0)
+    );
+    std::fs::remove_file(source.join("sub").join("b.txt")).unwrap();
+    assert_eq!(sync_sftp_targets(&o)[0].deletes, 1);
+}
+
+/// Builds and joins a synthetic tree of 1M source and 1M target entries.
+/// The sorted merge has to pair them correctly and take less time than
+/// building the entries did, which it does by a wide margin.
+/// Run with `cargo test --release bench_join_1m_entries -- --ignored`.
+#[test]
+#[ignore]
+fn bench_join_1m_entries() {
+    use crate::change_detector::join_entries;
+    use crate::paths::FileInfoParser;
+    use rayon::prelude::*;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    let dir = test_directory("bench-join");
+    std::fs::write(dir.join("file"), "x").unwrap();
+    let metadata = std::fs::metadata(dir.join("file")).unwrap();
+    let build = |root: &Arc<Path>, missing: usize| {
+        (0..1_000_000usize)
+            .into_par_iter()
+            .filter(|i| i % 10 != missing)
+            .map(|i| {
+                let path = root
+                    .join(format!("d{:03}", i / 10_000))
+                    .join(format!("e{:03}", (i / 100) % 100))
+                    .join(format!("file-{:07}.dat", i));
+                FileInfoParser::with_metadata(&path, root, metadata.clone(), false)
+            })
+            .collect::<Vec<FileInfoParser>>()
+    };
+
+    let started = Instant::now();
+    let source = build(&Arc::from(Path::new("/data/source")), 0);
+    let target = build(&Arc::from(Path::new("/data/target")), 1);
+    let built = started.elapsed();
+
+    let started = Instant::now();
+    let joined = join_entries(source, target);
+    let elapsed = started.elapsed();
+
+    assert_eq!(joined.in_first_only.len(), 100_000);
+    assert_eq!(joined.in_second_only.len(), 100_000);
+    assert_eq!(joined.in_both.len(), 800_000);
+    assert!(
+        elapsed < built,
+        "joined in {:?}, built in {:?}",
+        elapsed,
+        built
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Timestamp resolutions found in the wild, finest first: NTFS, common
+/// network filesystems, whole seconds (some SMB servers) and FAT/exFAT.
+const GRANULARITIES: [Duration; 5] = [
+    Duration::from_nanos(100),
+    Duration::from_micros(1),
+    Duration::from_millis(1),
+    Duration::from_secs(1),
+    FAT_GRANULARITY,
+];
+
+/// The resolution of FAT and exFAT, which also store local time rather
+/// than UTC.
+pub const FAT_GRANULARITY: Duration = Duration::from_secs(2);
+
+/// How many stored times `infer_time_granularity` looks at.
+const SAMPLE_SIZE: usize = 1000;
+
+/// Offsets FAT timestamps move by when the clock's timezone or DST setting
+/// changes, since FAT stores local time. Only whole hours, up to 14 of
+/// them, are accepted: half- and quarter-hour zones are rare, and allowing
+/// them would hide real edits that happen to move a file's time by such a
+/// step.
+const SHIFT_STEP: Duration = Duration::from_secs(60 * 60);
+const MAX_SHIFT: Duration = Duration::from_secs(14 * 60 * 60);
+
+/// How far apart two modification times may be and still count as equal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeTolerance {
+    pub window: Duration,
+    /// Also accept differences that are a whole-hour timezone or DST
+    /// shift, within `window`.
+    pub shifts: bool,
+}
+
+impl TimeTolerance {
+    /// The tolerance for a target that stores times to within
+    /// `granularity`: `window` when given, otherwise the granularity.
+    /// Whole-hour shifts are accepted on FAT targets, which move by them
+    /// when the clock's timezone or DST setting changes, and on any target
+    /// with `ignore_shifts`.
+    pub fn for_target(
+        granularity: Option<Duration>,
+        window: Option<Duration>,
+        ignore_shifts: bool,
+    ) -> TimeTolerance {
+        TimeTolerance {
+            window: window.or(granularity).unwrap_or(Duration::ZERO),
+            shifts: ignore_shifts || granularity == Some(FAT_GRANULARITY),
+        }
+    }
+
+    /// Whether `first` and `second` are the same time, allowing for the
+    /// target's precision.
+    pub fn same(&self, first: SystemTime, second: SystemTime) -> bool {
+        let difference = match first.duration_since(second) {
+            Ok(x) => x,
+            Err(e) => e.duration(),
+        };
+        if difference <= self.window {
+            return true;
+        }
+        if !self.shifts || difference > MAX_SHIFT + self.window {
+            return false;
+        }
+
+        let step = SHIFT_STEP.as_nanos();
+        let remainder = difference.as_nanos() % step;
+        let window = self.window.as_nanos();
+        remainder <= window || step - remainder <= window
+    }
+
+    /// Whether `first` is later than `second` by more than the tolerance.
+    pub fn newer(&self, first: SystemTime, second: SystemTime) -> bool {
+        first > second && !self.same(first, second)
+    }
+}
+
+/// Works out how precisely `dir` stores modification times by setting the
+/// time of a probe file and reading it back. Returns `None` when `may_write`
+/// is unset or the probe fails.
+pub fn probe_time_granularity(dir: &Path, may_write: bool) -> Option<Duration> {
+    if !may_write {
+        return None;
+    }
+
+    let probe = dir.join(format!(".quick-copy-time-probe-{}", std::process::id()));
+    let file = fs::File::create(&probe).ok()?;
+    // An odd second, once just past it and once just before the next one,
+    // so both truncating and rounding filesystems lose precision.
+    let base = UNIX_EPOCH + Duration::from_secs(1_500_000_001);
+    let mut error = Duration::ZERO;
+    for nanos in [1, 999_999_999] {
+        let written = base + Duration::from_nanos(nanos);
+        let read = file
+            .set_modified(written)
+            .and_then(|_| file.metadata())
+            .and_then(|x| x.modified());
+        let read = match read {
+            Ok(x) => x,
+            Err(_) => {
+                let _ = fs::remove_file(&probe);
+                return None;
+            }
+        };
+        let difference = match written.duration_since(read) {
+            Ok(x) => x,
+            Err(e) => e.duration(),
+        };
+        error = error.max(difference);
+    }
+    drop(file);
+    let _ = fs::remove_file(&probe);
+
+    if error.is_zero() {
+        return Some(Duration::ZERO);
+    }
+    Some(
+        GRANULARITIES
+            .into_iter()
+            .find(|x| *x >= error)
+            .unwrap_or(GRANULARITIES[GRANULARITIES.len() - 1]),
+    )
+}
+
+/// Works out how precisely `dir` stores modification times without writing
+/// to it: the coarsest known resolution that the times of the first files
+/// found under it are all multiples of. With only a few files the guess can
+/// be too coarse. Returns `None` when there are no files to look at.
+pub fn infer_time_granularity(dir: &Path) -> Option<Duration> {
+    let mut times = Vec::<Duration>::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        let entries = match fs::read_dir(&directory) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            let metadata = match entry.metadata() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Some(time) = metadata
+                .modified()
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            {
+                times.push(time);
+                if times.len() == SAMPLE_SIZE {
+                    pending.clear();
+                    break;
+                }
+            }
+        }
+    }
+    if times.is_empty() {
+        return None;
+    }
+
+    Some(
+        GRANULARITIES
+            .into_iter()
+            .rev()
+            .find(|x| times.iter().all(|time| time.as_nanos() % x.as_nanos() == 0))
+            .unwrap_or(Duration::ZERO),
+    )
+}
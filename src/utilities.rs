@@ -1,23 +1,57 @@
-use crate::constants::READ5192;
-use std::{fs::File, io::Read};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::{io, io::Read};
 
-pub fn string_match(needle: String, haystack: String) -> bool {
-    let needle_lower = needle.to_lowercase();
-    let haystack_lower = haystack.to_lowercase();
+/// Whether two whole names are the same, ignoring case unless
+/// `case_sensitive`.
+pub fn string_match(needle: &str, haystack: &str, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        return needle == haystack;
+    }
+    needle.to_lowercase() == haystack.to_lowercase()
+}
 
-    if needle_lower.len() == 0 && haystack_lower.len() == 0 {
-        return true;
+/// `string_match` for names that may not be valid Unicode; those are only
+/// ever equal to an identical byte sequence.
+pub fn os_str_match(needle: &OsStr, haystack: &OsStr, case_sensitive: bool) -> bool {
+    match (needle.to_str(), haystack.to_str()) {
+        (Some(n), Some(h)) => string_match(n, h, case_sensitive),
+        _ => needle == haystack,
     }
+}
 
-    for _ in needle_lower.matches(haystack_lower.as_str()) {
-        return true;
+/// The raw bytes of a file name. Outside Unix the name is converted lossily.
+pub fn os_str_bytes(name: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        name.as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_string_lossy().as_bytes().to_vec()
     }
+}
 
-    false
+/// `path` without `suffix` at the end of its name, or `None` if it doesn't
+/// end with it. Works on names that aren't valid Unicode too.
+pub fn strip_path_suffix(path: &Path, suffix: &str) -> Option<PathBuf> {
+    let stripped = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .strip_suffix(suffix.as_bytes())?;
+    // SAFETY: `stripped` was split off right before the valid UTF-8 `suffix`.
+    let stripped = unsafe { OsStr::from_encoded_bytes_unchecked(stripped) };
+    Some(PathBuf::from(stripped))
 }
 
-pub fn string_match_str(needle: &str, haystack: &str) -> bool {
-    string_match(String::from(needle), String::from(haystack))
+/// A lookup key for a relative path. Unless `case_sensitive`, it is
+/// lowercased when it is valid Unicode; otherwise it is the exact name.
+pub fn path_key(path: &Path, case_sensitive: bool) -> OsString {
+    match path.to_str() {
+        Some(p) if !case_sensitive => OsString::from(p.to_lowercase()),
+        _ => path.as_os_str().to_os_string(),
+    }
 }
 
 #[allow(dead_code)]
@@ -28,7 +62,7 @@ pub fn char_match(needle: char, haystack: char) -> bool {
     haystack_lower[0] == needle_lower[0]
 }
 
-pub fn path_is_unc(path: &String) -> bool {
+pub fn path_is_unc(path: &str) -> bool {
     path.starts_with("\\\\")
 }
 
@@ -40,17 +74,18 @@ pub fn match_list_or_all(item: &String, items: Vec<String>) -> bool {
     items.contains(item)
 }
 
-pub fn read_file_incremental_action<F: FnMut(&[u8])>(file: &mut File, mut do_something: F) {
-    let mut buffer = [0; READ5192];
-    //let mut count = 0;
-    while let Ok(n) = file.read(&mut buffer[..]) {
-        if n != READ5192 {
-            let rest = &buffer[0..n];
-            do_something(rest);
-            break;
-        } else {
-            do_something(&buffer);
-            //count += n;
+pub fn read_file_incremental_action<R: Read + ?Sized, F: FnMut(&[u8])>(
+    file: &mut R,
+    buffer_size: usize,
+    mut do_something: F,
+) -> io::Result<()> {
+    let mut buffer = vec![0; buffer_size.max(1)];
+    loop {
+        match file.read(&mut buffer[..]) {
+            Ok(0) => return Ok(()),
+            Ok(n) => do_something(&buffer[0..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
 }